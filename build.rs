@@ -1,7 +1,7 @@
 use std::env;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 fn main() {
     // Put the linker script somewhere the linker can find it
@@ -18,7 +18,58 @@ fn main() {
 
     println!("cargo:rustc-link-search={}", out.display());
 
+    link_blobs(out);
+
     // Only re-run the build script when memory.x is changed,
     // instead of when any part of the source code changes.
     println!("cargo:rerun-if-changed=memory.x");
 }
+
+// The WiFi driver blobs, the Bluetooth controller (libbtbb, libbtdm_app) isn't needed until there
+// is Bluetooth support. libcoexist is needed by the WiFi blobs even without Bluetooth.
+const WIFI_BLOBS: &[&str] = &[
+    "coexist",
+    "core",
+    "espnow",
+    "mesh",
+    "net80211",
+    "phy",
+    "pp",
+    "smartconfig",
+    "wapi",
+    "wpa_supplicant",
+];
+
+fn link_blobs(out: &Path) {
+    let libs = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("libs");
+    println!("cargo:rustc-link-search={}", libs.display());
+
+    for blob in WIFI_BLOBS {
+        println!("cargo:rustc-link-lib={}", blob);
+    }
+
+    // Set ESP_WIFI_SIZE_REPORT to get the size of the linked archives, the memory usage per region
+    // and a linker map (in OUT_DIR) showing what ends up in the binary
+    println!("cargo:rerun-if-env-changed=ESP_WIFI_SIZE_REPORT");
+    if env::var_os("ESP_WIFI_SIZE_REPORT").is_none() {
+        return;
+    }
+
+    let mut total = 0;
+    for blob in WIFI_BLOBS {
+        let size = std::fs::metadata(libs.join(format!("lib{}.a", blob)))
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        total += size;
+        println!("cargo:warning=lib{}.a: {} KB", blob, size / 1024);
+    }
+    println!(
+        "cargo:warning=linked archives: {} KB, only the objects referenced end up in the binary",
+        total / 1024
+    );
+
+    let map = out.join("esp32c3-wifi.map");
+    println!("cargo:warning=linker map: {}", map.display());
+    println!("cargo:rustc-link-arg=-Map={}", map.display());
+    println!("cargo:rustc-link-arg=--print-memory-usage");
+}
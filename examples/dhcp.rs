@@ -5,13 +5,16 @@
 
 use core::{arch::asm, fmt::Write};
 
-use esp32c3_wifi_rs::wifi::{get_sta_mac, init_clocks, init_rng};
+use esp32c3_wifi_rs::wifi::{get_sta_mac, init_clocks};
 use esp32c3_wifi_rs::Uart;
 use esp32c3_wifi_rs::{
-    binary, compat, println,
+    binary, compat,
+    net::ip_watch::IpWatcher,
+    println,
     timer::{get_systimer_count, init_intr11, init_tasks, setup_timer_isr},
     wifi::{
         self, init_buffer, wifi_connect, wifi_init, wifi_set_log_verbose, wifi_start, WifiDevice,
+        WifiInterface,
     },
 };
 use hal::{interrupt::TrapFrame, pac::Peripherals, RtcCntl, Serial};
@@ -19,7 +22,10 @@ use riscv_rt::entry;
 use smoltcp::{
     dhcp::Dhcpv4Client,
     iface::{NeighborCache, Routes},
-    socket::{RawPacketMetadata, RawSocketBuffer, TcpSocket, TcpSocketBuffer},
+    socket::{
+        RawPacketMetadata, RawSocketBuffer, TcpSocket, TcpSocketBuffer, UdpPacketMetadata,
+        UdpSocket, UdpSocketBuffer,
+    },
     time::Instant,
     wire::{EthernetAddress, IpCidr, Ipv4Address, Ipv4Cidr},
 };
@@ -32,7 +38,6 @@ fn main() -> ! {
     let peripherals = Peripherals::take().unwrap();
     setup_timer_isr(&peripherals);
     init_intr11(&peripherals);
-    init_rng(peripherals.RNG);
 
     let mut rtc_cntl = RtcCntl::new(peripherals.RTC_CNTL);
     let mut serial0 = Serial::new(peripherals.UART0).unwrap();
@@ -50,28 +55,30 @@ fn main() -> ! {
 
     init_clocks();
 
-    let res = wifi_init();
-    println!("\n\n\nesp_wifi_init_internal returned {}", res);
+    let res = wifi_init(peripherals.RNG);
+    println!("\n\n\nwifi_init returned {:?}", res);
+    let _driver = res.unwrap();
 
     println!("\n\n\nCall wifi_start");
     let res = wifi_start();
-    println!("\n\n\nwifi_start returned {}", res);
+    println!("\n\n\nwifi_start returned {:?}", res);
 
     // println!("Call wifi_start_scan");
     // let res = wifi::wifi_start_scan();
-    // println!("wifi_start_scan returned {}", res);
+    // println!("wifi_start_scan returned {:?}", res);
     print_scan_result();
     println!("\n\n\n\n");
 
     init_buffer();
 
-    let mut socket_set_entries: [_; 2] = Default::default();
+    // the socket set belongs to the application, size it for the sockets it needs
+    let mut socket_set_entries: [_; 3] = Default::default();
     let mut sockets = smoltcp::socket::SocketSet::new(&mut socket_set_entries[..]);
     let mut neighbor_cache_storage = [None; 8];
     let neighbor_cache = NeighborCache::new(&mut neighbor_cache_storage[..]);
 
     let hw_address = EthernetAddress::from_bytes(&[0, 0, 0, 0, 0, 0]);
-    let device = WifiDevice::new();
+    let device = WifiDevice::take(WifiInterface::Sta).unwrap();
 
     let ip_addr = IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0);
     let mut ip_addrs = [ip_addr];
@@ -106,7 +113,7 @@ fn main() -> ! {
 
     println!("Call wifi_connect");
     let res = wifi_connect(SSID, PASSWORD);
-    println!("wifi_connect returned {}", res);
+    println!("wifi_connect returned {:?}", res);
 
     loop {
         if wifi::is_connected() {
@@ -126,11 +133,26 @@ fn main() -> ! {
     };
     let greet_handle = sockets.add(greet_socket);
 
+    let echo_socket = {
+        static mut UDP_RX_METADATA: [UdpPacketMetadata; 2] = [UdpPacketMetadata::EMPTY; 2];
+        static mut UDP_RX_DATA: [u8; 128] = [0; 128];
+        static mut UDP_TX_METADATA: [UdpPacketMetadata; 2] = [UdpPacketMetadata::EMPTY; 2];
+        static mut UDP_TX_DATA: [u8; 128] = [0; 128];
+
+        let udp_rx_buffer =
+            unsafe { UdpSocketBuffer::new(&mut UDP_RX_METADATA[..], &mut UDP_RX_DATA[..]) };
+        let udp_tx_buffer =
+            unsafe { UdpSocketBuffer::new(&mut UDP_TX_METADATA[..], &mut UDP_TX_DATA[..]) };
+
+        UdpSocket::new(udp_rx_buffer, udp_tx_buffer)
+    };
+    let echo_handle = sockets.add(echo_socket);
+
+    let mut ip_watcher = IpWatcher::new();
+
     loop {
         let timestamp = timestamp();
-        critical_section::with(|_| {
-            ethernet.poll(&mut sockets, timestamp).ok();
-        });
+        ethernet.poll(&mut sockets, timestamp).ok();
 
         let config = dhcp
             .poll(&mut ethernet, &mut sockets, timestamp)
@@ -175,6 +197,10 @@ fn main() -> ! {
             }
         });
 
+        if let Some(change) = ip_watcher.update(ethernet.ip_addrs()) {
+            println!("IP address change: {:?}", change);
+        }
+
         // Control the "greeting" socket (:4321)
         {
             let mut socket = sockets.get::<TcpSocket>(greet_handle);
@@ -192,6 +218,19 @@ fn main() -> ! {
                 socket.close();
             }
         }
+
+        // Echo UDP datagrams sent to port 4322
+        {
+            let mut socket = sockets.get::<UdpSocket>(echo_handle);
+            if !socket.is_open() {
+                socket.bind(4322).unwrap();
+            }
+
+            let mut buffer = [0u8; 64];
+            if let Ok((len, endpoint)) = socket.recv_slice(&mut buffer) {
+                socket.send_slice(&buffer[..len], endpoint).ok();
+            }
+        }
     }
 }
 
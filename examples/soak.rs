@@ -0,0 +1,172 @@
+#![no_std]
+#![no_main]
+#![feature(c_variadic)]
+#![feature(const_mut_refs)]
+
+// Soak test for the compat primitives backing the driver (queue, semaphores, work queue, timers).
+// A producer running on the work queue task and a periodic timer running on the timer task
+// feed the main task. Random delays on both sides shuffle the interleavings.
+// Prints statistics every few seconds - any error count other than zero is a bug.
+
+use core::fmt::Write;
+
+use esp32c3_wifi_rs::{
+    binary::{c_types::c_void, include::ets_timer},
+    compat::{
+        common::{sem_create, sem_give, sem_take},
+        queue::SimpleQueue,
+        timer_compat::{compat_timer_arm, compat_timer_setfn},
+        work_queue::queue_work,
+    },
+    println,
+    timer::{get_systimer_count, init_tasks, setup_timer_isr},
+    wifi::{init_rng, random},
+    Uart,
+};
+use hal::{pac::Peripherals, RtcCntl};
+use riscv_rt::entry;
+
+const ITEMS_PER_ROUND: u32 = 1000;
+
+static mut QUEUE: Option<SimpleQueue<u32, 8>> = None;
+static mut SEM: *mut c_void = core::ptr::null_mut();
+
+static mut TIMER: ets_timer = ets_timer {
+    next: core::ptr::null_mut(),
+    expire: 0,
+    period: 0,
+    func: None,
+    priv_: core::ptr::null_mut(),
+};
+static mut TIMER_TICKS: u32 = 0;
+
+#[entry]
+fn main() -> ! {
+    let peripherals = Peripherals::take().unwrap();
+    setup_timer_isr(&peripherals);
+    init_rng(peripherals.RNG);
+
+    let mut rtc_cntl = RtcCntl::new(peripherals.RTC_CNTL);
+    rtc_cntl.set_super_wdt_enable(false);
+    rtc_cntl.set_wdt_enable(false);
+
+    init_tasks();
+
+    unsafe {
+        QUEUE = Some(SimpleQueue::new());
+        SEM = sem_create(1, 0);
+
+        compat_timer_setfn(
+            &mut TIMER as *mut _ as *mut c_void,
+            timer_callback as *mut c_void,
+            core::ptr::null_mut(),
+        );
+        compat_timer_arm(&mut TIMER as *mut _ as *mut c_void, 3, true);
+    }
+
+    let mut round = 0;
+    let mut sequence_errors = 0;
+    let mut timeouts = 0;
+    let mut last_timer_ticks = 0;
+    let mut timer_errors = 0;
+    let mut last_report = get_systimer_count();
+
+    loop {
+        unsafe {
+            queue_work(
+                producer as *mut c_void,
+                core::ptr::null(),
+                0,
+                core::ptr::null_mut(),
+                1,
+                core::ptr::null_mut(),
+                0,
+            );
+        }
+
+        let mut expected = 0;
+        while expected < ITEMS_PER_ROUND {
+            // 100ms
+            if sem_take(unsafe { SEM }, 1_600_000) == 0 {
+                timeouts += 1;
+                break;
+            }
+
+            loop {
+                let item = critical_section::with(|_| unsafe { QUEUE.as_mut().unwrap().dequeue() });
+                match item {
+                    Some(item) => {
+                        if item != expected {
+                            sequence_errors += 1;
+                        }
+                        expected = item + 1;
+                    }
+                    None => break,
+                }
+            }
+
+            random_delay();
+        }
+
+        let timer_ticks = critical_section::with(|_| unsafe { TIMER_TICKS });
+        if timer_ticks < last_timer_ticks {
+            timer_errors += 1;
+        }
+        last_timer_ticks = timer_ticks;
+
+        round += 1;
+
+        if get_systimer_count() - last_report > 16_000_000 * 5 {
+            last_report = get_systimer_count();
+            println!(
+                "round {} sequence errors {} timeouts {} timer ticks {} timer errors {}",
+                round, sequence_errors, timeouts, timer_ticks, timer_errors
+            );
+        }
+    }
+}
+
+extern "C" fn producer(_param: *mut c_void) {
+    for i in 0..ITEMS_PER_ROUND {
+        loop {
+            let queued = critical_section::with(|_| unsafe {
+                let queue = QUEUE.as_mut().unwrap();
+                if queue.is_full() {
+                    false
+                } else {
+                    queue.enqueue(i);
+                    true
+                }
+            });
+
+            if queued {
+                break;
+            }
+        }
+
+        sem_give(unsafe { SEM });
+        random_delay();
+    }
+}
+
+extern "C" fn timer_callback(_arg: *mut c_void) {
+    critical_section::with(|_| unsafe {
+        TIMER_TICKS += 1;
+    });
+    random_delay();
+}
+
+fn random_delay() {
+    let ticks = unsafe { random() } % 2_000;
+    let end = get_systimer_count() + ticks as u64;
+    while get_systimer_count() < end {}
+}
+
+#[panic_handler]
+fn panic_handler(info: &core::panic::PanicInfo) -> ! {
+    unsafe {
+        riscv::interrupt::disable();
+    }
+    writeln!(Uart, "{}", info).ok();
+    loop {}
+}
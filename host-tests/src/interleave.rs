@@ -0,0 +1,77 @@
+// Randomized interleavings: threads stand in for the driver's tasks and ISRs, a mutex for the
+// critical sections. Every thread yields or spins for a random while between operations so each
+// seed produces a different schedule.
+
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// xorshift32, good enough to shuffle schedules and reproducible from the seed
+pub struct Rng(u32);
+
+impl Rng {
+    pub fn new(seed: u32) -> Rng {
+        Rng(seed.max(1))
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    /// Yield or spin for a random while
+    pub fn pause(&mut self) {
+        match self.next_u32() % 4 {
+            0 => (),
+            1 => thread::yield_now(),
+            _ => {
+                for _ in 0..self.next_u32() % 64 {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+    }
+}
+
+/// Seeds every test runs with, `SOAK_SEEDS` raises the count
+pub fn seeds() -> impl Iterator<Item = u32> {
+    let count = std::env::var("SOAK_SEEDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(32);
+    1..=count
+}
+
+/// Run `tasks` on their own threads sharing `state`, each with its own RNG derived from `seed`.
+/// Returns the state once all tasks are done.
+pub fn run<S, F>(seed: u32, state: S, tasks: Vec<F>) -> S
+where
+    S: Send + 'static,
+    F: FnOnce(&Mutex<S>, &mut Rng) + Send + 'static,
+{
+    let state = Arc::new(Mutex::new(state));
+
+    let handles: Vec<_> = tasks
+        .into_iter()
+        .enumerate()
+        .map(|(i, task)| {
+            let state = state.clone();
+            let mut rng = Rng::new(seed.wrapping_mul(0x9e37_79b9).wrapping_add(i as u32));
+            thread::spawn(move || task(&state, &mut rng))
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    match Arc::try_unwrap(state) {
+        Ok(state) => state.into_inner().unwrap(),
+        Err(_) => unreachable!(),
+    }
+}
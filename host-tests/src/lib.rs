@@ -0,0 +1,12 @@
+// The compat primitives and net helpers without dependencies on the target, included from the
+// main crate so the tests exercise the same code. Run with `cargo test` from this directory.
+
+#[path = "../../src/compat/queue.rs"]
+pub mod queue;
+#[path = "../../src/compat/sync.rs"]
+pub mod sync;
+
+#[path = "../../src/net/dns_wire.rs"]
+pub mod dns_wire;
+
+pub mod interleave;
@@ -0,0 +1,124 @@
+use esp32c3_wifi_host_tests::dns_wire::{
+    encode_query, parse_response, skip_name, Answer, HEADER_LEN, QUERY_MAX_LEN,
+};
+
+const ID: u16 = 0x1234;
+
+fn query(name: &str) -> Option<Vec<u8>> {
+    let mut query = [0u8; QUERY_MAX_LEN];
+    let len = encode_query(name, ID, &mut query)?;
+    Some(query[..len].to_vec())
+}
+
+// a response to the query for `example.com` with the given answer records
+fn response(rcode: u8, answers: &[&[u8]]) -> Vec<u8> {
+    let mut response = query("example.com").unwrap();
+    // response, recursion desired and available
+    response[2] = 0x81;
+    response[3] = 0x80 | rcode;
+    response[7] = answers.len() as u8;
+    for answer in answers {
+        response.extend_from_slice(answer);
+    }
+    response
+}
+
+// a record for the name at offset 12 (the question) via a compression pointer
+fn record(record_type: u16, class: u16, data: &[u8]) -> Vec<u8> {
+    let mut record = vec![0xc0, HEADER_LEN as u8];
+    record.extend_from_slice(&record_type.to_be_bytes());
+    record.extend_from_slice(&class.to_be_bytes());
+    // TTL
+    record.extend_from_slice(&[0, 0, 0x0e, 0x10]);
+    record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    record.extend_from_slice(data);
+    record
+}
+
+#[test]
+fn query_encoding() {
+    let query = query("example.com").unwrap();
+
+    assert_eq!(&query[0..2], &ID.to_be_bytes());
+    assert_eq!(&query[2..12], &[0x01, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(&query[12..25], b"\x07example\x03com\x00");
+    // type A, class IN
+    assert_eq!(&query[25..], &[0, 1, 0, 1]);
+}
+
+#[test]
+fn query_trailing_dot() {
+    assert_eq!(query("example.com."), query("example.com"));
+}
+
+#[test]
+fn query_invalid_names() {
+    assert_eq!(query(""), None);
+    assert_eq!(query("."), None);
+    assert_eq!(query("example..com"), None);
+    assert_eq!(query(&"a".repeat(64)), None);
+    assert!(query(&"a".repeat(63)).is_some());
+
+    let long = ["a".repeat(63).as_str(); 4].join(".");
+    assert_eq!(long.len(), 255);
+    assert_eq!(query(&long), None);
+    assert!(query(&long[2..]).is_some());
+}
+
+#[test]
+fn resolved() {
+    let response = response(0, &[&record(1, 1, &[93, 184, 216, 34])]);
+    assert_eq!(
+        parse_response(&response, ID),
+        Some(Answer::Address([93, 184, 216, 34]))
+    );
+}
+
+#[test]
+fn cname_before_address() {
+    let cname = record(5, 1, b"\x03www\xc0\x0c");
+    let response = response(0, &[&cname, &record(1, 1, &[10, 0, 0, 1])]);
+    assert_eq!(
+        parse_response(&response, ID),
+        Some(Answer::Address([10, 0, 0, 1]))
+    );
+}
+
+#[test]
+fn no_address() {
+    // NXDOMAIN
+    assert_eq!(parse_response(&response(3, &[]), ID), Some(Answer::NotFound));
+    // only an AAAA record
+    let response = response(0, &[&record(28, 1, &[0; 16])]);
+    assert_eq!(parse_response(&response, ID), Some(Answer::NotFound));
+}
+
+#[test]
+fn not_the_answer() {
+    let response = response(0, &[&record(1, 1, &[10, 0, 0, 1])]);
+    assert_eq!(parse_response(&response, ID + 1), None);
+    assert_eq!(parse_response(&response[..HEADER_LEN - 1], ID), None);
+
+    // the query itself
+    assert_eq!(parse_response(&query("example.com").unwrap(), ID), None);
+}
+
+#[test]
+fn truncated() {
+    let response = response(0, &[&record(1, 1, &[10, 0, 0, 1])]);
+    for len in HEADER_LEN..response.len() {
+        assert_eq!(parse_response(&response[..len], ID), None, "length {}", len);
+    }
+}
+
+#[test]
+fn names() {
+    let data = b"\x07example\x03com\x00\x03www\xc0\x00";
+    assert_eq!(skip_name(data, 0), Some(13));
+    assert_eq!(skip_name(data, 13), Some(19));
+    // the root name
+    assert_eq!(skip_name(&[0], 0), Some(1));
+    // runs past the end
+    assert_eq!(skip_name(&data[..10], 0), None);
+    assert_eq!(skip_name(data, data.len()), None);
+}
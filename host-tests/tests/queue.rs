@@ -0,0 +1,83 @@
+use std::sync::Mutex;
+
+use esp32c3_wifi_host_tests::{
+    interleave::{run, seeds, Rng},
+    queue::SimpleQueue,
+};
+
+type Task<S> = Box<dyn FnOnce(&Mutex<S>, &mut Rng) + Send>;
+
+const ITEMS: u32 = 2000;
+
+#[test]
+fn fifo_order() {
+    let mut queue: SimpleQueue<u32, 4> = SimpleQueue::new();
+    assert!(queue.is_empty());
+
+    for i in 0..3 {
+        assert!(queue.enqueue(i));
+    }
+    assert!(queue.is_full());
+    assert_eq!(queue.len(), 3);
+    assert_eq!(queue.peek(), Some(&0));
+
+    for i in 0..3 {
+        assert_eq!(queue.dequeue(), Some(i));
+    }
+    assert_eq!(queue.dequeue(), None);
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn wraps_around() {
+    let mut queue: SimpleQueue<u32, 3> = SimpleQueue::new();
+
+    for i in 0..10 {
+        assert!(queue.enqueue(i));
+        assert!(queue.enqueue(i + 100));
+        assert_eq!(queue.dequeue(), Some(i));
+        assert_eq!(queue.dequeue(), Some(i + 100));
+        assert!(queue.is_empty());
+    }
+}
+
+// Two producers (e.g. the STA and AP RX callbacks) and one consumer - every item arrives once and
+// the items of each producer keep their order.
+#[test]
+fn producers_and_consumer_interleaved() {
+    for seed in seeds() {
+        let producer = |tag: usize| -> Task<SimpleQueue<(usize, u32), 8>> {
+            Box::new(move |queue, rng| {
+                let mut next = 0;
+                while next < ITEMS {
+                    {
+                        let mut queue = queue.lock().unwrap();
+                        if !queue.is_full() {
+                            assert!(queue.enqueue((tag, next)));
+                            next += 1;
+                        }
+                    }
+                    rng.pause();
+                }
+            })
+        };
+        let consumer: Task<SimpleQueue<(usize, u32), 8>> = Box::new(move |queue, rng| {
+            let mut expected = [0u32; 2];
+            while expected.iter().any(|&next| next < ITEMS) {
+                let item = queue.lock().unwrap().dequeue();
+                if let Some((tag, value)) = item {
+                    assert_eq!(value, expected[tag], "seed {}", seed);
+                    expected[tag] += 1;
+                }
+                rng.pause();
+            }
+        });
+
+        let mut queue = run(
+            seed,
+            SimpleQueue::new(),
+            vec![producer(0), producer(1), consumer],
+        );
+        assert!(queue.dequeue().is_none());
+    }
+}
@@ -0,0 +1,150 @@
+use std::sync::Mutex;
+
+use esp32c3_wifi_host_tests::{
+    interleave::{run, seeds, Rng},
+    sync::{EventGroups, Semaphores},
+};
+
+type Task<S> = Box<dyn FnOnce(&Mutex<S>, &mut Rng) + Send>;
+
+const ROUNDS: u32 = 500;
+
+#[test]
+fn semaphore_slots() {
+    let mut semaphores: Semaphores<2> = Semaphores::new();
+
+    let a = semaphores.create(1).unwrap();
+    let b = semaphores.create(0).unwrap();
+    assert_eq!(semaphores.create(0), None);
+
+    assert!(semaphores.try_take(a));
+    assert!(!semaphores.try_take(a));
+    assert!(!semaphores.try_take(b));
+    assert!(semaphores.give(b));
+    assert!(semaphores.try_take(b));
+
+    semaphores.delete(a);
+    assert!(!semaphores.give(a));
+    assert_eq!(semaphores.create(3), Some(a));
+    assert_eq!(semaphores.count(a), Some(3));
+
+    semaphores.reset();
+    assert_eq!(semaphores.count(a), None);
+    assert_eq!(semaphores.count(b), None);
+}
+
+// One task gives, two take - nothing is taken that wasn't given and nothing gets lost.
+#[test]
+fn semaphore_give_take_interleaved() {
+    for seed in seeds() {
+        let mut semaphores: Semaphores<4> = Semaphores::new();
+        let sem = semaphores.create(0).unwrap();
+
+        let giver: Task<(Semaphores<4>, u32, u32)> = Box::new(move |state, rng| {
+            for _ in 0..ROUNDS * 2 {
+                {
+                    let mut state = state.lock().unwrap();
+                    assert!(state.0.give(sem));
+                    state.1 += 1;
+                }
+                rng.pause();
+            }
+        });
+        let taker = || -> Task<(Semaphores<4>, u32, u32)> {
+            Box::new(move |state, rng| {
+                let mut taken = 0;
+                while taken < ROUNDS {
+                    {
+                        let mut state = state.lock().unwrap();
+                        if state.0.try_take(sem) {
+                            taken += 1;
+                            state.2 += 1;
+                            assert!(state.2 <= state.1, "seed {}", seed);
+                        }
+                    }
+                    rng.pause();
+                }
+            })
+        };
+
+        let (semaphores, given, taken) =
+            run(seed, (semaphores, 0, 0), vec![giver, taker(), taker()]);
+        assert_eq!(given, taken);
+        assert_eq!(semaphores.count(sem), Some(0));
+    }
+}
+
+#[test]
+fn event_bits() {
+    let mut groups: EventGroups<1> = EventGroups::new();
+    let group = groups.create().unwrap();
+    assert_eq!(groups.create(), None);
+
+    assert_eq!(groups.set(group, 0b01), 0b01);
+    assert_eq!(groups.try_wait(group, 0b11, false, true), (0b01, false));
+    assert_eq!(groups.try_wait(group, 0b11, false, false), (0b01, true));
+
+    assert_eq!(groups.set(group, 0b10), 0b11);
+    assert_eq!(groups.try_wait(group, 0b11, true, true), (0b11, true));
+    assert_eq!(groups.try_wait(group, 0b11, false, false), (0, false));
+
+    groups.set(group, 0b110);
+    assert_eq!(groups.clear(group, 0b010), 0b110);
+    assert_eq!(groups.clear(group, 0b100), 0b100);
+
+    groups.delete(group);
+    assert_eq!(groups.create(), Some(group));
+}
+
+// Two tasks set one bit each, a third waits for both and clears them - every round completes
+// with both bits seen and no bit is left over.
+#[test]
+fn event_group_wait_all_interleaved() {
+    const BIT_A: u32 = 1 << 0;
+    const BIT_B: u32 = 1 << 3;
+
+    for seed in seeds() {
+        let mut groups: EventGroups<4> = EventGroups::new();
+        let group = groups.create().unwrap();
+
+        let setter = |bit: u32| -> Task<(EventGroups<4>, u32)> {
+            Box::new(move |state, rng| {
+                let mut rounds = 0;
+                while rounds < ROUNDS {
+                    {
+                        let mut state = state.lock().unwrap();
+                        // wait until the waiter consumed the bit of the last round
+                        let (bits, _) = state.0.try_wait(group, bit, false, false);
+                        if bits & bit == 0 {
+                            state.0.set(group, bit);
+                            rounds += 1;
+                        }
+                    }
+                    rng.pause();
+                }
+            })
+        };
+        let waiter: Task<(EventGroups<4>, u32)> = Box::new(move |state, rng| loop {
+            {
+                let mut state = state.lock().unwrap();
+                let (bits, done) = state.0.try_wait(group, BIT_A | BIT_B, true, true);
+                if done {
+                    assert_eq!(bits, BIT_A | BIT_B, "seed {}", seed);
+                    state.1 += 1;
+                    if state.1 == ROUNDS {
+                        break;
+                    }
+                }
+            }
+            rng.pause();
+        });
+
+        let (mut groups, completed) = run(
+            seed,
+            (groups, 0),
+            vec![setter(BIT_A), setter(BIT_B), waiter],
+        );
+        assert_eq!(completed, ROUNDS);
+        assert_eq!(groups.try_wait(group, !0, false, false), (0, false));
+    }
+}
@@ -8,12 +8,14 @@ use crate::{
 };
 use core::{ffi::VaListImpl, fmt::Write};
 
-use super::queue::SimpleQueue;
+use super::{
+    queue::SimpleQueue,
+    sync::{EventGroups, Semaphores},
+};
+
+static mut SEMAPHORES: Semaphores<20> = Semaphores::new();
 
-static mut CURR_SEM: [Option<u32>; 20] = [
-    None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-    None, None, None, None,
-];
+static mut EVENT_GROUPS: EventGroups<4> = EventGroups::new();
 
 static mut PER_THREAD_SEM: [Option<*mut crate::binary::c_types::c_void>; 3] = [None; 3];
 
@@ -22,14 +24,17 @@ struct Mutex {
     locking_pid: usize,
     count: u32,
     recursive: bool,
+    allocated: bool,
 }
 
-static mut MUTEXES: [Mutex; 10] = [Mutex {
+const MUTEX_UNUSED: Mutex = Mutex {
     locking_pid: 0xffff_ffff,
     count: 0,
     recursive: false,
-}; 10];
-static mut MUTEX_IDX_CURRENT: usize = 0;
+    allocated: false,
+};
+
+static mut MUTEXES: [Mutex; 10] = [MUTEX_UNUSED; 10];
 
 static mut FAKE_WIFI_QUEUE: &Option<SimpleQueue<[u8; 8], 200>> = unsafe { &REAL_WIFI_QUEUE };
 static mut REAL_WIFI_QUEUE: Option<SimpleQueue<[u8; 8], 200>> = None; // first there is a ptr to the real queue - driver checks it's not null
@@ -228,28 +233,20 @@ unsafe extern "C" fn strnlen(chars: *const u8, maxlen: usize) -> usize {
 
 pub fn sem_create(max: u32, init: u32) -> *mut crate::binary::c_types::c_void {
     critical_section::with(|_| unsafe {
-        let mut res = 0xffff;
-        for (i, sem) in CURR_SEM.iter().enumerate() {
-            if let None = *sem {
-                res = i;
-                break;
-            }
-        }
+        let res = SEMAPHORES.create(init);
 
-        trace!("sem created res = {} (+1)", res);
+        trace!("sem created res = {:?} (+1)", res);
 
-        if res != 0xffff {
-            CURR_SEM[res] = Some(init);
-            (res + 1) as *mut crate::binary::c_types::c_void
-        } else {
-            core::ptr::null_mut()
+        match res {
+            Some(index) => (index + 1) as *mut crate::binary::c_types::c_void,
+            None => core::ptr::null_mut(),
         }
     })
 }
 
 pub fn sem_delete(semphr: *mut crate::binary::c_types::c_void) {
     critical_section::with(|_| unsafe {
-        CURR_SEM[semphr as usize - 1] = None;
+        SEMAPHORES.delete(semphr as usize - 1);
     })
 }
 
@@ -265,30 +262,15 @@ pub fn sem_take(semphr: *mut crate::binary::c_types::c_void, tick: u32) -> i32 {
     let end_time = crate::timer::get_systimer_count() + tick as u64;
 
     loop {
-        loop {
-            let res = critical_section::with(|_| unsafe {
-                if let Some(cnt) = CURR_SEM[semphr as usize - 1] {
-                    if cnt > 0 {
-                        CURR_SEM[semphr as usize - 1] = Some(cnt - 1);
-                        1
-                    } else {
-                        0
-                    }
-                } else {
-                    0
-                }
-            });
+        let taken = critical_section::with(|_| unsafe { SEMAPHORES.try_take(semphr as usize - 1) });
 
-            if res == 1 {
-                trace!(">>>> return from semphr_take");
-                return 1;
-            }
+        if taken {
+            trace!(">>>> return from semphr_take");
+            return 1;
+        }
 
-            if !forever {
-                if crate::timer::get_systimer_count() > end_time {
-                    break;
-                }
-            }
+        if !forever && crate::timer::get_systimer_count() > end_time {
+            break;
         }
     }
 
@@ -299,16 +281,73 @@ pub fn sem_take(semphr: *mut crate::binary::c_types::c_void, tick: u32) -> i32 {
 pub fn sem_give(semphr: *mut crate::binary::c_types::c_void) -> i32 {
     trace!("semphr_give {:p}", semphr);
 
-    let res = critical_section::with(|_| unsafe {
-        if let Some(cnt) = CURR_SEM[semphr as usize - 1] {
-            CURR_SEM[semphr as usize - 1] = Some(cnt + 1);
-            1
-        } else {
-            0
+    critical_section::with(|_| unsafe { SEMAPHORES.give(semphr as usize - 1) as i32 })
+}
+
+pub fn create_event_group() -> *mut crate::binary::c_types::c_void {
+    critical_section::with(|_| unsafe {
+        match EVENT_GROUPS.create() {
+            Some(index) => {
+                trace!("event group created {} (+1)", index);
+                (index + 1) as *mut crate::binary::c_types::c_void
+            }
+            None => core::ptr::null_mut(),
         }
-    });
+    })
+}
+
+pub fn delete_event_group(event: *mut crate::binary::c_types::c_void) {
+    critical_section::with(|_| unsafe {
+        EVENT_GROUPS.delete(event as usize - 1);
+    })
+}
 
-    res
+/// Returns the bits after setting them
+pub fn set_event_bits(event: *mut crate::binary::c_types::c_void, bits: u32) -> u32 {
+    trace!("set_event_bits {:p} {:x}", event, bits);
+
+    critical_section::with(|_| unsafe { EVENT_GROUPS.set(event as usize - 1, bits) })
+}
+
+/// Returns the bits before clearing them
+pub fn clear_event_bits(event: *mut crate::binary::c_types::c_void, bits: u32) -> u32 {
+    trace!("clear_event_bits {:p} {:x}", event, bits);
+
+    critical_section::with(|_| unsafe { EVENT_GROUPS.clear(event as usize - 1, bits) })
+}
+
+/// Returns the bits when the wait ended, the caller checks them to tell a timeout apart
+pub fn wait_event_bits(
+    event: *mut crate::binary::c_types::c_void,
+    bits_to_wait_for: u32,
+    clear_on_exit: bool,
+    wait_for_all_bits: bool,
+    tick: u32,
+) -> u32 {
+    trace!(
+        "wait_event_bits {:p} {:x} block_time_tick {}",
+        event,
+        bits_to_wait_for,
+        tick
+    );
+
+    let forever = tick == OSI_FUNCS_TIME_BLOCKING;
+    let end_time = crate::timer::get_systimer_count() + tick as u64;
+
+    loop {
+        let (bits, done) = critical_section::with(|_| unsafe {
+            EVENT_GROUPS.try_wait(
+                event as usize - 1,
+                bits_to_wait_for,
+                clear_on_exit,
+                wait_for_all_bits,
+            )
+        });
+
+        if done || (!forever && crate::timer::get_systimer_count() > end_time) {
+            return bits;
+        }
+    }
 }
 
 pub fn thread_sem_get() -> *mut crate::binary::c_types::c_void {
@@ -330,14 +369,28 @@ pub fn thread_sem_get() -> *mut crate::binary::c_types::c_void {
 
 pub fn create_recursive_mutex() -> *mut crate::binary::c_types::c_void {
     critical_section::with(|_| unsafe {
-        let ptr = &mut MUTEXES[MUTEX_IDX_CURRENT] as *mut _ as *mut Mutex;
-        (*ptr).recursive = true;
-        MUTEX_IDX_CURRENT += 1;
+        let mutex = match MUTEXES.iter_mut().find(|mutex| !mutex.allocated) {
+            Some(mutex) => mutex,
+            None => return core::ptr::null_mut(),
+        };
+        *mutex = Mutex {
+            recursive: true,
+            allocated: true,
+            ..MUTEX_UNUSED
+        };
+        let ptr = mutex as *mut Mutex;
         trace!("recursive_mutex_create called {:p}", ptr);
         ptr as *mut crate::binary::c_types::c_void
     })
 }
 
+pub fn delete_mutex(mutex: *mut crate::binary::c_types::c_void) {
+    trace!("mutex_delete {:p}", mutex);
+    critical_section::with(|_| unsafe {
+        *(mutex as *mut Mutex) = MUTEX_UNUSED;
+    })
+}
+
 pub fn lock_mutex(mutex: *mut crate::binary::c_types::c_void) -> i32 {
     trace!("mutex_lock ptr = {:p}", mutex);
 
@@ -416,6 +469,30 @@ pub fn create_wifi_queue(
     }
 }
 
+pub fn delete_wifi_queue(queue: *mut crate::binary::c_types::c_void) {
+    trace!("wifi_delete_queue {:p}", queue);
+
+    critical_section::with(|_| unsafe {
+        if queue == &mut FAKE_WIFI_QUEUE as *mut _ as *mut crate::binary::c_types::c_void
+            || queue == &mut REAL_WIFI_QUEUE as *mut _ as *mut crate::binary::c_types::c_void
+        {
+            REAL_WIFI_QUEUE = None;
+        }
+    })
+}
+
+/// Forget all semaphores, event groups, mutexes and the WiFi queue after the driver was
+/// deinitialized, including the ones the driver didn't delete.
+pub(crate) fn compat_reset() {
+    critical_section::with(|_| unsafe {
+        SEMAPHORES.reset();
+        EVENT_GROUPS.reset();
+        PER_THREAD_SEM = [None; 3];
+        MUTEXES = [MUTEX_UNUSED; 10];
+        REAL_WIFI_QUEUE = None;
+    })
+}
+
 pub fn send_queued(
     queue: *mut crate::binary::c_types::c_void,
     item: *mut crate::binary::c_types::c_void,
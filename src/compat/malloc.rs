@@ -1,9 +1,43 @@
 use crate::trace;
 
-extern "C" {
-    static _sheap: u8;
+/// Size of the heap the driver allocates from, set the env variable `ESP_WIFI_HEAP_SIZE` when
+/// building to change it (16384 at least, 65536 by default).
+///
+/// When it's exhausted allocations fail and the driver reports `ESP_ERR_NO_MEM`: `wifi_init`
+/// fails if it's too small to start with, later on frames get dropped.
+pub const HEAP_SIZE: usize = parse_heap_size(option_env!("ESP_WIFI_HEAP_SIZE"));
+
+const DEFAULT_HEAP_SIZE: usize = 64 * 1024;
+
+const fn parse_heap_size(value: Option<&str>) -> usize {
+    let value = match value {
+        Some(value) => value.as_bytes(),
+        None => return DEFAULT_HEAP_SIZE,
+    };
+
+    let mut size = 0;
+    let mut i = 0;
+    while i < value.len() {
+        assert!(
+            value[i].is_ascii_digit(),
+            "ESP_WIFI_HEAP_SIZE must be a number"
+        );
+        size = size * 10 + (value[i] - b'0') as usize;
+        i += 1;
+    }
+
+    assert!(
+        size >= 16 * 1024,
+        "ESP_WIFI_HEAP_SIZE must be 16384 at least"
+    );
+    size
 }
 
+#[repr(align(8))]
+struct Heap([u8; HEAP_SIZE]);
+
+static mut HEAP: Heap = Heap([0u8; HEAP_SIZE]);
+
 #[derive(Debug, Copy, Clone)]
 struct Allocation {
     address: *const u8,
@@ -17,7 +51,7 @@ static mut ALLOC_INDEX: isize = -1;
 pub unsafe extern "C" fn malloc(size: u32) -> *const u8 {
     trace!("malloc called {}", size);
 
-    let mut candidate_addr = &_sheap as *const u8;
+    let mut candidate_addr = HEAP.0.as_ptr();
 
     critical_section::with(|_critical_section| {
         let aligned_size = size + if size % 8 != 0 { 8 - size % 8 } else { 0 };
@@ -46,6 +80,15 @@ pub unsafe extern "C" fn malloc(size: u32) -> *const u8 {
                     .offset(ALLOCATIONS[ALLOC_INDEX as usize].unwrap().size as isize);
             }
 
+            let heap_end = HEAP.0.as_ptr().add(HEAP_SIZE);
+            if (ALLOC_INDEX + 1) as usize == ALLOCATIONS.len()
+                || candidate_addr.add(aligned_size as usize) > heap_end
+            {
+                trace!("out of heap");
+                candidate_addr = core::ptr::null();
+                return;
+            }
+
             ALLOC_INDEX += 1;
 
             ALLOCATIONS[ALLOC_INDEX as usize] = Some(Allocation {
@@ -96,10 +139,34 @@ pub unsafe extern "C" fn free(ptr: *const u8) {
     });
 }
 
+/// Bytes currently allocated by the driver
+pub fn heap_used() -> usize {
+    critical_section::with(|_critical_section| unsafe {
+        ALLOCATIONS
+            .iter()
+            .flatten()
+            .filter(|allocation| !allocation.free)
+            .map(|allocation| allocation.size)
+            .sum()
+    })
+}
+
+/// Forget all allocations. Only safe once nothing uses the allocated memory anymore.
+pub(crate) unsafe fn heap_reset() {
+    critical_section::with(|_critical_section| {
+        ALLOCATIONS = [None; 128];
+        ALLOC_INDEX = -1;
+    });
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn calloc(number: u32, size: u32) -> *const u8 {
     trace!("calloc {} {}", number, size);
     let ptr = malloc(number * size);
+    if ptr.is_null() {
+        return ptr;
+    }
+
     let mut zp = ptr as *mut u8;
     for _ in 0..(number * size) {
         zp.write_volatile(0x00);
@@ -1,5 +1,6 @@
 pub mod common;
 pub mod malloc;
 pub mod queue;
+pub mod sync;
 pub mod timer_compat;
 pub mod work_queue;
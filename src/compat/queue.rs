@@ -1,7 +1,5 @@
 #![allow(unused)]
 
-use core::mem::MaybeUninit;
-
 pub struct SimpleQueue<T, const N: usize> {
     data: [Option<T>; N],
     read_index: usize,
@@ -10,19 +8,11 @@ pub struct SimpleQueue<T, const N: usize> {
 
 impl<T, const N: usize> SimpleQueue<T, N> {
     pub fn new() -> SimpleQueue<T, N> {
-        let mut queue = unsafe {
-            SimpleQueue {
-                data: MaybeUninit::uninit().assume_init(),
-                read_index: 0,
-                write_index: 0,
-            }
-        };
-
-        for i in 0..N {
-            queue.data[i] = None;
+        SimpleQueue {
+            data: [(); N].map(|_| None),
+            read_index: 0,
+            write_index: 0,
         }
-
-        queue
     }
 
     pub fn enqueue(&mut self, e: T) -> bool {
@@ -49,14 +39,32 @@ impl<T, const N: usize> SimpleQueue<T, N> {
         }
     }
 
+    pub fn peek(&self) -> Option<&T> {
+        if self.write_index == self.read_index {
+            None
+        } else {
+            self.data[self.read_index].as_ref()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        (self.write_index + N - self.read_index) % N
+    }
+
     pub fn is_empty(&self) -> bool {
         self.read_index == self.write_index
     }
 
     pub fn is_full(&self) -> bool {
-        let mut next_write = self.read_index + 1;
+        let mut next_write = self.write_index + 1;
         next_write %= N;
 
         next_write == self.read_index
     }
 }
+
+impl<T, const N: usize> Default for SimpleQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
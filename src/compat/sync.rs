@@ -0,0 +1,131 @@
+// Bookkeeping of the semaphores and event groups handed to the driver. Callers serialize access
+// (critical sections on the target) - this doesn't depend on anything else so the host tests in
+// `host-tests/` can run it with randomized interleavings.
+
+/// Counts of the semaphores, `None` for free slots
+pub struct Semaphores<const N: usize> {
+    counts: [Option<u32>; N],
+}
+
+impl<const N: usize> Semaphores<N> {
+    pub const fn new() -> Semaphores<N> {
+        Semaphores { counts: [None; N] }
+    }
+
+    /// Returns the index of the semaphore or `None` if all are used
+    pub fn create(&mut self, init: u32) -> Option<usize> {
+        let index = self.counts.iter().position(|count| count.is_none())?;
+        self.counts[index] = Some(init);
+        Some(index)
+    }
+
+    pub fn delete(&mut self, index: usize) {
+        self.counts[index] = None;
+    }
+
+    /// Returns false if the count is zero or the semaphore doesn't exist
+    pub fn try_take(&mut self, index: usize) -> bool {
+        match self.counts[index] {
+            Some(count) if count > 0 => {
+                self.counts[index] = Some(count - 1);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns false if the semaphore doesn't exist
+    pub fn give(&mut self, index: usize) -> bool {
+        match self.counts[index] {
+            Some(count) => {
+                self.counts[index] = Some(count + 1);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn count(&self, index: usize) -> Option<u32> {
+        self.counts[index]
+    }
+
+    pub fn reset(&mut self) {
+        self.counts = [None; N];
+    }
+}
+
+impl<const N: usize> Default for Semaphores<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Bits of the event groups, `None` for free slots
+pub struct EventGroups<const N: usize> {
+    bits: [Option<u32>; N],
+}
+
+impl<const N: usize> EventGroups<N> {
+    pub const fn new() -> EventGroups<N> {
+        EventGroups { bits: [None; N] }
+    }
+
+    /// Returns the index of the group or `None` if all are used
+    pub fn create(&mut self) -> Option<usize> {
+        let index = self.bits.iter().position(|bits| bits.is_none())?;
+        self.bits[index] = Some(0);
+        Some(index)
+    }
+
+    pub fn delete(&mut self, index: usize) {
+        self.bits[index] = None;
+    }
+
+    /// Returns the bits after setting them
+    pub fn set(&mut self, index: usize, bits: u32) -> u32 {
+        let group = self.bits[index].get_or_insert(0);
+        *group |= bits;
+        *group
+    }
+
+    /// Returns the bits before clearing them
+    pub fn clear(&mut self, index: usize, bits: u32) -> u32 {
+        let group = self.bits[index].get_or_insert(0);
+        let previous = *group;
+        *group &= !bits;
+        previous
+    }
+
+    /// Check if the bits a task waits for are set, clearing them if wanted. Returns the bits at
+    /// the time of the check and if the wait is over.
+    pub fn try_wait(
+        &mut self,
+        index: usize,
+        bits_to_wait_for: u32,
+        clear_on_exit: bool,
+        wait_for_all_bits: bool,
+    ) -> (u32, bool) {
+        let group = self.bits[index].get_or_insert(0);
+        let bits = *group;
+        let done = if wait_for_all_bits {
+            bits & bits_to_wait_for == bits_to_wait_for
+        } else {
+            bits & bits_to_wait_for != 0
+        };
+
+        if done && clear_on_exit {
+            *group &= !bits_to_wait_for;
+        }
+        (bits, done)
+    }
+
+    pub fn reset(&mut self) {
+        self.bits = [None; N];
+    }
+}
+
+impl<const N: usize> Default for EventGroups<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
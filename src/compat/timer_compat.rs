@@ -77,6 +77,14 @@ pub fn compat_timer_done(ptimer: *mut crate::binary::c_types::c_void) {
     });
 }
 
+/// Forget all timers after the driver was deinitialized, their callbacks point into memory
+/// that is freed.
+pub(crate) fn compat_timers_reset() {
+    critical_section::with(|_| unsafe {
+        TIMERS = [None; 20];
+    });
+}
+
 pub fn compat_timer_setfn(
     ptimer: *mut crate::binary::c_types::c_void,
     pfunction: *mut crate::binary::c_types::c_void,
@@ -1,15 +1,26 @@
-use super::queue::SimpleQueue;
 use crate::trace;
 
-static mut WORKER_HIGH: Option<
-    SimpleQueue<
-        (
-            extern "C" fn(*mut crate::binary::c_types::c_void),
-            *mut crate::binary::c_types::c_void,
-        ),
-        10,
-    >,
-> = None;
+const WORK_QUEUE_LEN: usize = 10;
+
+/// `configMAX_PRIORITIES` of ESP-IDF, the driver derives the priorities of its tasks from it
+pub const MAX_PRIORITY: i32 = 25;
+
+#[derive(Clone, Copy)]
+struct Work {
+    task_func: extern "C" fn(*mut crate::binary::c_types::c_void),
+    param: *mut crate::binary::c_types::c_void,
+    prio: u32,
+    // work of the same priority runs in the order it was queued
+    seq: u32,
+}
+
+static mut WORK: [Option<Work>; WORK_QUEUE_LEN] = [None; WORK_QUEUE_LEN];
+static mut NEXT_SEQ: u32 = 0;
+
+// priority of the work `do_work` runs, None while it's idle
+static mut RUNNING_PRIO: Option<u32> = None;
+// true while `do_preempting_work` runs work
+static mut PREEMPTING: bool = false;
 
 pub fn queue_work(
     task_func: *mut crate::binary::c_types::c_void,
@@ -28,52 +39,107 @@ pub fn queue_work(
     );
 
     critical_section::with(|_| unsafe {
-        if WORKER_HIGH.is_none() {
-            WORKER_HIGH = Some(SimpleQueue::new());
+        match WORK.iter_mut().find(|work| work.is_none()) {
+            Some(slot) => {
+                *slot = Some(Work {
+                    task_func: core::mem::transmute(task_func),
+                    param,
+                    prio,
+                    seq: NEXT_SEQ,
+                });
+                NEXT_SEQ = NEXT_SEQ.wrapping_add(1);
+            }
+            None => trace!("work queue full, dropping task {:p}", task_func),
         }
+    });
+}
 
-        let _ = &WORKER_HIGH
-            .as_mut()
-            .unwrap()
-            .enqueue((core::mem::transmute(task_func), param));
+/// Drop work queued by the driver after it was deinitialized
+pub(crate) fn work_queue_reset() {
+    critical_section::with(|_| unsafe {
+        WORK = [None; WORK_QUEUE_LEN];
     });
 }
 
-pub fn do_work() {
-    unsafe {
-        let mut todo: [Option<(
-            extern "C" fn(*mut crate::binary::c_types::c_void),
-            *mut crate::binary::c_types::c_void,
-        )>; 10] = [None; 10];
-
-        critical_section::with(|_| {
-            if WORKER_HIGH.is_none() {
-                return;
-            }
+// Index of the queued work with the highest priority above `above`, the oldest one of those
+unsafe fn next_work(above: Option<u32>) -> Option<usize> {
+    let mut next: Option<usize> = None;
+    for (i, work) in WORK.iter().enumerate() {
+        let work = match work {
+            Some(work) if above.map_or(true, |above| work.prio > above) => work,
+            _ => continue,
+        };
 
-            todo.iter_mut().for_each(|e| {
-                let work = &WORKER_HIGH.as_mut().unwrap().dequeue();
+        let better = match next.and_then(|next| WORK[next]) {
+            Some(best) => {
+                work.prio > best.prio
+                    || (work.prio == best.prio && (work.seq.wrapping_sub(best.seq) as i32) < 0)
+            }
+            None => true,
+        };
+        if better {
+            next = Some(i);
+        }
+    }
+    next
+}
 
-                match work {
-                    Some(worker) => {
-                        e.replace(*worker);
-                    }
-                    None => {}
-                }
-            });
+// Work is run one item at a time so work queued by a high priority task meanwhile runs
+// before lower priority work queued earlier
+pub fn do_work() {
+    loop {
+        let work = critical_section::with(|_| unsafe {
+            let work = next_work(None).and_then(|next| WORK[next].take());
+            RUNNING_PRIO = work.map(|work| work.prio);
+            work
         });
+        let work = match work {
+            Some(work) => work,
+            None => break,
+        };
 
-        for worker in todo.iter() {
-            match worker {
-                core::option::Option::Some((f, p)) => {
-                    trace!("before worker {:p} {:p}", f, *p);
+        trace!("before worker {:p} {:p}", work.task_func, work.param);
 
-                    f(*p);
+        (work.task_func)(work.param);
 
-                    crate::println!("after worker");
-                }
-                core::option::Option::None => {}
-            }
+        trace!("after worker");
+    }
+}
+
+/// True if work with a higher priority than the one `do_work` runs is queued and the preempting
+/// worker is idle. Called by the scheduler to switch to the preempting worker right away.
+pub fn preempting_work_pending() -> bool {
+    critical_section::with(|_| unsafe {
+        match RUNNING_PRIO {
+            Some(running) => !PREEMPTING && next_work(Some(running)).is_some(),
+            None => false,
         }
+    })
+}
+
+/// Run work with a higher priority than the one `do_work` runs, preempting it
+pub fn do_preempting_work() {
+    loop {
+        let work = critical_section::with(|_| unsafe {
+            let work = RUNNING_PRIO
+                .and_then(|running| next_work(Some(running)))
+                .and_then(|next| WORK[next].take());
+            PREEMPTING = work.is_some();
+            work
+        });
+        let work = match work {
+            Some(work) => work,
+            None => break,
+        };
+
+        trace!(
+            "before preempting worker {:p} {:p}",
+            work.task_func,
+            work.param
+        );
+
+        (work.task_func)(work.param);
+
+        trace!("after preempting worker");
     }
 }
@@ -0,0 +1,45 @@
+use crate::{
+    compat::malloc::heap_used,
+    timer::get_systimer_count,
+    wifi::{
+        is_connected, reconnect::reconnect_attempts, wifi_events_dropped, wifi_queue_stats,
+        wifi_rssi, wifi_rx_dropped_count, wifi_tx_dropped_count, QueueStats,
+    },
+};
+
+/// Health of the WiFi stack at one point in time, e.g. to report to a fleet monitoring system.
+///
+/// Enable the `serde` feature to serialize it. CPU usage of the tasks isn't tracked by the
+/// scheduler and the sockets belong to the application, so neither is part of it.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Snapshot {
+    pub uptime_ms: u64,
+    pub connected: bool,
+    /// dBm, `None` while not connected
+    pub rssi: Option<i8>,
+    /// Reconnect attempts since the station was last connected
+    pub reconnect_attempts: u32,
+    pub rx_dropped: u32,
+    pub tx_dropped: u32,
+    pub events_dropped: u32,
+    pub queue_stats: QueueStats,
+    /// Bytes allocated by the driver
+    pub heap_used: usize,
+}
+
+pub fn snapshot() -> Snapshot {
+    let connected = is_connected();
+
+    Snapshot {
+        uptime_ms: get_systimer_count() / 16_000,
+        connected,
+        rssi: if connected { wifi_rssi().ok() } else { None },
+        reconnect_attempts: reconnect_attempts(),
+        rx_dropped: wifi_rx_dropped_count(),
+        tx_dropped: wifi_tx_dropped_count(),
+        events_dropped: wifi_events_dropped(),
+        queue_stats: wifi_queue_stats(),
+        heap_used: heap_used(),
+    }
+}
@@ -1,9 +1,14 @@
 #![no_std]
 #![feature(c_variadic)]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 pub mod binary;
 pub mod compat;
+pub mod diagnostics;
 pub mod log;
+pub mod net;
 pub mod preempt;
 pub mod timer;
 pub mod wifi;
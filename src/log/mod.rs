@@ -1,3 +1,11 @@
+pub mod usb_serial_jtag;
+
+// where the log macros write to
+#[cfg(not(feature = "log-usb-serial-jtag"))]
+pub use crate::Uart as LogWriter;
+#[cfg(feature = "log-usb-serial-jtag")]
+pub use usb_serial_jtag::UsbSerialJtag as LogWriter;
+
 #[derive(PartialEq, PartialOrd, Clone, Copy)]
 pub enum LogLevel {
     None,
@@ -17,10 +25,10 @@ macro_rules! trace {
                 use core::fmt::Write;
 
                 unsafe {
-                    write!(crate::Uart, "{}: ", $crate::preempt::current_task()).ok();
+                    write!($crate::log::LogWriter, "{}: ", $crate::preempt::current_task()).ok();
                 }
-                write!(crate::Uart, $($arg)*).ok();
-                write!(crate::Uart, "\r\n").ok();
+                write!($crate::log::LogWriter, $($arg)*).ok();
+                write!($crate::log::LogWriter, "\r\n").ok();
             });
         }
     };
@@ -35,10 +43,10 @@ macro_rules! verbose {
                 use core::fmt::Write;
 
                 unsafe {
-                    write!(crate::Uart, "{}: ", $crate::preempt::current_task()).ok();
+                    write!($crate::log::LogWriter, "{}: ", $crate::preempt::current_task()).ok();
                 }
-                write!(crate::Uart, $($arg)*).ok();
-                write!(crate::Uart, "\r\n").ok();
+                write!($crate::log::LogWriter, $($arg)*).ok();
+                write!($crate::log::LogWriter, "\r\n").ok();
             });
         }
     };
@@ -53,10 +61,10 @@ macro_rules! debug {
                 use core::fmt::Write;
 
                 unsafe {
-                    write!(crate::Uart, "{}: ", $crate::preempt::current_task()).ok();
+                    write!($crate::log::LogWriter, "{}: ", $crate::preempt::current_task()).ok();
                 }
-                write!(crate::Uart, $($arg)*).ok();
-                write!(crate::Uart, "\r\n").ok();
+                write!($crate::log::LogWriter, $($arg)*).ok();
+                write!($crate::log::LogWriter, "\r\n").ok();
             });
         }
     };
@@ -69,8 +77,8 @@ macro_rules! println {
         unsafe {
             use core::fmt::Write;
 
-            write!(crate::Uart, $($arg)*).ok();
-            write!(crate::Uart, "\r\n").ok();
+            write!($crate::log::LogWriter, $($arg)*).ok();
+            write!($crate::log::LogWriter, "\r\n").ok();
         }
     };
 }
@@ -81,6 +89,6 @@ macro_rules! print {
         use core::fmt::Write;
 
         #[allow(unused_unsafe)]
-        write!(crate::Uart, $($arg)*).ok();
+        write!($crate::log::LogWriter, $($arg)*).ok();
     };
 }
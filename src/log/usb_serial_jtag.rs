@@ -0,0 +1,82 @@
+// USB_SERIAL_JTAG_EP1_REG
+const EP1: *mut u32 = 0x6004_3000 as *mut u32;
+// USB_SERIAL_JTAG_EP1_CONF_REG
+const EP1_CONF: *mut u32 = 0x6004_3004 as *mut u32;
+
+const EP1_CONF_WR_DONE: u32 = 1 << 0;
+const EP1_CONF_SERIAL_IN_EP_DATA_FREE: u32 = 1 << 1;
+
+// how often to check for free space in the FIFO before giving up
+const TIMEOUT_LOOPS: u32 = 50_000;
+
+// set once the host didn't read the FIFO in time - don't wait again until it does
+static mut TIMED_OUT: bool = false;
+
+/// Writes to the USB-serial-JTAG peripheral - no second UART or USB-UART bridge is needed
+/// to stream logs or other diagnostic data.
+///
+/// Waits for the host to read the 64 byte FIFO before writing more. If no host reads it
+/// (e.g. the USB cable isn't connected) the data is dropped instead of blocking forever.
+pub struct UsbSerialJtag;
+
+impl UsbSerialJtag {
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        for &b in data {
+            if !wait_for_fifo() {
+                return;
+            }
+
+            unsafe {
+                EP1.write_volatile(b as u32);
+            }
+        }
+
+        self.flush();
+    }
+
+    /// Hand the FIFO contents to the host
+    pub fn flush(&mut self) {
+        unsafe {
+            EP1_CONF.write_volatile(EP1_CONF_WR_DONE);
+        }
+    }
+}
+
+fn fifo_has_room() -> bool {
+    unsafe { EP1_CONF.read_volatile() & EP1_CONF_SERIAL_IN_EP_DATA_FREE != 0 }
+}
+
+fn wait_for_fifo() -> bool {
+    if fifo_has_room() {
+        unsafe {
+            TIMED_OUT = false;
+        }
+        return true;
+    }
+
+    if unsafe { TIMED_OUT } {
+        return false;
+    }
+
+    unsafe {
+        EP1_CONF.write_volatile(EP1_CONF_WR_DONE);
+    }
+
+    for _ in 0..TIMEOUT_LOOPS {
+        if fifo_has_room() {
+            return true;
+        }
+    }
+
+    unsafe {
+        TIMED_OUT = true;
+    }
+    false
+}
+
+impl core::fmt::Write for UsbSerialJtag {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+use smoltcp::{
+    socket::{SocketHandle, SocketSet, TcpSocket},
+    time::{Duration, Instant},
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+
+use crate::debug;
+
+const REQUEST_MAX_LEN: usize = 256;
+
+/// URL to probe, e.g. `connectivitycheck.gstatic.com` / `/generate_204` which answers with
+/// status 204. The address of the host can be looked up via `net::dns`.
+#[derive(Debug, Clone, Copy)]
+pub struct ProbeTarget<'a> {
+    pub addr: Ipv4Address,
+    pub port: u16,
+    /// Sent in the `Host` header
+    pub host: &'a str,
+    pub path: &'a str,
+    /// Status the target answers with if the traffic isn't intercepted
+    pub expected_status: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PortalStatus {
+    /// The probe didn't finish yet
+    Pending,
+    /// The expected answer was received - the network isn't intercepting traffic
+    Open,
+    /// Something else answered, most likely a captive portal (e.g. on hotel or guest WiFi)
+    Intercepted { status: u16 },
+    /// No answer in time or the connection failed
+    Unreachable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Connecting,
+    Sending,
+    Receiving,
+    Done(PortalStatus),
+}
+
+/// Probes a known URL via plain HTTP after the station got an IP address to find out if the
+/// network intercepts traffic. Devices can then alert the user instead of futilely retrying
+/// TLS connections.
+///
+/// Uses a TCP socket of the application's socket set. Call `poll` after polling the interface
+/// until it returns something else than `PortalStatus::Pending`.
+pub struct CaptivePortalProbe {
+    handle: SocketHandle,
+    local_port: u16,
+    timeout: Duration,
+    request: [u8; REQUEST_MAX_LEN],
+    request_len: usize,
+    sent: usize,
+    response: [u8; 16],
+    response_len: usize,
+    remote: IpEndpoint,
+    expected_status: u16,
+    started: Option<Instant>,
+    state: State,
+}
+
+impl CaptivePortalProbe {
+    /// Returns `None` if the request doesn't fit into the request buffer (256 bytes).
+    pub fn new(
+        target: &ProbeTarget,
+        handle: SocketHandle,
+        local_port: u16,
+        timeout: Duration,
+    ) -> Option<CaptivePortalProbe> {
+        let mut request = [0u8; REQUEST_MAX_LEN];
+        let mut request_len = 0;
+        for part in [
+            "GET ",
+            target.path,
+            " HTTP/1.0\r\nHost: ",
+            target.host,
+            "\r\nConnection: close\r\n\r\n",
+        ] {
+            let end = request_len + part.len();
+            if end > REQUEST_MAX_LEN {
+                return None;
+            }
+            request[request_len..end].copy_from_slice(part.as_bytes());
+            request_len = end;
+        }
+
+        Some(CaptivePortalProbe {
+            handle,
+            local_port,
+            timeout,
+            request,
+            request_len,
+            sent: 0,
+            response: [0u8; 16],
+            response_len: 0,
+            remote: IpEndpoint::new(IpAddress::Ipv4(target.addr), target.port),
+            expected_status: target.expected_status,
+            started: None,
+            state: State::Idle,
+        })
+    }
+
+    pub fn poll(&mut self, sockets: &mut SocketSet, now: Instant) -> PortalStatus {
+        if let State::Done(status) = self.state {
+            return status;
+        }
+
+        let mut socket = sockets.get::<TcpSocket>(self.handle);
+
+        let started = *self.started.get_or_insert(now);
+        if now - started > self.timeout {
+            debug!("captive portal probe timed out");
+            socket.abort();
+            return self.finish(PortalStatus::Unreachable);
+        }
+
+        match self.state {
+            State::Idle => {
+                if socket.connect(self.remote, self.local_port).is_err() {
+                    return self.finish(PortalStatus::Unreachable);
+                }
+                self.state = State::Connecting;
+            }
+            State::Connecting => {
+                if socket.may_send() {
+                    self.state = State::Sending;
+                } else if !socket.is_open() {
+                    return self.finish(PortalStatus::Unreachable);
+                }
+            }
+            State::Sending => {
+                if socket.can_send() {
+                    match socket.send_slice(&self.request[self.sent..self.request_len]) {
+                        Ok(sent) => self.sent += sent,
+                        Err(_) => return self.finish(PortalStatus::Unreachable),
+                    }
+                }
+                if self.sent == self.request_len {
+                    self.state = State::Receiving;
+                }
+            }
+            State::Receiving => {
+                if socket.can_recv() {
+                    let buffer = &mut self.response[self.response_len..];
+                    if let Ok(received) = socket.recv_slice(buffer) {
+                        self.response_len += received;
+                    }
+                }
+
+                // the status line is "HTTP/1.x NNN ..."
+                if self.response_len >= 12 || !socket.may_recv() {
+                    socket.close();
+                    let status = parse_status(&self.response[..self.response_len]);
+                    return match status {
+                        Some(status) if status == self.expected_status => {
+                            self.finish(PortalStatus::Open)
+                        }
+                        Some(status) => self.finish(PortalStatus::Intercepted { status }),
+                        None => self.finish(PortalStatus::Unreachable),
+                    };
+                }
+            }
+            State::Done(_) => (),
+        }
+
+        PortalStatus::Pending
+    }
+
+    fn finish(&mut self, status: PortalStatus) -> PortalStatus {
+        debug!("captive portal probe result {:?}", status);
+        self.state = State::Done(status);
+        status
+    }
+}
+
+fn parse_status(response: &[u8]) -> Option<u16> {
+    if response.len() < 12 || !response.starts_with(b"HTTP/1.") || response[8] != b' ' {
+        return None;
+    }
+
+    let mut status = 0u16;
+    for digit in &response[9..12] {
+        if !digit.is_ascii_digit() {
+            return None;
+        }
+        status = status * 10 + (digit - b'0') as u16;
+    }
+    Some(status)
+}
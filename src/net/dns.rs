@@ -0,0 +1,152 @@
+use smoltcp::{
+    iface::EthernetInterface,
+    phy::Device,
+    socket::{SocketHandle, SocketSet, UdpSocket},
+    time::{Duration, Instant},
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+
+use super::dns_wire::{encode_query, parse_response, Answer, QUERY_MAX_LEN};
+use crate::{debug, timer::get_systimer_count, wifi::fill_random};
+
+const DNS_PORT: u16 = 53;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DnsStatus {
+    /// No answer yet
+    Pending,
+    Resolved(Ipv4Address),
+    /// The name doesn't exist or has no IPv4 address
+    NotFound,
+    /// No answer in time or the query couldn't be sent
+    Failed,
+}
+
+/// Resolves a host name to an IPv4 address by asking a DNS server (e.g. one handed out by DHCP).
+///
+/// Uses a UDP socket of the application's socket set. Call `poll` after polling the interface
+/// until it returns something else than `DnsStatus::Pending` or use `resolve` to block.
+pub struct DnsQuery {
+    handle: SocketHandle,
+    local_port: u16,
+    timeout: Duration,
+    server: IpEndpoint,
+    query: [u8; QUERY_MAX_LEN],
+    query_len: usize,
+    id: u16,
+    started: Option<Instant>,
+    sent: bool,
+    status: DnsStatus,
+}
+
+impl DnsQuery {
+    /// Returns `None` if `name` isn't a valid host name.
+    pub fn new(
+        name: &str,
+        server: Ipv4Address,
+        handle: SocketHandle,
+        local_port: u16,
+        timeout: Duration,
+    ) -> Option<DnsQuery> {
+        let mut id = [0u8; 2];
+        fill_random(&mut id);
+        let id = u16::from_be_bytes(id);
+
+        let mut query = [0u8; QUERY_MAX_LEN];
+        let len = encode_query(name, id, &mut query)?;
+
+        Some(DnsQuery {
+            handle,
+            local_port,
+            timeout,
+            server: IpEndpoint::new(IpAddress::Ipv4(server), DNS_PORT),
+            query,
+            query_len: len,
+            id,
+            started: None,
+            sent: false,
+            status: DnsStatus::Pending,
+        })
+    }
+
+    pub fn poll(&mut self, sockets: &mut SocketSet, now: Instant) -> DnsStatus {
+        if self.status != DnsStatus::Pending {
+            return self.status;
+        }
+
+        let mut socket = sockets.get::<UdpSocket>(self.handle);
+
+        let started = *self.started.get_or_insert(now);
+        if now - started > self.timeout {
+            debug!("DNS query timed out");
+            socket.close();
+            return self.finish(DnsStatus::Failed);
+        }
+
+        if !socket.is_open() && socket.bind(self.local_port).is_err() {
+            return self.finish(DnsStatus::Failed);
+        }
+
+        if !self.sent {
+            if socket.can_send() {
+                if socket
+                    .send_slice(&self.query[..self.query_len], self.server)
+                    .is_err()
+                {
+                    socket.close();
+                    return self.finish(DnsStatus::Failed);
+                }
+                self.sent = true;
+            }
+            return DnsStatus::Pending;
+        }
+
+        while socket.can_recv() {
+            let status = match socket.recv() {
+                Ok((response, endpoint)) if endpoint == self.server => {
+                    parse_response(response, self.id).map(|answer| match answer {
+                        Answer::Address(address) => {
+                            DnsStatus::Resolved(Ipv4Address::from_bytes(&address))
+                        }
+                        Answer::NotFound => DnsStatus::NotFound,
+                    })
+                }
+                Ok(_) => continue,
+                Err(_) => break,
+            };
+
+            if let Some(status) = status {
+                socket.close();
+                return self.finish(status);
+            }
+        }
+
+        DnsStatus::Pending
+    }
+
+    fn finish(&mut self, status: DnsStatus) -> DnsStatus {
+        debug!("DNS query result {:?}", status);
+        self.status = status;
+        status
+    }
+}
+
+/// Block until `query` is answered or timed out, polling the interface meanwhile
+pub fn resolve<DeviceT>(
+    iface: &mut EthernetInterface<DeviceT>,
+    sockets: &mut SocketSet,
+    query: &mut DnsQuery,
+) -> DnsStatus
+where
+    DeviceT: for<'d> Device<'d>,
+{
+    loop {
+        let now = Instant::from_millis((get_systimer_count() / 16_000) as i64);
+        iface.poll(sockets, now).ok();
+
+        let status = query.poll(sockets, now);
+        if status != DnsStatus::Pending {
+            return status;
+        }
+    }
+}
@@ -0,0 +1,106 @@
+// Encoding of DNS queries and parsing of the answers. This doesn't depend on smoltcp or the
+// target so the host tests in `host-tests/` can run it.
+
+pub const HEADER_LEN: usize = 12;
+// header + encoded name (at most 255 bytes) + type and class
+pub const QUERY_MAX_LEN: usize = HEADER_LEN + 255 + 4;
+
+pub const TYPE_A: u16 = 1;
+pub const CLASS_IN: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Answer {
+    Address([u8; 4]),
+    /// The name doesn't exist or has no IPv4 address
+    NotFound,
+}
+
+/// Write a query for the A record of `name` into `query`, returns the length or `None` if
+/// `name` isn't a valid host name.
+pub fn encode_query(name: &str, id: u16, query: &mut [u8; QUERY_MAX_LEN]) -> Option<usize> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() || name.len() > 253 {
+        return None;
+    }
+
+    *query = [0u8; QUERY_MAX_LEN];
+    query[0..2].copy_from_slice(&id.to_be_bytes());
+    // standard query, recursion desired
+    query[2] = 0x01;
+    // one question
+    query[5] = 1;
+
+    let mut len = HEADER_LEN;
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        query[len] = label.len() as u8;
+        query[len + 1..len + 1 + label.len()].copy_from_slice(label.as_bytes());
+        len += 1 + label.len();
+    }
+    // root label
+    query[len] = 0;
+    len += 1;
+    query[len..len + 2].copy_from_slice(&TYPE_A.to_be_bytes());
+    query[len + 2..len + 4].copy_from_slice(&CLASS_IN.to_be_bytes());
+    len += 4;
+
+    Some(len)
+}
+
+/// `None` if the response isn't the answer to the query
+pub fn parse_response(response: &[u8], id: u16) -> Option<Answer> {
+    if response.len() < HEADER_LEN || response[0..2] != id.to_be_bytes() {
+        return None;
+    }
+
+    // QR bit - it's a response
+    if response[2] & 0x80 == 0 {
+        return None;
+    }
+
+    let rcode = response[3] & 0x0f;
+    if rcode != 0 {
+        return Some(Answer::NotFound);
+    }
+
+    let questions = u16::from_be_bytes([response[4], response[5]]);
+    let answers = u16::from_be_bytes([response[6], response[7]]);
+
+    let mut pos = HEADER_LEN;
+    for _ in 0..questions {
+        pos = skip_name(response, pos)? + 4;
+    }
+
+    for _ in 0..answers {
+        pos = skip_name(response, pos)?;
+        let record = response.get(pos..pos + 10)?;
+        let record_type = u16::from_be_bytes([record[0], record[1]]);
+        let class = u16::from_be_bytes([record[2], record[3]]);
+        let data_len = u16::from_be_bytes([record[8], record[9]]) as usize;
+        pos += 10;
+
+        let data = response.get(pos..pos + data_len)?;
+        // CNAMEs are followed by the records of the canonical name
+        if record_type == TYPE_A && class == CLASS_IN && data_len == 4 {
+            return Some(Answer::Address([data[0], data[1], data[2], data[3]]));
+        }
+        pos += data_len;
+    }
+
+    Some(Answer::NotFound)
+}
+
+/// Position after the (possibly compressed) name starting at `pos`
+pub fn skip_name(response: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *response.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            // pointer to a name elsewhere, it ends the name
+            len if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
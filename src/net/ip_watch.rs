@@ -0,0 +1,57 @@
+use smoltcp::wire::{IpCidr, Ipv4Cidr};
+
+use crate::{debug, wifi::is_connected};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpChange {
+    /// The station got an address, e.g. from DHCP
+    Acquired(Ipv4Cidr),
+    /// The address changed, e.g. the DHCP lease was renewed with a different address.
+    /// Sockets bound to the old address need to be re-established.
+    Changed { old: Ipv4Cidr, new: Ipv4Cidr },
+    /// The address was removed or the station disconnected
+    Lost(Ipv4Cidr),
+}
+
+/// Notices changes of the station's IPv4 address so long-lived sockets can be re-established
+/// right away instead of waiting for them to time out.
+///
+/// Call `update` with the interface's addresses (`EthernetInterface::ip_addrs`) after polling it.
+/// The unspecified address (used while there is no DHCP lease) counts as no address.
+#[derive(Debug, Default)]
+pub struct IpWatcher {
+    current: Option<Ipv4Cidr>,
+}
+
+impl IpWatcher {
+    pub fn new() -> IpWatcher {
+        IpWatcher { current: None }
+    }
+
+    /// The address seen by the last `update`
+    pub fn current(&self) -> Option<Ipv4Cidr> {
+        self.current
+    }
+
+    pub fn update(&mut self, addrs: &[IpCidr]) -> Option<IpChange> {
+        let address = if is_connected() {
+            addrs.iter().find_map(|addr| match addr {
+                IpCidr::Ipv4(cidr) if !cidr.address().is_unspecified() => Some(*cidr),
+                _ => None,
+            })
+        } else {
+            None
+        };
+
+        let change = match (self.current, address) {
+            (None, Some(new)) => IpChange::Acquired(new),
+            (Some(old), Some(new)) if old != new => IpChange::Changed { old, new },
+            (Some(old), None) => IpChange::Lost(old),
+            _ => return None,
+        };
+
+        debug!("IP address change {:?}", change);
+        self.current = address;
+        Some(change)
+    }
+}
@@ -0,0 +1,117 @@
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    socket::{RawSocket, SocketHandle, SocketSet},
+    wire::{
+        Icmpv6Packet, Icmpv6Repr, IpAddress, Ipv6Address, Ipv6Cidr, Ipv6Packet,
+        NdiscPrefixInfoFlags, NdiscRepr,
+    },
+};
+
+use crate::debug;
+
+/// Link-local address (fe80::/64) derived from the MAC address as modified EUI-64 (RFC 4291).
+/// Add it to the interface's addresses to talk to neighbours and routers.
+pub fn link_local_address(mac: &[u8; 6]) -> Ipv6Cidr {
+    let mut prefix = [0u8; 16];
+    prefix[0] = 0xfe;
+    prefix[1] = 0x80;
+    address_in_prefix(&Ipv6Address(prefix), mac)
+}
+
+/// Address in the /64 `prefix` derived from the MAC address the same way as the link-local one
+fn address_in_prefix(prefix: &Ipv6Address, mac: &[u8; 6]) -> Ipv6Cidr {
+    let mut address = [0u8; 16];
+    address[..8].copy_from_slice(&prefix.0[..8]);
+    address[8] = mac[0] ^ 0x02;
+    address[9] = mac[1];
+    address[10] = mac[2];
+    address[11] = 0xff;
+    address[12] = 0xfe;
+    address[13] = mac[3];
+    address[14] = mac[4];
+    address[15] = mac[5];
+    Ipv6Cidr::new(Ipv6Address(address), 64)
+}
+
+/// Global address and default router learned from a router advertisement
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SlaacConfig {
+    pub address: Ipv6Cidr,
+    pub router: Ipv6Address,
+}
+
+/// Stateless address autoconfiguration (RFC 4862): listens for router advertisements and
+/// derives a global address from the announced prefix.
+///
+/// Uses a raw socket (`IpVersion::Ipv6`, `IpProtocol::Icmpv6`) of the application's socket set.
+/// Call `poll` after polling the interface, it returns the configuration when it changed.
+/// The interface needs the link-local address. Routers advertise periodically (every few minutes
+/// at most), router solicitations aren't sent.
+pub struct Slaac {
+    handle: SocketHandle,
+    mac: [u8; 6],
+    config: Option<SlaacConfig>,
+}
+
+impl Slaac {
+    pub fn new(handle: SocketHandle, mac: [u8; 6]) -> Slaac {
+        Slaac {
+            handle,
+            mac,
+            config: None,
+        }
+    }
+
+    pub fn config(&self) -> Option<SlaacConfig> {
+        self.config
+    }
+
+    pub fn poll(&mut self, sockets: &mut SocketSet) -> Option<SlaacConfig> {
+        let mut socket = sockets.get::<RawSocket>(self.handle);
+
+        let mut changed = None;
+        while let Ok(packet) = socket.recv() {
+            if let Some(config) = self.parse_router_advertisement(packet) {
+                if self.config != Some(config) {
+                    debug!("SLAAC config {:?}", config);
+                    self.config = Some(config);
+                    changed = Some(config);
+                }
+            }
+        }
+
+        changed
+    }
+
+    fn parse_router_advertisement(&self, packet: &[u8]) -> Option<SlaacConfig> {
+        let ip = Ipv6Packet::new_checked(packet).ok()?;
+        // router advertisements are only valid from a link-local source with a hop limit of 255
+        if ip.hop_limit() != 255 || !ip.src_addr().is_link_local() {
+            return None;
+        }
+
+        let icmp = Icmpv6Packet::new_checked(ip.payload()).ok()?;
+        let repr = Icmpv6Repr::parse(
+            &IpAddress::Ipv6(ip.src_addr()),
+            &IpAddress::Ipv6(ip.dst_addr()),
+            &icmp,
+            &ChecksumCapabilities::default(),
+        )
+        .ok()?;
+
+        match repr {
+            Icmpv6Repr::Ndisc(NdiscRepr::RouterAdvert {
+                prefix_info: Some(prefix_info),
+                ..
+            }) if prefix_info.prefix_len == 64
+                && prefix_info.flags.contains(NdiscPrefixInfoFlags::ADDRCONF) =>
+            {
+                Some(SlaacConfig {
+                    address: address_in_prefix(&prefix_info.prefix, &self.mac),
+                    router: ip.src_addr(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
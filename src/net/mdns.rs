@@ -0,0 +1,392 @@
+use smoltcp::{
+    socket::{SocketHandle, SocketSet, UdpSocket},
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+
+use crate::debug;
+
+/// Multicast group mDNS uses, the interface needs to join it (`join_multicast_group`)
+pub const MDNS_GROUP: Ipv4Address = Ipv4Address([224, 0, 0, 251]);
+pub const MDNS_PORT: u16 = 5353;
+
+const MESSAGE_MAX_LEN: usize = 512;
+const HEADER_LEN: usize = 12;
+const NAME_MAX_LEN: usize = 255;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+// set in answers for records only we own
+const CLASS_CACHE_FLUSH: u16 = 0x8000;
+
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+
+const SERVICES_ENUMERATION: &str = "_services._dns-sd._udp";
+const LOCAL: &str = "local";
+
+/// A service advertised via DNS-SD, e.g. a web server
+#[derive(Debug, Clone, Copy)]
+pub struct MdnsService<'a> {
+    /// Name shown to the user, e.g. `Living Room Sensor`
+    pub instance: &'a str,
+    /// Service type and protocol, e.g. `_http._tcp`
+    pub service: &'a str,
+    pub port: u16,
+    /// `key=value` pairs
+    pub txt: &'a [&'a str],
+}
+
+/// Answers mDNS queries for `<hostname>.local` and the given services so the device can be found
+/// on the LAN without knowing its address.
+///
+/// Uses a UDP socket of the application's socket set. The interface needs to join `MDNS_GROUP`.
+/// Call `poll` after polling the interface. The records are announced whenever the address changes.
+pub struct MdnsResponder<'a> {
+    hostname: &'a str,
+    services: &'a [MdnsService<'a>],
+    handle: SocketHandle,
+    announced: Option<Ipv4Address>,
+}
+
+impl<'a> MdnsResponder<'a> {
+    pub fn new(
+        hostname: &'a str,
+        services: &'a [MdnsService<'a>],
+        handle: SocketHandle,
+    ) -> MdnsResponder<'a> {
+        MdnsResponder {
+            hostname,
+            services,
+            handle,
+            announced: None,
+        }
+    }
+
+    /// `address` is the current address of the interface, `None` while there is none
+    pub fn poll(&mut self, sockets: &mut SocketSet, address: Option<Ipv4Address>) {
+        let mut socket = sockets.get::<UdpSocket>(self.handle);
+        if !socket.is_open() && socket.bind(MDNS_PORT).is_err() {
+            return;
+        }
+
+        let address = match address {
+            Some(address) if !address.is_unspecified() => address,
+            _ => {
+                self.announced = None;
+                // drop queries received meanwhile
+                while socket.recv().is_ok() {}
+                return;
+            }
+        };
+
+        if self.announced != Some(address) && socket.can_send() {
+            let mut response = Response::new(0);
+            self.answer(&mut response, &[], TYPE_ANY, address, true);
+            self.send(&mut socket, &response, None);
+            self.announced = Some(address);
+        }
+
+        while socket.can_recv() {
+            let (query, source) = match socket.recv() {
+                Ok((query, source)) => (query, source),
+                Err(_) => break,
+            };
+
+            // a query from a port other than 5353 is a "legacy" unicast query
+            let legacy = source.port != MDNS_PORT;
+            let id = if legacy && query.len() >= 2 {
+                u16::from_be_bytes([query[0], query[1]])
+            } else {
+                0
+            };
+
+            let mut response = Response::new(id);
+            if self.answer_query(query, &mut response, address) {
+                let destination = if legacy { Some(source) } else { None };
+                self.send(&mut socket, &response, destination);
+            }
+        }
+    }
+
+    /// Adds the answers to the questions of `query`, returns true if there are any
+    fn answer_query(&self, query: &[u8], response: &mut Response, address: Ipv4Address) -> bool {
+        // only standard queries
+        if query.len() < HEADER_LEN || query[2] & 0xf8 != 0 {
+            return false;
+        }
+
+        let questions = u16::from_be_bytes([query[4], query[5]]);
+        let mut pos = HEADER_LEN;
+        for _ in 0..questions {
+            let mut name = [0u8; NAME_MAX_LEN];
+            let (name_len, next) = match read_name(query, pos, &mut name) {
+                Some(name) => name,
+                None => return response.answers > 0,
+            };
+            let question = match query.get(next..next + 4) {
+                Some(question) => question,
+                None => return response.answers > 0,
+            };
+            let record_type = u16::from_be_bytes([question[0], question[1]]);
+            pos = next + 4;
+
+            self.answer(response, &name[..name_len], record_type, address, false);
+        }
+
+        response.answers > 0
+    }
+
+    /// Add the records matching `name` / `record_type`. With `all` every record is added,
+    /// used for the announcements.
+    fn answer(
+        &self,
+        response: &mut Response,
+        name: &[u8],
+        record_type: u16,
+        address: Ipv4Address,
+        all: bool,
+    ) {
+        let any = record_type == TYPE_ANY;
+
+        if all || ((any || record_type == TYPE_A) && name_eq(name, &[self.hostname, LOCAL])) {
+            response.a_record(self.hostname, address);
+        }
+
+        if all
+            || ((any || record_type == TYPE_PTR) && name_eq(name, &[SERVICES_ENUMERATION, LOCAL]))
+        {
+            for service in self.services {
+                response.record(
+                    &[SERVICES_ENUMERATION, LOCAL],
+                    TYPE_PTR,
+                    CLASS_IN,
+                    SERVICE_TTL,
+                    |response| response.name(&[service.service, LOCAL]),
+                );
+            }
+        }
+
+        for service in self.services {
+            if all || ((any || record_type == TYPE_PTR) && name_eq(name, &[service.service, LOCAL]))
+            {
+                response.record(
+                    &[service.service, LOCAL],
+                    TYPE_PTR,
+                    CLASS_IN,
+                    SERVICE_TTL,
+                    |response| response.name(&[service.instance, service.service, LOCAL]),
+                );
+            }
+
+            let instance = name_eq(name, &[service.instance, service.service, LOCAL]);
+            if all || ((any || record_type == TYPE_SRV) && instance) {
+                response.srv_record(service, self.hostname);
+            }
+            if all || ((any || record_type == TYPE_TXT) && instance) {
+                response.txt_record(service);
+            }
+        }
+    }
+
+    fn send(&self, socket: &mut UdpSocket, response: &Response, to: Option<IpEndpoint>) {
+        let to = to.unwrap_or(IpEndpoint::new(IpAddress::Ipv4(MDNS_GROUP), MDNS_PORT));
+        if socket.send_slice(response.finish(), to).is_err() {
+            debug!("failed to send mDNS response");
+        }
+    }
+}
+
+struct Response {
+    buffer: [u8; MESSAGE_MAX_LEN],
+    len: usize,
+    answers: u16,
+    // set if a record didn't fit, writing stops then
+    full: bool,
+}
+
+impl Response {
+    fn new(id: u16) -> Response {
+        let mut buffer = [0u8; MESSAGE_MAX_LEN];
+        buffer[0..2].copy_from_slice(&id.to_be_bytes());
+        // response, authoritative answer
+        buffer[2] = 0x84;
+
+        Response {
+            buffer,
+            len: HEADER_LEN,
+            answers: 0,
+            full: false,
+        }
+    }
+
+    fn finish(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    fn put(&mut self, data: &[u8]) {
+        if self.len + data.len() > MESSAGE_MAX_LEN {
+            self.full = true;
+            return;
+        }
+        self.buffer[self.len..self.len + data.len()].copy_from_slice(data);
+        self.len += data.len();
+    }
+
+    /// Write the name made of `parts`, each of them can contain several labels
+    fn name(&mut self, parts: &[&str]) {
+        for part in parts {
+            for label in part.split('.') {
+                let label = &label.as_bytes()[..label.len().min(63)];
+                self.put(&[label.len() as u8]);
+                self.put(label);
+            }
+        }
+        self.put(&[0]);
+    }
+
+    fn record(
+        &mut self,
+        name: &[&str],
+        record_type: u16,
+        class: u16,
+        ttl: u32,
+        data: impl FnOnce(&mut Response),
+    ) {
+        if self.full {
+            return;
+        }
+
+        let start = self.len;
+        self.name(name);
+        self.put(&record_type.to_be_bytes());
+        self.put(&class.to_be_bytes());
+        self.put(&ttl.to_be_bytes());
+        self.put(&[0, 0]);
+        let data_start = self.len;
+        data(self);
+
+        if self.full {
+            // drop the partially written record
+            self.len = start;
+            return;
+        }
+
+        let data_len = (self.len - data_start) as u16;
+        self.buffer[data_start - 2..data_start].copy_from_slice(&data_len.to_be_bytes());
+        self.answers += 1;
+        self.buffer[6..8].copy_from_slice(&self.answers.to_be_bytes());
+    }
+
+    fn a_record(&mut self, hostname: &str, address: Ipv4Address) {
+        self.record(
+            &[hostname, LOCAL],
+            TYPE_A,
+            CLASS_IN | CLASS_CACHE_FLUSH,
+            HOST_TTL,
+            |response| response.put(address.as_bytes()),
+        );
+    }
+
+    fn srv_record(&mut self, service: &MdnsService, hostname: &str) {
+        self.record(
+            &[service.instance, service.service, LOCAL],
+            TYPE_SRV,
+            CLASS_IN | CLASS_CACHE_FLUSH,
+            HOST_TTL,
+            |response| {
+                // priority and weight
+                response.put(&[0, 0, 0, 0]);
+                response.put(&service.port.to_be_bytes());
+                response.name(&[hostname, LOCAL]);
+            },
+        );
+    }
+
+    fn txt_record(&mut self, service: &MdnsService) {
+        self.record(
+            &[service.instance, service.service, LOCAL],
+            TYPE_TXT,
+            CLASS_IN | CLASS_CACHE_FLUSH,
+            SERVICE_TTL,
+            |response| {
+                // an empty TXT record still needs one (empty) string
+                if service.txt.is_empty() {
+                    response.put(&[0]);
+                }
+                for entry in service.txt {
+                    let entry = &entry.as_bytes()[..entry.len().min(255)];
+                    response.put(&[entry.len() as u8]);
+                    response.put(entry);
+                }
+            },
+        );
+    }
+}
+
+/// Decode the (possibly compressed) name at `pos` into dotted form.
+/// Returns its length and the position after the name in the message.
+fn read_name(
+    message: &[u8],
+    mut pos: usize,
+    name: &mut [u8; NAME_MAX_LEN],
+) -> Option<(usize, usize)> {
+    let mut len = 0;
+    let mut next = None;
+    // guards against pointer loops
+    let mut jumps = 0;
+
+    loop {
+        let label_len = *message.get(pos)? as usize;
+        if label_len == 0 {
+            return Some((len, next.unwrap_or(pos + 1)));
+        }
+
+        if label_len & 0xc0 == 0xc0 {
+            let target = ((label_len & 0x3f) << 8) | *message.get(pos + 1)? as usize;
+            next.get_or_insert(pos + 2);
+            jumps += 1;
+            if jumps > 16 {
+                return None;
+            }
+            pos = target;
+            continue;
+        }
+
+        let label = message.get(pos + 1..pos + 1 + label_len)?;
+        let dot = if len > 0 { 1 } else { 0 };
+        if len + dot + label_len > NAME_MAX_LEN {
+            return None;
+        }
+        if dot == 1 {
+            name[len] = b'.';
+        }
+        name[len + dot..len + dot + label_len].copy_from_slice(label);
+        len += dot + label_len;
+        pos += 1 + label_len;
+    }
+}
+
+/// Case insensitive comparison of a dotted name with the name made of `parts`
+fn name_eq(name: &[u8], parts: &[&str]) -> bool {
+    let mut rest = name;
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            match rest.split_first() {
+                Some((b'.', tail)) => rest = tail,
+                _ => return false,
+            }
+        }
+
+        let part = part.as_bytes();
+        if rest.len() < part.len() || !rest[..part.len()].eq_ignore_ascii_case(part) {
+            return false;
+        }
+        rest = &rest[part.len()..];
+    }
+
+    rest.is_empty()
+}
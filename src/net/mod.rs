@@ -0,0 +1,10 @@
+pub mod captive_portal;
+pub mod dns;
+mod dns_wire;
+pub mod ip_watch;
+#[cfg(feature = "ipv6")]
+pub mod ipv6;
+pub mod mdns;
+pub mod poll_loop;
+pub mod sntp;
+pub mod tcp_options;
@@ -0,0 +1,70 @@
+use smoltcp::{
+    iface::EthernetInterface,
+    phy::Device,
+    socket::{Socket, SocketHandle, SocketSet},
+    time::Instant,
+};
+
+use crate::timer::get_systimer_count;
+
+/// Application code driven by `run` for one socket of the socket set.
+/// Get the socket in the callbacks via `sockets.get::<TcpSocket>(self.handle())` etc.
+pub trait SocketHandler {
+    fn handle(&self) -> SocketHandle;
+
+    /// Called on every iteration, e.g. to listen or (re)connect when the socket is closed
+    fn poll(&mut self, _sockets: &mut SocketSet, _now: Instant) {}
+
+    /// Data was received
+    fn readable(&mut self, _sockets: &mut SocketSet) {}
+
+    /// There is room in the TX buffer
+    fn writable(&mut self, _sockets: &mut SocketSet) {}
+}
+
+/// Poll the interface forever and call the handlers, for simple applications which only react
+/// to their sockets. Nothing can forget to poll the interface then.
+///
+/// TCP, UDP and raw sockets are supported.
+pub fn run<DeviceT>(
+    iface: &mut EthernetInterface<DeviceT>,
+    sockets: &mut SocketSet,
+    handlers: &mut [&mut dyn SocketHandler],
+) -> !
+where
+    DeviceT: for<'d> Device<'d>,
+{
+    loop {
+        let now = Instant::from_millis((get_systimer_count() / 16_000) as i64);
+        iface.poll(sockets, now).ok();
+
+        for handler in handlers.iter_mut() {
+            handler.poll(sockets, now);
+
+            let (readable, writable) = readiness(sockets, handler.handle());
+            if readable {
+                handler.readable(sockets);
+            }
+            if writable {
+                handler.writable(sockets);
+            }
+        }
+    }
+}
+
+fn readiness(sockets: &SocketSet, handle: SocketHandle) -> (bool, bool) {
+    for socket in sockets.iter() {
+        if socket.handle() != handle {
+            continue;
+        }
+
+        return match socket {
+            Socket::Tcp(socket) => (socket.can_recv(), socket.may_send() && socket.can_send()),
+            Socket::Udp(socket) => (socket.can_recv(), socket.is_open() && socket.can_send()),
+            Socket::Raw(socket) => (socket.can_recv(), socket.can_send()),
+            _ => (false, false),
+        };
+    }
+
+    (false, false)
+}
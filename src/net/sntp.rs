@@ -0,0 +1,186 @@
+use smoltcp::{
+    iface::EthernetInterface,
+    phy::Device,
+    socket::{SocketHandle, SocketSet, UdpSocket},
+    time::{Duration, Instant},
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+
+use crate::{debug, timer::get_systimer_count, wifi::fill_random};
+
+const NTP_PORT: u16 = 123;
+const PACKET_LEN: usize = 48;
+// seconds from 1900 (NTP era 0) to 1970
+const NTP_UNIX_OFFSET_S: u64 = 2_208_988_800;
+
+// unix time in milliseconds at systimer count 0, set once synchronized
+static mut UNIX_TIME_AT_BOOT_MS: Option<u64> = None;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SntpStatus {
+    /// No answer yet
+    Pending,
+    /// The wall clock is set, carries the unix time in milliseconds
+    Synchronized(u64),
+    /// No valid answer in time or the request couldn't be sent
+    Failed,
+}
+
+/// Current unix time in milliseconds, `None` until synchronized via `SntpClient`.
+/// TLS needs it to check the validity period of certificates.
+pub fn unix_time_ms() -> Option<u64> {
+    let at_boot = critical_section::with(|_| unsafe { UNIX_TIME_AT_BOOT_MS })?;
+    Some(at_boot + get_systimer_count() / 16_000)
+}
+
+/// Gets the time from an NTP server (e.g. `pool.ntp.org`, resolved via `net::dns`) and sets
+/// the wall clock read by `unix_time_ms`.
+///
+/// Uses a UDP socket of the application's socket set. Call `poll` after polling the interface
+/// until it returns something else than `SntpStatus::Pending` or use `synchronize` to block.
+/// The systimer drifts, synchronize again every few hours.
+pub struct SntpClient {
+    handle: SocketHandle,
+    local_port: u16,
+    timeout: Duration,
+    server: IpEndpoint,
+    // sent as our transmit timestamp, the server echoes it as the originate timestamp
+    nonce: [u8; 8],
+    started: Option<Instant>,
+    sent_at: Option<u64>,
+    status: SntpStatus,
+}
+
+impl SntpClient {
+    pub fn new(
+        server: Ipv4Address,
+        handle: SocketHandle,
+        local_port: u16,
+        timeout: Duration,
+    ) -> SntpClient {
+        let mut nonce = [0u8; 8];
+        fill_random(&mut nonce);
+
+        SntpClient {
+            handle,
+            local_port,
+            timeout,
+            server: IpEndpoint::new(IpAddress::Ipv4(server), NTP_PORT),
+            nonce,
+            started: None,
+            sent_at: None,
+            status: SntpStatus::Pending,
+        }
+    }
+
+    pub fn poll(&mut self, sockets: &mut SocketSet, now: Instant) -> SntpStatus {
+        if self.status != SntpStatus::Pending {
+            return self.status;
+        }
+
+        let mut socket = sockets.get::<UdpSocket>(self.handle);
+
+        let started = *self.started.get_or_insert(now);
+        if now - started > self.timeout {
+            debug!("SNTP request timed out");
+            socket.close();
+            return self.finish(SntpStatus::Failed);
+        }
+
+        if !socket.is_open() && socket.bind(self.local_port).is_err() {
+            return self.finish(SntpStatus::Failed);
+        }
+
+        let sent_at = match self.sent_at {
+            Some(sent_at) => sent_at,
+            None => {
+                if socket.can_send() {
+                    let mut request = [0u8; PACKET_LEN];
+                    // no leap second warning, version 4, client mode
+                    request[0] = 0x23;
+                    request[40..48].copy_from_slice(&self.nonce);
+
+                    if socket.send_slice(&request, self.server).is_err() {
+                        socket.close();
+                        return self.finish(SntpStatus::Failed);
+                    }
+                    self.sent_at = Some(get_systimer_count());
+                }
+                return SntpStatus::Pending;
+            }
+        };
+
+        while socket.can_recv() {
+            let server_time_ms = match socket.recv() {
+                Ok((response, endpoint)) if endpoint == self.server => {
+                    parse_response(response, &self.nonce)
+                }
+                Ok(_) => continue,
+                Err(_) => break,
+            };
+
+            if let Some(server_time_ms) = server_time_ms {
+                socket.close();
+
+                // the answer took half of the round trip to get here
+                let received_at = get_systimer_count();
+                let unix_time_ms = server_time_ms + (received_at - sent_at) / 16_000 / 2;
+                critical_section::with(|_| unsafe {
+                    UNIX_TIME_AT_BOOT_MS = Some(unix_time_ms - received_at / 16_000);
+                });
+
+                return self.finish(SntpStatus::Synchronized(unix_time_ms));
+            }
+        }
+
+        SntpStatus::Pending
+    }
+
+    fn finish(&mut self, status: SntpStatus) -> SntpStatus {
+        debug!("SNTP result {:?}", status);
+        self.status = status;
+        status
+    }
+}
+
+/// Block until `client` got the time or timed out, polling the interface meanwhile
+pub fn synchronize<DeviceT>(
+    iface: &mut EthernetInterface<DeviceT>,
+    sockets: &mut SocketSet,
+    client: &mut SntpClient,
+) -> SntpStatus
+where
+    DeviceT: for<'d> Device<'d>,
+{
+    loop {
+        let now = Instant::from_millis((get_systimer_count() / 16_000) as i64);
+        iface.poll(sockets, now).ok();
+
+        let status = client.poll(sockets, now);
+        if status != SntpStatus::Pending {
+            return status;
+        }
+    }
+}
+
+/// The server's transmit time as unix time in milliseconds, `None` if the response isn't a
+/// valid answer to our request
+fn parse_response(response: &[u8], nonce: &[u8; 8]) -> Option<u64> {
+    if response.len() < PACKET_LEN {
+        return None;
+    }
+
+    let mode = response[0] & 0x07;
+    let leap_indicator = response[0] >> 6;
+    let stratum = response[1];
+    // stratum 0 is a "kiss-o'-death", leap indicator 3 means the server isn't synchronized
+    if mode != 4 || stratum == 0 || leap_indicator == 3 || &response[24..32] != nonce {
+        return None;
+    }
+
+    let seconds = u32::from_be_bytes([response[40], response[41], response[42], response[43]]);
+    let fraction = u32::from_be_bytes([response[44], response[45], response[46], response[47]]);
+
+    let unix_seconds = (seconds as u64).checked_sub(NTP_UNIX_OFFSET_S)?;
+    Some(unix_seconds * 1000 + ((fraction as u64 * 1000) >> 32))
+}
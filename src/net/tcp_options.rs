@@ -0,0 +1,34 @@
+use smoltcp::{socket::TcpSocket, time::Duration};
+
+/// Options of a TCP socket, `None` keeps smoltcp's default.
+///
+/// smoltcp 0.7 doesn't implement Nagle's algorithm, segments are always sent right away.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TcpOptions {
+    /// Send a keep-alive segment after this much idle time
+    pub keep_alive: Option<Duration>,
+    /// Abort the connection if the peer didn't answer for this long
+    pub timeout: Option<Duration>,
+    /// TTL of the sent IP packets
+    pub hop_limit: Option<u8>,
+}
+
+impl TcpOptions {
+    /// For connections idling for a long time (e.g. MQTT): keep-alives every 60 s keep NAT
+    /// mappings open (they typically expire after a few minutes) and a dead peer is noticed
+    /// after 3 minutes without the application sending pings.
+    pub fn long_lived() -> TcpOptions {
+        TcpOptions {
+            keep_alive: Some(Duration::from_secs(60)),
+            timeout: Some(Duration::from_secs(180)),
+            hop_limit: None,
+        }
+    }
+
+    /// Set the options on `socket`, can be done before connecting or listening
+    pub fn apply(&self, socket: &mut TcpSocket) {
+        socket.set_keep_alive(self.keep_alive);
+        socket.set_timeout(self.timeout);
+        socket.set_hop_limit(self.hop_limit);
+    }
+}
@@ -8,7 +8,7 @@ pub struct Context {
 }
 
 const STACK_SIZE: usize = 8192 * 2; // TODO how much is enough? would be better to have this per task
-const MAX_TASK: usize = 3;
+const MAX_TASK: usize = 4;
 
 static mut TASK_STACK: [u8; STACK_SIZE * MAX_TASK] = [0u8; STACK_SIZE * MAX_TASK];
 
@@ -18,6 +18,9 @@ static mut TASK_TOP: usize = 0;
 
 static mut CTX_NOW: usize = 0;
 
+// the task switched to out of order while its function returns true
+static mut PREEMPTING_TASK: Option<(usize, fn() -> bool)> = None;
+
 static mut CTX_TASKS: [Context; MAX_TASK] = [Context {
     trap_frame: TrapFrame {
         ra: 0,
@@ -71,6 +74,16 @@ pub fn task_create(task: extern "C" fn()) -> usize {
     }
 }
 
+/// Create a task which is switched to right away (at the next tick) instead of waiting for its
+/// turn while `ready` returns true. `ready` is called from the scheduler interrupt.
+pub fn task_create_preempting(task: extern "C" fn(), ready: fn() -> bool) -> usize {
+    let i = task_create(task);
+    unsafe {
+        PREEMPTING_TASK = Some((i, ready));
+    }
+    i
+}
+
 fn task_create_from_mepc(mepc: usize) -> usize {
     unsafe {
         let i = TASK_TOP;
@@ -175,7 +188,10 @@ pub fn task_switch(trap_frame: &mut TrapFrame) {
 
         trap_frame_to_task(CTX_NOW, old_mepc, trap_frame);
 
-        next_task();
+        match PREEMPTING_TASK {
+            Some((task, ready)) if task != CTX_NOW && ready() => CTX_NOW = task,
+            _ => next_task(),
+        }
 
         let new_pc = task_to_trap_frame(CTX_NOW, trap_frame);
 
@@ -7,13 +7,93 @@ use crate::{
         queue::SimpleQueue,
         timer_compat::{Timer, TIMERS},
     },
-    preempt::{task_create, task_switch},
+    preempt::{task_create, task_create_preempting, task_switch},
     trace,
-    wifi::send_data_if_needed,
+    wifi::{
+        check_tsf_alarm, radio::check_radio_request, reconnect::check_reconnect,
+        send_data_if_needed,
+    },
     Uart,
 };
 use core::fmt::Write;
 
+/// Time slice of the scheduler in microseconds, tasks are switched on every systimer interrupt.
+/// Set the env variable `ESP_WIFI_TIMESLICE_US` when building to change it (100 to 100000,
+/// 1250 by default).
+///
+/// A shorter slice lets the driver's tasks react faster, a longer one interrupts the
+/// application less often, e.g. for latency sensitive work or to save power.
+pub const TIMESLICE_US: u32 = parse_timeslice(option_env!("ESP_WIFI_TIMESLICE_US"));
+
+const DEFAULT_TIMESLICE_US: u32 = 1250;
+
+const fn parse_timeslice(value: Option<&str>) -> u32 {
+    let value = match value {
+        Some(value) => value.as_bytes(),
+        None => return DEFAULT_TIMESLICE_US,
+    };
+
+    let mut us = 0;
+    let mut i = 0;
+    while i < value.len() {
+        assert!(
+            value[i].is_ascii_digit(),
+            "ESP_WIFI_TIMESLICE_US must be a number"
+        );
+        us = us * 10 + (value[i] - b'0') as u32;
+        i += 1;
+    }
+
+    assert!(
+        us >= 100 && us <= 100_000,
+        "ESP_WIFI_TIMESLICE_US must be between 100 and 100000"
+    );
+    us
+}
+
+/// Priorities (1 to 15) of the CPU interrupts used by the driver and the scheduler, e.g. to
+/// let an application interrupt win over them. Interrupts don't nest, the priority decides
+/// which pending interrupt is handled first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InterruptPriorities {
+    /// The WiFi interrupt (CPU interrupt 1), `None` uses the priority the driver asks for
+    pub radio: Option<u8>,
+    /// The systimer interrupt switching tasks (CPU interrupt 10), 1 by default
+    pub scheduler: u8,
+}
+
+impl Default for InterruptPriorities {
+    fn default() -> InterruptPriorities {
+        InterruptPriorities {
+            radio: None,
+            scheduler: 1,
+        }
+    }
+}
+
+static mut INTERRUPT_PRIORITIES: InterruptPriorities = InterruptPriorities {
+    radio: None,
+    scheduler: 1,
+};
+
+/// Needs to be called before `setup_timer_isr` and `wifi_init`. Returns false if a priority is
+/// out of range.
+pub fn set_interrupt_priorities(priorities: InterruptPriorities) -> bool {
+    let valid = |priority: u8| (1..=15).contains(&priority);
+    if !valid(priorities.scheduler) || !priorities.radio.map_or(true, valid) {
+        return false;
+    }
+
+    critical_section::with(|_| unsafe {
+        INTERRUPT_PRIORITIES = priorities;
+    });
+    true
+}
+
+pub(crate) fn interrupt_priorities() -> InterruptPriorities {
+    critical_section::with(|_| unsafe { INTERRUPT_PRIORITIES })
+}
+
 pub fn init_intr11(peripherals: &Peripherals) {
     // esp32c3_wl_init sets an interrupt handler - not clear who generates the interrupt etc.
     // seems unused - check and remove
@@ -52,7 +132,7 @@ pub fn setup_timer_isr(peripherals: &Peripherals) {
     peripherals
         .SYSTIMER
         .target0_conf
-        .write(|w| unsafe { w.bits((1 << 30) | 20_000) });
+        .write(|w| unsafe { w.bits((1 << 30) | (TIMESLICE_US * 16)) });
     // LOAD CONF VALUE
     peripherals
         .SYSTIMER
@@ -82,7 +162,7 @@ pub fn setup_timer_isr(peripherals: &Peripherals) {
     peripherals
         .INTERRUPT_CORE0
         .cpu_int_pri_10
-        .write(|w| unsafe { w.bits(1) }); // PRIO = 1
+        .write(|w| unsafe { w.bits(interrupt_priorities().scheduler as u32) });
     peripherals
         .INTERRUPT_CORE0
         .cpu_int_enable
@@ -92,6 +172,10 @@ pub fn setup_timer_isr(peripherals: &Peripherals) {
 pub fn init_tasks() {
     task_create(worker_task1);
     task_create(worker_task2);
+    task_create_preempting(
+        preempting_worker_task,
+        compat::work_queue::preempting_work_pending,
+    );
 }
 
 pub extern "C" fn worker_task1() {
@@ -100,6 +184,12 @@ pub extern "C" fn worker_task1() {
     }
 }
 
+pub extern "C" fn preempting_worker_task() {
+    loop {
+        compat::work_queue::do_preempting_work();
+    }
+}
+
 pub extern "C" fn worker_task2() {
     loop {
         let mut to_run: SimpleQueue<
@@ -151,6 +241,12 @@ pub extern "C" fn worker_task2() {
             trace!("timer callback called");
         }
 
+        check_tsf_alarm();
+
+        check_reconnect();
+
+        check_radio_request();
+
         send_data_if_needed();
     }
 }
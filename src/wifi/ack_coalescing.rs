@@ -0,0 +1,130 @@
+use smoltcp::wire::{EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Packet, TcpPacket};
+
+use crate::{
+    debug,
+    timer::get_systimer_count,
+    wifi::{backend::with_backend, WifiInterface},
+};
+
+// large enough for a pure ACK including TCP options
+const HELD_ACK_MAX_LEN: usize = 128;
+
+struct HeldAck {
+    data: [u8; HELD_ACK_MAX_LEN],
+    len: usize,
+    interface: WifiInterface,
+    flow: Flow,
+    deadline: u64,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+struct Flow {
+    src_addr: [u8; 4],
+    dst_addr: [u8; 4],
+    src_port: u16,
+    dst_port: u16,
+}
+
+// systimer ticks a pure ACK may be held back, 0 = disabled
+static mut WINDOW: u64 = 0;
+static mut HELD_ACK: Option<HeldAck> = None;
+
+pub(crate) fn set_window_ms(window_ms: u32) {
+    critical_section::with(|_| unsafe {
+        WINDOW = window_ms as u64 * 16_000;
+    });
+}
+
+/// Decide what to do with a frame about to be queued for sending.
+/// Returns true if the frame was taken (it's a pure ACK which is held back for now).
+/// A held ACK superseded by a frame of the same TCP connection is dropped.
+pub(crate) fn filter_tx_frame(frame: &[u8], interface: WifiInterface) -> bool {
+    let window = critical_section::with(|_| unsafe { WINDOW });
+    if window == 0 {
+        return false;
+    }
+
+    let (flow, pure_ack) = match parse_tcp(frame) {
+        Some(parsed) => parsed,
+        None => return false,
+    };
+
+    critical_section::with(|_| unsafe {
+        let same_flow = match &HELD_ACK {
+            Some(held) => held.interface == interface && held.flow == flow,
+            None => false,
+        };
+
+        if !pure_ack || frame.len() > HELD_ACK_MAX_LEN {
+            // every segment carries the latest ACK number
+            if same_flow {
+                HELD_ACK = None;
+            }
+            return false;
+        }
+
+        let deadline = match &HELD_ACK {
+            // keep the deadline so a stream of ACKs doesn't get delayed forever
+            Some(held) if same_flow => held.deadline,
+            // only one ACK is held, send others right away
+            Some(_) => return false,
+            None => get_systimer_count() + window,
+        };
+
+        let mut data = [0u8; HELD_ACK_MAX_LEN];
+        data[..frame.len()].copy_from_slice(frame);
+        HELD_ACK = Some(HeldAck {
+            data,
+            len: frame.len(),
+            interface,
+            flow,
+            deadline,
+        });
+        true
+    })
+}
+
+/// Send the held ACK once its window is over
+pub(crate) fn send_held_ack_if_due() {
+    let due = critical_section::with(|_| unsafe {
+        match &HELD_ACK {
+            Some(held) if get_systimer_count() >= held.deadline => HELD_ACK.take(),
+            _ => None,
+        }
+    });
+
+    if let Some(held) = due {
+        let _res = with_backend(|backend| backend.transmit(held.interface, &held.data[..held.len]));
+        debug!("transmit (held ACK) {}", _res);
+    }
+}
+
+pub(crate) fn discard_held_ack() {
+    critical_section::with(|_| unsafe {
+        HELD_ACK = None;
+    });
+}
+
+/// Returns the TCP connection of the frame and whether it's a pure ACK
+fn parse_tcp(frame: &[u8]) -> Option<(Flow, bool)> {
+    let eth = EthernetFrame::new_checked(frame).ok()?;
+    if eth.ethertype() != EthernetProtocol::Ipv4 {
+        return None;
+    }
+
+    let ip = Ipv4Packet::new_checked(eth.payload()).ok()?;
+    if ip.protocol() != IpProtocol::Tcp {
+        return None;
+    }
+
+    let tcp = TcpPacket::new_checked(ip.payload()).ok()?;
+    let flow = Flow {
+        src_addr: ip.src_addr().0,
+        dst_addr: ip.dst_addr().0,
+        src_port: tcp.src_port(),
+        dst_port: tcp.dst_port(),
+    };
+    let pure_ack = tcp.ack() && !tcp.syn() && !tcp.fin() && !tcp.rst() && tcp.payload().is_empty();
+
+    Some((flow, pure_ack))
+}
@@ -0,0 +1,70 @@
+use alloc::{boxed::Box, vec, vec::Vec};
+
+use crate::{
+    binary::include::{esp_wifi_scan_get_ap_num, esp_wifi_scan_get_ap_records, wifi_ap_record_t},
+    wifi::{wifi_start_scan_with_config, ApInfo, ScanConfig, WifiEvent},
+};
+
+type EventHandler = Box<dyn FnMut(&WifiEvent) + Send>;
+
+static mut EVENT_HANDLER: Option<EventHandler> = None;
+// bumped whenever the handler is replaced
+static mut EVENT_HANDLER_GENERATION: u32 = 0;
+
+/// Scan and return all APs found. Unlike `wifi_scan_each` nothing is dropped since the
+/// results are fetched into a heap allocated buffer.
+pub fn wifi_scan_vec(config: &ScanConfig) -> Result<Vec<ApInfo>, i32> {
+    wifi_start_scan_with_config(config).map_err(|err| err.code())?;
+
+    let mut count = 0u16;
+    let res = unsafe { esp_wifi_scan_get_ap_num(&mut count) };
+    if res != 0 {
+        return Err(res);
+    }
+
+    let mut records: Vec<wifi_ap_record_t> = vec![unsafe { core::mem::zeroed() }; count as usize];
+    let res = unsafe { esp_wifi_scan_get_ap_records(&mut count, records.as_mut_ptr()) };
+    if res != 0 {
+        return Err(res);
+    }
+
+    Ok(records[..count as usize]
+        .iter()
+        .map(ApInfo::from_record)
+        .collect())
+}
+
+/// Call `handler` for every event received from the driver, in addition to queueing it.
+/// Pass `None` to remove the handler again.
+///
+/// The handler runs on the task sending the event (usually the driver's task). It must return
+/// quickly and must not call into the driver.
+pub fn wifi_set_event_handler(handler: Option<EventHandler>) {
+    let previous = critical_section::with(|_| unsafe {
+        EVENT_HANDLER_GENERATION = EVENT_HANDLER_GENERATION.wrapping_add(1);
+        core::mem::replace(&mut EVENT_HANDLER, handler)
+    });
+    // dropped outside the critical section
+    drop(previous);
+}
+
+pub(crate) fn call_event_handler(event: &WifiEvent) {
+    // taken out while it runs so it isn't called concurrently from another task
+    let (handler, generation) =
+        critical_section::with(|_| unsafe { (EVENT_HANDLER.take(), EVENT_HANDLER_GENERATION) });
+
+    if let Some(mut handler) = handler {
+        handler(event);
+
+        // don't bring it back if it was replaced or removed in the meantime
+        let replaced = critical_section::with(|_| unsafe {
+            if EVENT_HANDLER_GENERATION == generation {
+                EVENT_HANDLER = Some(handler);
+                None
+            } else {
+                Some(handler)
+            }
+        });
+        drop(replaced);
+    }
+}
@@ -0,0 +1,106 @@
+use crate::{
+    debug,
+    wifi::{get_ap_mac, WifiInterface},
+};
+
+const ALLOW_LIST_LEN: usize = 8;
+
+static mut ENABLED: bool = false;
+static mut AP_MAC: [u8; 6] = [0u8; 6];
+// clients which may still talk to other clients
+static mut ALLOW_LIST: [Option<[u8; 6]>; ALLOW_LIST_LEN] = [None; ALLOW_LIST_LEN];
+
+/// Drop frames from one client of the access point to another (AP isolation), clients can only
+/// talk to the device itself. Disabled by default.
+///
+/// Frames received from a client for another client's MAC address are dropped and so are frames
+/// sent on the AP interface on behalf of a client (e.g. by a bridge relaying them).
+/// Clients added via `ap_isolation_allow` are exempt, e.g. a printer every client needs to reach.
+pub fn ap_isolation_set_enabled(enabled: bool) {
+    let mut mac = [0u8; 6];
+    get_ap_mac(&mut mac);
+
+    critical_section::with(|_| unsafe {
+        AP_MAC = mac;
+        ENABLED = enabled;
+    });
+}
+
+/// Exempt the client with the MAC address `mac` from the isolation.
+/// Returns false if the allow-list (8 entries) is full.
+pub fn ap_isolation_allow(mac: [u8; 6]) -> bool {
+    critical_section::with(|_| unsafe {
+        if ALLOW_LIST.contains(&Some(mac)) {
+            return true;
+        }
+
+        match ALLOW_LIST.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => {
+                *entry = Some(mac);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Remove the client with the MAC address `mac` from the allow-list
+pub fn ap_isolation_disallow(mac: [u8; 6]) {
+    critical_section::with(|_| unsafe {
+        for entry in ALLOW_LIST.iter_mut() {
+            if *entry == Some(mac) {
+                *entry = None;
+            }
+        }
+    });
+}
+
+/// True if the frame received on the AP interface is addressed to another client and needs to
+/// be dropped
+pub(crate) fn filter_rx_frame(frame: &[u8]) -> bool {
+    if frame.len() < 14 {
+        return false;
+    }
+
+    let (dst, src) = (&frame[0..6], &frame[6..12]);
+    let drop = critical_section::with(|_| unsafe {
+        ENABLED && !is_group(dst) && dst != AP_MAC && !allowed(src) && !allowed(dst)
+    });
+
+    if drop {
+        debug!("AP isolation: dropping frame from {:x?} to {:x?}", src, dst);
+    }
+    drop
+}
+
+/// True if the frame to be sent on `interface` is relayed on behalf of a client and needs to be
+/// dropped
+pub(crate) fn filter_tx_frame(frame: &[u8], interface: WifiInterface) -> bool {
+    if interface != WifiInterface::Ap || frame.len() < 14 {
+        return false;
+    }
+
+    let (dst, src) = (&frame[0..6], &frame[6..12]);
+    let drop = critical_section::with(|_| unsafe {
+        ENABLED && src != AP_MAC && !allowed(src) && (is_group(dst) || !allowed(dst))
+    });
+
+    if drop {
+        debug!(
+            "AP isolation: not relaying frame from {:x?} to {:x?}",
+            src, dst
+        );
+    }
+    drop
+}
+
+fn is_group(mac: &[u8]) -> bool {
+    mac[0] & 0x01 != 0
+}
+
+/// Needs to be called in a critical section
+unsafe fn allowed(mac: &[u8]) -> bool {
+    ALLOW_LIST
+        .iter()
+        .any(|entry| matches!(entry, Some(entry) if entry == mac))
+}
@@ -0,0 +1,155 @@
+use crate::{
+    binary::include::{
+        esp_wifi_internal_tx, esp_wifi_internal_tx_by_ref, esp_wifi_start, esp_wifi_stop, size_t,
+    },
+    wifi::{
+        fill_rx_buffer, reserve_rx_buffer, tx_credits, WifiInterface, MAX_FRAME_LEN, RX_DROPPED,
+        WIFI_STARTED,
+    },
+};
+
+/// Hands a received frame (including the ethernet header) to `WifiDevice`, returns false if it
+/// was dropped
+pub type RxSink = fn(WifiInterface, &[u8]) -> bool;
+
+/// The radio below the data path (TX buffers, RX queues, `WifiDevice`, EAPOL frames), by default
+/// the WiFi driver. Another backend can be plugged in via `backend_set`, e.g. a simulated radio.
+pub trait RadioBackend {
+    /// Called by `backend_set` with the sink to pass received frames to
+    fn set_rx_sink(&mut self, sink: RxSink);
+
+    /// Send `frame` (including the ethernet header) on `interface`. The frame buffer can be
+    /// reused once this returns. Returns 0 or an `esp_err_t` code, `ESP_ERR_NO_MEM` if the radio
+    /// is out of buffers.
+    fn transmit(&mut self, interface: WifiInterface, frame: &[u8]) -> i32;
+
+    /// Send a frame of the by-reference pool (`wifi_set_tx_by_ref`). The driver holds on to the
+    /// buffer via `netstack_buf` until it's sent, other radios copy the frame via `transmit` by
+    /// default.
+    fn transmit_by_ref(
+        &mut self,
+        interface: WifiInterface,
+        frame: &mut [u8],
+        _netstack_buf: *mut crate::binary::c_types::c_void,
+    ) -> i32 {
+        self.transmit(interface, frame)
+    }
+
+    /// Called by `wifi_start`, returns 0 or an `esp_err_t` code
+    fn start(&mut self) -> i32;
+
+    /// Called by `wifi_stop`, returns 0 or an `esp_err_t` code
+    fn stop(&mut self) -> i32;
+}
+
+/// The WiFi driver
+pub struct DriverBackend;
+
+impl RadioBackend for DriverBackend {
+    fn set_rx_sink(&mut self, _sink: RxSink) {
+        // the driver's RX callbacks are registered by `wifi_init`
+    }
+
+    fn transmit(&mut self, interface: WifiInterface, frame: &[u8]) -> i32 {
+        // the driver copies the frame
+        let res = unsafe {
+            esp_wifi_internal_tx(
+                interface.to_raw(),
+                frame.as_ptr() as *mut crate::binary::c_types::c_void,
+                frame.len() as u16,
+            )
+        };
+
+        if res == 0 {
+            tx_credits::take();
+        }
+        res
+    }
+
+    fn transmit_by_ref(
+        &mut self,
+        interface: WifiInterface,
+        frame: &mut [u8],
+        netstack_buf: *mut crate::binary::c_types::c_void,
+    ) -> i32 {
+        let res = unsafe {
+            esp_wifi_internal_tx_by_ref(
+                interface.to_raw(),
+                frame.as_mut_ptr() as *mut crate::binary::c_types::c_void,
+                frame.len() as size_t,
+                netstack_buf,
+            )
+        };
+
+        if res == 0 {
+            tx_credits::take();
+        }
+        res
+    }
+
+    fn start(&mut self) -> i32 {
+        unsafe { esp_wifi_start() }
+    }
+
+    fn stop(&mut self) -> i32 {
+        unsafe { esp_wifi_stop() }
+    }
+}
+
+static mut BACKEND: Option<&'static mut dyn RadioBackend> = None;
+
+/// Use `backend` instead of the WiFi driver. It gets frames to send - including EAPOL frames and
+/// those sent by reference - and passes received ones to the sink set via `set_rx_sink`.
+///
+/// The backend is called inside a critical section so it must not block. Needs to be called
+/// before `wifi_start`. The driver still needs to be initialized via `wifi_init`.
+pub fn backend_set(backend: &'static mut dyn RadioBackend) {
+    critical_section::with(|_| unsafe {
+        backend.set_rx_sink(backend_deliver_rx);
+        BACKEND = Some(backend);
+    });
+}
+
+// Queue a frame received by the backend (including the ethernet header) for `WifiDevice`.
+// Returns false if it was dropped since the RX queue is full or the frame is too large.
+fn backend_deliver_rx(interface: WifiInterface, frame: &[u8]) -> bool {
+    let slot = critical_section::with(|_| unsafe {
+        if !WIFI_STARTED {
+            return None;
+        }
+
+        let slot = if frame.len() <= MAX_FRAME_LEN {
+            reserve_rx_buffer(interface)
+        } else {
+            None
+        };
+        if slot.is_none() {
+            RX_DROPPED += 1;
+        }
+        slot
+    });
+
+    match slot {
+        Some((index, generation)) => {
+            unsafe { fill_rx_buffer(interface, index, generation, frame) };
+            true
+        }
+        None => false,
+    }
+}
+
+/// Run `f` with the backend in use. The driver can block (e.g. when starting) so it's called
+/// outside of a critical section, it has no state of its own.
+pub(crate) fn with_backend<R>(f: impl FnOnce(&mut dyn RadioBackend) -> R) -> R {
+    let f = match critical_section::with(|_| unsafe {
+        match BACKEND {
+            Some(ref mut backend) => Ok(f(&mut **backend)),
+            None => Err(f),
+        }
+    }) {
+        Ok(res) => return res,
+        Err(f) => f,
+    };
+
+    f(&mut DriverBackend)
+}
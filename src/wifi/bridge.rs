@@ -0,0 +1,126 @@
+use smoltcp::{
+    phy::{Device, RxToken, TxToken},
+    time::Instant,
+    wire::{ArpPacket, EthernetAddress, EthernetFrame, EthernetProtocol},
+};
+
+/// Forwards frames between the WiFi station interface and another smoltcp `Device`
+/// (e.g. an SPI Ethernet MAC).
+///
+/// A station can only send frames using its own MAC address, so frames coming from the
+/// wired side get their source address (and ARP sender address) replaced by the STA MAC.
+/// Frames addressed to the STA MAC are handed back to the last seen wired host.
+/// This means only a single wired host is supported behind the bridge.
+pub struct WifiBridge {
+    sta_mac: EthernetAddress,
+    wired_host: Option<EthernetAddress>,
+}
+
+impl WifiBridge {
+    pub fn new(sta_mac: [u8; 6]) -> WifiBridge {
+        WifiBridge {
+            sta_mac: EthernetAddress::from_bytes(&sta_mac),
+            wired_host: None,
+        }
+    }
+
+    /// Forward at most one frame in each direction.
+    /// Returns true if any frame was forwarded.
+    pub fn poll<W, E>(&mut self, wifi: &mut W, wired: &mut E, timestamp: Instant) -> bool
+    where
+        W: for<'a> Device<'a>,
+        E: for<'a> Device<'a>,
+    {
+        let to_wired = self.forward_to_wired(wifi, wired, timestamp);
+        let to_wifi = self.forward_to_wifi(wired, wifi, timestamp);
+
+        to_wired || to_wifi
+    }
+
+    fn forward_to_wifi<E, W>(&mut self, wired: &mut E, wifi: &mut W, timestamp: Instant) -> bool
+    where
+        E: for<'a> Device<'a>,
+        W: for<'a> Device<'a>,
+    {
+        let mtu = wifi.capabilities().max_transmission_unit;
+        let tx = match wifi.transmit() {
+            Some(tx) => tx,
+            None => return false,
+        };
+        let (rx, _) = match wired.receive() {
+            Some(tokens) => tokens,
+            None => return false,
+        };
+
+        let sta_mac = self.sta_mac;
+        let mut wired_host = self.wired_host;
+        let res = rx.consume(timestamp, |frame| {
+            if frame.len() > mtu {
+                return Err(smoltcp::Error::Truncated);
+            }
+
+            tx.consume(timestamp, frame.len(), |buffer| {
+                buffer.copy_from_slice(frame);
+
+                let mut eth = EthernetFrame::new_checked(buffer)?;
+                wired_host = Some(eth.src_addr());
+                eth.set_src_addr(sta_mac);
+                if eth.ethertype() == EthernetProtocol::Arp {
+                    let mut arp = ArpPacket::new_checked(eth.payload_mut())?;
+                    arp.set_source_hardware_addr(sta_mac.as_bytes());
+                }
+                Ok(())
+            })
+        });
+        self.wired_host = wired_host;
+
+        res.is_ok()
+    }
+
+    fn forward_to_wired<W, E>(&mut self, wifi: &mut W, wired: &mut E, timestamp: Instant) -> bool
+    where
+        W: for<'a> Device<'a>,
+        E: for<'a> Device<'a>,
+    {
+        let mtu = wired.capabilities().max_transmission_unit;
+        let tx = match wired.transmit() {
+            Some(tx) => tx,
+            None => return false,
+        };
+        let (rx, _) = match wifi.receive() {
+            Some(tokens) => tokens,
+            None => return false,
+        };
+
+        let sta_mac = self.sta_mac;
+        let wired_host = self.wired_host;
+        let res = rx.consume(timestamp, |frame| {
+            if frame.len() > mtu {
+                return Err(smoltcp::Error::Truncated);
+            }
+
+            tx.consume(timestamp, frame.len(), |buffer| {
+                buffer.copy_from_slice(frame);
+
+                let host = match wired_host {
+                    Some(host) => host,
+                    None => return Ok(()),
+                };
+
+                let mut eth = EthernetFrame::new_checked(buffer)?;
+                if eth.dst_addr() == sta_mac {
+                    eth.set_dst_addr(host);
+                }
+                if eth.ethertype() == EthernetProtocol::Arp {
+                    let mut arp = ArpPacket::new_checked(eth.payload_mut())?;
+                    if arp.target_hardware_addr() == sta_mac.as_bytes() {
+                        arp.set_target_hardware_addr(host.as_bytes());
+                    }
+                }
+                Ok(())
+            })
+        });
+
+        res.is_ok()
+    }
+}
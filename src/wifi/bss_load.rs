@@ -0,0 +1,129 @@
+use crate::wifi::sniffer::{
+    sniffer_active, sniffer_start, sniffer_stop, FrameType, PromiscuousFilter, RxControlInfo,
+};
+
+const BSS_LOAD_ELEMENT_ID: u8 = 11;
+const BSS_LOAD_TABLE_SIZE: usize = 16;
+
+// management header + timestamp, beacon interval and capabilities
+const IES_OFFSET: usize = 24 + 12;
+const FCS_LEN: usize = 4;
+
+/// Load the AP advertises in the BSS Load element of its beacons (802.11e QBSS load)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BssLoad {
+    /// Number of associated stations
+    pub station_count: u16,
+    /// Share of the time the AP sensed the medium busy, 255 is 100%
+    pub channel_utilization: u8,
+    /// Remaining medium time for admission control in units of 32 microseconds per second
+    pub available_admission_capacity: u16,
+}
+
+static mut ENABLED: bool = false;
+static mut BSS_LOADS: [Option<([u8; 6], BssLoad)>; BSS_LOAD_TABLE_SIZE] =
+    [None; BSS_LOAD_TABLE_SIZE];
+// next entry to overwrite if the table is full
+static mut NEXT_SLOT: usize = 0;
+
+/// Collect the BSS load of the APs found while scanning, reported in `ApInfo::bss_load`.
+/// Disabled by default.
+///
+/// The beacons are captured in promiscuous mode during the scan, so nothing is collected
+/// while the sniffer is in use. Only APs advertising the element (most enterprise APs, few
+/// consumer ones) report a load. The load of the last 16 APs seen is remembered.
+pub fn bss_load_set_enabled(enabled: bool) {
+    critical_section::with(|_| unsafe {
+        ENABLED = enabled;
+        if !enabled {
+            BSS_LOADS = [None; BSS_LOAD_TABLE_SIZE];
+        }
+    });
+}
+
+/// The load last seen for the AP with the given BSSID
+pub fn bss_load_lookup(bssid: &[u8; 6]) -> Option<BssLoad> {
+    critical_section::with(|_| unsafe {
+        BSS_LOADS
+            .iter()
+            .flatten()
+            .find(|(entry, _)| entry == bssid)
+            .map(|(_, load)| *load)
+    })
+}
+
+/// Start capturing beacons for a scan, returns true if it has to be stopped afterwards
+pub(crate) fn collect_start() -> bool {
+    if !critical_section::with(|_| unsafe { ENABLED }) || sniffer_active() {
+        return false;
+    }
+
+    let filter = PromiscuousFilter {
+        management: true,
+        control: false,
+        data: false,
+        misc: false,
+    };
+    if sniffer_start(filter, None, collect_frame) != 0 {
+        sniffer_stop();
+        return false;
+    }
+
+    true
+}
+
+pub(crate) fn collect_stop() {
+    sniffer_stop();
+}
+
+fn collect_frame(frame_type: FrameType, _info: &RxControlInfo, frame: &[u8]) {
+    if frame_type != FrameType::Management || frame.len() < IES_OFFSET + FCS_LEN {
+        return;
+    }
+
+    // beacons and probe responses
+    if frame[0] != 0x80 && frame[0] != 0x50 {
+        return;
+    }
+
+    let mut bssid = [0u8; 6];
+    bssid.copy_from_slice(&frame[16..22]);
+
+    let mut ies = &frame[IES_OFFSET..frame.len() - FCS_LEN];
+    while ies.len() >= 2 {
+        let (id, len) = (ies[0], ies[1] as usize);
+        if ies.len() < 2 + len {
+            return;
+        }
+
+        if id == BSS_LOAD_ELEMENT_ID && len == 5 {
+            let data = &ies[2..7];
+            store(
+                bssid,
+                BssLoad {
+                    station_count: u16::from_le_bytes([data[0], data[1]]),
+                    channel_utilization: data[2],
+                    available_admission_capacity: u16::from_le_bytes([data[3], data[4]]),
+                },
+            );
+            return;
+        }
+
+        ies = &ies[2 + len..];
+    }
+}
+
+fn store(bssid: [u8; 6], load: BssLoad) {
+    critical_section::with(|_| unsafe {
+        let slot = BSS_LOADS
+            .iter()
+            .position(|entry| matches!(entry, Some((entry, _)) if *entry == bssid))
+            .or_else(|| BSS_LOADS.iter().position(|entry| entry.is_none()))
+            .unwrap_or_else(|| {
+                let slot = NEXT_SLOT;
+                NEXT_SLOT = (NEXT_SLOT + 1) % BSS_LOAD_TABLE_SIZE;
+                slot
+            });
+        BSS_LOADS[slot] = Some((bssid, load));
+    });
+}
@@ -0,0 +1,143 @@
+use embedded_hal::blocking::serial::Write;
+
+use crate::{print, println, timer::get_systimer_count, wifi::WifiInterface};
+
+// pcap link type of Ethernet frames
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Capture sinks get every frame, incl. its direction, the interface and the time in
+/// microseconds since boot
+pub type CaptureSink = fn(WifiInterface, CaptureDirection, u64, &[u8]);
+
+static mut SINK: Option<CaptureSink> = None;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaptureDirection {
+    /// Received, passed to smoltcp
+    Rx,
+    /// Handed to the driver to be sent
+    Tx,
+}
+
+/// Pass every Ethernet frame (header included) the devices receive and send to `sink`, `None`
+/// disables the capture. Use `capture_print` to print a summary of each frame or write them
+/// as pcap records via `pcap_write_record` to open them in Wireshark.
+///
+/// The sink is called from the worker task for sent frames. It must not block for long or
+/// frames pile up in the TX buffers.
+pub fn capture_set_sink(sink: Option<CaptureSink>) {
+    critical_section::with(|_| unsafe {
+        SINK = sink;
+    });
+}
+
+pub(crate) fn capture_frame(interface: WifiInterface, direction: CaptureDirection, frame: &[u8]) {
+    if let Some(sink) = critical_section::with(|_| unsafe { SINK }) {
+        sink(interface, direction, get_systimer_count() / 16, frame);
+    }
+}
+
+/// Write the header of a pcap file (Ethernet frames, microsecond timestamps). It needs to be
+/// sent once before the records, e.g. when the sink is set.
+///
+/// Don't write the capture to the serial port used for logging, the log output would end up
+/// in the capture. Use the second UART or turn the log off.
+pub fn pcap_write_header<W: Write<u8>>(writer: &mut W) -> Result<(), W::Error> {
+    let mut header = [0u8; 24];
+    header[0..4].copy_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+    // version 2.4
+    header[4..6].copy_from_slice(&2u16.to_le_bytes());
+    header[6..8].copy_from_slice(&4u16.to_le_bytes());
+    // time zone offset and timestamp accuracy are always 0
+    header[16..20].copy_from_slice(&(crate::wifi::MAX_FRAME_LEN as u32).to_le_bytes());
+    header[20..24].copy_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+
+    writer.bwrite_all(&header)?;
+    writer.bflush()
+}
+
+/// Write `frame` as a pcap record
+pub fn pcap_write_record<W: Write<u8>>(
+    writer: &mut W,
+    timestamp_us: u64,
+    frame: &[u8],
+) -> Result<(), W::Error> {
+    let mut header = [0u8; 16];
+    header[0..4].copy_from_slice(&((timestamp_us / 1_000_000) as u32).to_le_bytes());
+    header[4..8].copy_from_slice(&((timestamp_us % 1_000_000) as u32).to_le_bytes());
+    // captured and original length
+    header[8..12].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+    header[12..16].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+
+    writer.bwrite_all(&header)?;
+    writer.bwrite_all(frame)?;
+    writer.bflush()
+}
+
+/// A sink printing the addresses, protocol and ports of each frame, UDP payloads as text
+pub fn capture_print(
+    interface: WifiInterface,
+    direction: CaptureDirection,
+    _timestamp_us: u64,
+    frame: &[u8],
+) {
+    println!("{:?} {:?} {} bytes", interface, direction, frame.len());
+
+    let ef = smoltcp::wire::EthernetFrame::new_unchecked(frame);
+    println!(
+        "src={:x?} dst={:x?} type={:x?}",
+        ef.src_addr(),
+        ef.dst_addr(),
+        ef.ethertype()
+    );
+    match ef.ethertype() {
+        smoltcp::wire::EthernetProtocol::Ipv4 => {
+            let ip = smoltcp::wire::Ipv4Packet::new_unchecked(ef.payload());
+            println!(
+                "src={:?} dst={:?} proto={:x?}",
+                ip.src_addr(),
+                ip.dst_addr(),
+                ip.protocol()
+            );
+
+            match ip.protocol() {
+                smoltcp::wire::IpProtocol::HopByHop => {}
+                smoltcp::wire::IpProtocol::Icmp => {}
+                smoltcp::wire::IpProtocol::Igmp => {}
+                smoltcp::wire::IpProtocol::Tcp => {
+                    let tp = smoltcp::wire::TcpPacket::new_unchecked(ip.payload());
+                    println!("src={:?} dst={:?}", tp.src_port(), tp.dst_port());
+                }
+                smoltcp::wire::IpProtocol::Udp => {
+                    let up = smoltcp::wire::UdpPacket::new_unchecked(ip.payload());
+                    println!("src={:?} dst={:?}", up.src_port(), up.dst_port());
+
+                    for c in up.payload() {
+                        if *c >= 32 {
+                            print!("{}", *c as char);
+                        } else {
+                            print!(".");
+                        }
+                    }
+                }
+                smoltcp::wire::IpProtocol::Ipv6Route => {}
+                smoltcp::wire::IpProtocol::Ipv6Frag => {}
+                smoltcp::wire::IpProtocol::Icmpv6 => {}
+                smoltcp::wire::IpProtocol::Ipv6NoNxt => {}
+                smoltcp::wire::IpProtocol::Ipv6Opts => {}
+                smoltcp::wire::IpProtocol::Unknown(_) => {}
+            }
+        }
+        smoltcp::wire::EthernetProtocol::Arp => {
+            let ap = smoltcp::wire::ArpPacket::new_unchecked(ef.payload());
+            println!(
+                "src={:x?} dst={:x?} src proto addr={:x?}",
+                ap.source_hardware_addr(),
+                ap.target_hardware_addr(),
+                ap.source_protocol_addr()
+            );
+        }
+        smoltcp::wire::EthernetProtocol::Ipv6 => {}
+        smoltcp::wire::EthernetProtocol::Unknown(_) => {}
+    }
+}
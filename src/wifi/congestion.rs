@@ -0,0 +1,99 @@
+use crate::{
+    debug,
+    wifi::{queue_event, WifiEvent},
+};
+
+/// How congested the TX path is
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum CongestionLevel {
+    /// Frames get sent again
+    Cleared,
+    /// Frames failed to be sent for a while, producers should slow down
+    Moderate,
+    /// The driver is out of buffers, producers should pause
+    Severe,
+}
+
+static mut MODERATE_THRESHOLD: u32 = 8;
+static mut SEVERE_THRESHOLD: u32 = 32;
+// frames in a row which couldn't be queued or sent
+static mut FAILURES: u32 = 0;
+static mut LEVEL: CongestionLevel = CongestionLevel::Cleared;
+
+/// Number of frames in a row which couldn't be sent (the driver was out of buffers or the TX
+/// buffer was still busy) before `WifiEvent::Congestion` is sent with `CongestionLevel::Moderate`
+/// / `CongestionLevel::Severe`. The defaults are 8 and 32.
+///
+/// `CongestionLevel::Cleared` is sent once a frame is sent again.
+pub fn congestion_set_thresholds(moderate: u32, severe: u32) {
+    critical_section::with(|_| unsafe {
+        MODERATE_THRESHOLD = moderate;
+        SEVERE_THRESHOLD = severe;
+    });
+}
+
+/// The current level, the events may get dropped if they aren't taken in time
+pub fn congestion_level() -> CongestionLevel {
+    critical_section::with(|_| unsafe { LEVEL })
+}
+
+pub(crate) fn reset() {
+    critical_section::with(|_| unsafe {
+        FAILURES = 0;
+        LEVEL = CongestionLevel::Cleared;
+    });
+}
+
+/// `res` is what `esp_wifi_internal_tx` returned
+pub(crate) fn note_tx_result(res: i32) {
+    if res == 0 {
+        note_sent();
+    } else {
+        note_failure();
+    }
+}
+
+/// A frame couldn't be queued since the TX buffer is still busy
+pub(crate) fn note_tx_busy() {
+    note_failure();
+}
+
+fn note_sent() {
+    let changed = critical_section::with(|_| unsafe {
+        FAILURES = 0;
+        set_level(CongestionLevel::Cleared)
+    });
+
+    if let Some(level) = changed {
+        queue_event(WifiEvent::Congestion(level));
+    }
+}
+
+fn note_failure() {
+    let changed = critical_section::with(|_| unsafe {
+        FAILURES = FAILURES.saturating_add(1);
+
+        if FAILURES >= SEVERE_THRESHOLD {
+            set_level(CongestionLevel::Severe)
+        } else if FAILURES >= MODERATE_THRESHOLD && LEVEL < CongestionLevel::Moderate {
+            set_level(CongestionLevel::Moderate)
+        } else {
+            None
+        }
+    });
+
+    if let Some(level) = changed {
+        debug!("TX congestion {:?}", level);
+        queue_event(WifiEvent::Congestion(level));
+    }
+}
+
+/// Returns the new level if it changed. Needs to be called in a critical section.
+unsafe fn set_level(level: CongestionLevel) -> Option<CongestionLevel> {
+    if LEVEL == level {
+        None
+    } else {
+        LEVEL = level;
+        Some(level)
+    }
+}
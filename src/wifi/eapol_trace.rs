@@ -0,0 +1,135 @@
+use crate::{
+    println,
+    wifi::{DisconnectReason, WifiEvent},
+};
+
+const EAPOL_TYPE_EAP: u8 = 0;
+const EAPOL_TYPE_START: u8 = 1;
+const EAPOL_TYPE_KEY: u8 = 3;
+
+const KEY_INFO_PAIRWISE: u16 = 0x0008;
+const KEY_INFO_INSTALL: u16 = 0x0040;
+const KEY_INFO_ACK: u16 = 0x0080;
+const KEY_INFO_MIC: u16 = 0x0100;
+const KEY_INFO_SECURE: u16 = 0x0200;
+
+static mut ENABLED: bool = false;
+// last message of the 4-way handshake seen since connecting, 0 if none
+static mut LAST_MESSAGE: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Direction {
+    Rx,
+    Tx,
+}
+
+/// Print every EAPOL frame of the station (EAP exchange, 4-way and group key handshake with
+/// message numbers and replay counters) and the reason when the station gets disconnected
+/// during the handshake. Meant to triage failures against enterprise networks from logs alone.
+///
+/// The frames are only visible with an external supplicant (`wifi_set_external_supplicant`),
+/// the driver's supplicant handles them internally. Then only the handshake failures are printed.
+pub fn eapol_trace_set_enabled(enabled: bool) {
+    critical_section::with(|_| unsafe {
+        ENABLED = enabled;
+        LAST_MESSAGE = 0;
+    });
+}
+
+pub(crate) fn note_frame(frame: &[u8], direction: Direction) {
+    if !critical_section::with(|_| unsafe { ENABLED }) || frame.len() < 18 {
+        return;
+    }
+
+    let version = frame[14];
+    let body = &frame[18..];
+    match frame[15] {
+        EAPOL_TYPE_EAP if body.len() >= 4 => {
+            let eap_type = body.get(4).copied().unwrap_or(0);
+            println!(
+                "EAPOL {:?} v{} EAP code {} id {} type {}",
+                direction, version, body[0], body[1], eap_type
+            );
+        }
+        EAPOL_TYPE_START => println!("EAPOL {:?} v{} start", direction, version),
+        EAPOL_TYPE_KEY if body.len() >= 13 => {
+            let key_info = u16::from_be_bytes([body[1], body[2]]);
+            let replay_counter = u64::from_be_bytes([
+                body[5], body[6], body[7], body[8], body[9], body[10], body[11], body[12],
+            ]);
+
+            match key_message(key_info) {
+                Some((true, message)) => {
+                    critical_section::with(|_| unsafe { LAST_MESSAGE = message });
+                    println!(
+                        "EAPOL {:?} v{} 4-way handshake message {}/4 replay counter {}",
+                        direction, version, message, replay_counter
+                    );
+                }
+                Some((false, message)) => println!(
+                    "EAPOL {:?} v{} group key handshake message {}/2 replay counter {}",
+                    direction, version, message, replay_counter
+                ),
+                None => println!(
+                    "EAPOL {:?} v{} key frame with key info {:04x}",
+                    direction, version, key_info
+                ),
+            }
+        }
+        other => println!("EAPOL {:?} v{} type {}", direction, version, other),
+    }
+}
+
+pub(crate) fn note_event(event: &WifiEvent) {
+    match event {
+        WifiEvent::StaConnected { .. } => critical_section::with(|_| unsafe { LAST_MESSAGE = 0 }),
+        WifiEvent::StaDisconnected { reason, .. } => note_disconnected(*reason),
+        _ => (),
+    }
+}
+
+fn note_disconnected(reason: u8) {
+    let (enabled, last_message) = critical_section::with(|_| unsafe {
+        let last_message = LAST_MESSAGE;
+        LAST_MESSAGE = 0;
+        (ENABLED, last_message)
+    });
+
+    if !enabled {
+        return;
+    }
+
+    let reason_text = DisconnectReason::from_raw(reason);
+    if last_message == 0 {
+        println!(
+            "EAPOL disconnected without seeing the 4-way handshake, reason {} ({:?})",
+            reason, reason_text
+        );
+    } else if last_message < 4 {
+        println!(
+            "EAPOL handshake failed after message {}/4, reason {} ({:?})",
+            last_message, reason, reason_text
+        );
+    }
+}
+
+/// Whether it's a pairwise (4-way handshake) frame and its message number
+fn key_message(key_info: u16) -> Option<(bool, u8)> {
+    let pairwise = key_info & KEY_INFO_PAIRWISE != 0;
+    let ack = key_info & KEY_INFO_ACK != 0;
+    let mic = key_info & KEY_INFO_MIC != 0;
+    let install = key_info & KEY_INFO_INSTALL != 0;
+    let secure = key_info & KEY_INFO_SECURE != 0;
+
+    let message = match (pairwise, ack, mic) {
+        (true, true, false) => 1,
+        (true, false, true) if !secure => 2,
+        (true, true, true) if install => 3,
+        (true, false, true) => 4,
+        (false, true, true) => 1,
+        (false, false, true) => 2,
+        _ => return None,
+    };
+
+    Some((pairwise, message))
+}
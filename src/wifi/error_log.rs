@@ -0,0 +1,179 @@
+use crate::{binary::include::ESP_ERR_NO_MEM, timer::get_systimer_count, wifi::WifiEvent};
+
+const LOG_LEN: usize = 16;
+const ERROR_LOG_MAGIC: u32 = 0x4552_524c;
+
+/// What failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorSource {
+    /// `wifi_init`, the code is the driver's error code
+    Init,
+    /// `wifi_start`
+    Start,
+    /// `wifi_connect` / `wifi_connect_with_config`
+    Connect,
+    /// `wifi_start_scan`
+    Scan,
+    /// Sending a frame, running out of buffers isn't logged
+    Tx,
+    /// The station got disconnected, the code is the reason (see `DisconnectReason`)
+    Disconnected,
+    /// No beacons were received from the AP for a while
+    BeaconTimeout,
+}
+
+impl ErrorSource {
+    fn from_raw(source: u8) -> Option<ErrorSource> {
+        Some(match source {
+            0 => ErrorSource::Init,
+            1 => ErrorSource::Start,
+            2 => ErrorSource::Connect,
+            3 => ErrorSource::Scan,
+            4 => ErrorSource::Tx,
+            5 => ErrorSource::Disconnected,
+            6 => ErrorSource::BeaconTimeout,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ErrorRecord {
+    /// Boot the error happened in, compare with `error_log_boot` to find the ones from before
+    /// the last reset
+    pub boot: u32,
+    /// Uptime in that boot
+    pub uptime_ms: u64,
+    pub source: ErrorSource,
+    pub code: i32,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct RawRecord {
+    boot: u32,
+    source: u8,
+    code: i32,
+    uptime_ms: u64,
+}
+
+const EMPTY_RECORD: RawRecord = RawRecord {
+    boot: 0,
+    source: 0,
+    code: 0,
+    uptime_ms: 0,
+};
+
+// The log is kept in RTC fast memory which is retained over non power-on resets
+// (watchdog, panic, brownout) so it can be read out after the reset
+#[link_section = ".rtc_fast.noinit"]
+static mut LOG_MAGIC: u32 = 0;
+#[link_section = ".rtc_fast.noinit"]
+static mut BOOT: u32 = 0;
+#[link_section = ".rtc_fast.noinit"]
+static mut RECORDS: [RawRecord; LOG_LEN] = [EMPTY_RECORD; LOG_LEN];
+// index of the next record to write and how many are valid
+#[link_section = ".rtc_fast.noinit"]
+static mut NEXT: usize = 0;
+#[link_section = ".rtc_fast.noinit"]
+static mut COUNT: usize = 0;
+
+static mut BOOT_COUNTED: bool = false;
+
+/// Copy the logged errors into `records`, oldest first. Returns how many were copied.
+///
+/// The last 16 errors are kept, they survive resets other than power-on resets. Read them after
+/// booting and ship them to a backend to find out why a device in the field reset.
+pub fn error_log_read(records: &mut [ErrorRecord]) -> usize {
+    critical_section::with(|_| unsafe {
+        validate();
+
+        let first = (NEXT + LOG_LEN - COUNT) % LOG_LEN;
+        let mut copied = 0;
+        for i in 0..COUNT {
+            if copied == records.len() {
+                break;
+            }
+
+            let raw = RECORDS[(first + i) % LOG_LEN];
+            if let Some(source) = ErrorSource::from_raw(raw.source) {
+                records[copied] = ErrorRecord {
+                    boot: raw.boot,
+                    uptime_ms: raw.uptime_ms,
+                    source,
+                    code: raw.code,
+                };
+                copied += 1;
+            }
+        }
+        copied
+    })
+}
+
+/// Forget the logged errors, e.g. after they were shipped
+pub fn error_log_clear() {
+    critical_section::with(|_| unsafe {
+        validate();
+        NEXT = 0;
+        COUNT = 0;
+    });
+}
+
+/// The number of the current boot, counted since the last power-on reset
+pub fn error_log_boot() -> u32 {
+    critical_section::with(|_| unsafe {
+        validate();
+        BOOT
+    })
+}
+
+pub(crate) fn record(source: ErrorSource, code: i32) {
+    let uptime_ms = get_systimer_count() / 16_000;
+
+    critical_section::with(|_| unsafe {
+        validate();
+
+        RECORDS[NEXT] = RawRecord {
+            boot: BOOT,
+            source: source as u8,
+            code,
+            uptime_ms,
+        };
+        NEXT = (NEXT + 1) % LOG_LEN;
+        COUNT = usize::min(COUNT + 1, LOG_LEN);
+    });
+}
+
+/// `res` is what `esp_wifi_internal_tx` returned
+pub(crate) fn note_tx_result(res: i32) {
+    // running out of buffers is reported as congestion
+    if res != 0 && res != ESP_ERR_NO_MEM as i32 {
+        record(ErrorSource::Tx, res);
+    }
+}
+
+pub(crate) fn note_event(event: &WifiEvent) {
+    match event {
+        WifiEvent::StaDisconnected { reason, .. } => {
+            record(ErrorSource::Disconnected, *reason as i32)
+        }
+        WifiEvent::StaBeaconTimeout => record(ErrorSource::BeaconTimeout, 0),
+        _ => (),
+    }
+}
+
+/// Starts a new log after a power-on reset (the RTC memory content is random then) and counts
+/// the boots. Needs to be called in a critical section.
+unsafe fn validate() {
+    if LOG_MAGIC != ERROR_LOG_MAGIC || NEXT >= LOG_LEN || COUNT > LOG_LEN {
+        LOG_MAGIC = ERROR_LOG_MAGIC;
+        BOOT = 0;
+        NEXT = 0;
+        COUNT = 0;
+    }
+
+    if !BOOT_COUNTED {
+        BOOT_COUNTED = true;
+        BOOT = BOOT.wrapping_add(1);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{
+    binary::include::{g_wifi_default_wpa_crypto_funcs, ESP_ERR_INVALID_ARG},
+    debug,
+    wifi::{
+        wifi_connect_with_config, wifi_get_ap_info, wifi_negotiated_auth_method, AuthMethod,
+        EspError, StaConfig,
+    },
+};
+
+const CACHE_VERSION: u8 = 1;
+const FLAG_HAS_PMK: u8 = 1 << 0;
+
+// set if the last connection was made using the cached info, which is known to work then
+static mut CONNECTED_FROM_CACHE: bool = false;
+
+/// Size of the cached AP info in storage
+pub const CACHED_AP_LEN: usize = 74;
+
+/// Keeps the cached AP info across resets, e.g. in flash or RTC memory.
+pub trait ApCacheStorage {
+    /// Fill `buffer` with the stored data, returns false if nothing is stored
+    fn load(&mut self, buffer: &mut [u8; CACHED_AP_LEN]) -> bool;
+
+    fn store(&mut self, data: &[u8; CACHED_AP_LEN]);
+}
+
+/// What is needed to associate without scanning
+#[derive(Clone, Copy)]
+struct CachedAp {
+    ssid: [u8; 32],
+    ssid_len: u8,
+    bssid: [u8; 6],
+    channel: u8,
+    // not available for WPA3 - SAE needs the password
+    pmk: Option<[u8; 32]>,
+}
+
+impl CachedAp {
+    fn to_bytes(&self) -> [u8; CACHED_AP_LEN] {
+        let mut data = [0u8; CACHED_AP_LEN];
+        data[0] = CACHE_VERSION;
+        data[1] = if self.pmk.is_some() { FLAG_HAS_PMK } else { 0 };
+        data[2] = self.ssid_len;
+        data[3..35].copy_from_slice(&self.ssid);
+        data[35..41].copy_from_slice(&self.bssid);
+        data[41] = self.channel;
+        data[42..74].copy_from_slice(&self.pmk.unwrap_or_default());
+        data
+    }
+
+    fn from_bytes(data: &[u8; CACHED_AP_LEN]) -> Option<CachedAp> {
+        if data[0] != CACHE_VERSION || data[2] as usize > 32 {
+            return None;
+        }
+
+        let mut cached = CachedAp {
+            ssid: [0u8; 32],
+            ssid_len: data[2],
+            bssid: [0u8; 6],
+            channel: data[41],
+            pmk: None,
+        };
+        cached.ssid.copy_from_slice(&data[3..35]);
+        cached.bssid.copy_from_slice(&data[35..41]);
+        if data[1] & FLAG_HAS_PMK != 0 {
+            let mut pmk = [0u8; 32];
+            pmk.copy_from_slice(&data[42..74]);
+            cached.pmk = Some(pmk);
+        }
+        Some(cached)
+    }
+
+    fn ssid(&self) -> &[u8] {
+        &self.ssid[..self.ssid_len as usize]
+    }
+}
+
+/// Connect using the AP info cached by `fast_connect_remember` if it's for the same SSID.
+/// The cached BSSID and channel are used so no full scan is needed, and for WPA2 the cached
+/// PMK instead of the password so the expensive key derivation is skipped. This way the
+/// station associates in a few hundred milliseconds after a reset instead of seconds.
+///
+/// Falls back to a normal `wifi_connect` if nothing is cached. If the fast connection fails
+/// (e.g. the AP moved to another channel or the password changed) call `fast_connect_forget`
+/// and connect again.
+pub fn fast_connect(
+    ssid: &str,
+    password: &str,
+    storage: &mut impl ApCacheStorage,
+) -> Result<(), EspError> {
+    let mut data = [0u8; CACHED_AP_LEN];
+    let cached = if storage.load(&mut data) {
+        CachedAp::from_bytes(&data).filter(|cached| cached.ssid() == ssid.as_bytes())
+    } else {
+        None
+    };
+
+    critical_section::with(|_| unsafe { CONNECTED_FROM_CACHE = cached.is_some() });
+
+    let cached = match cached {
+        Some(cached) => cached,
+        None => {
+            debug!("no cached AP info for this SSID");
+            return wifi_connect_with_config(&StaConfig::new(ssid, password));
+        }
+    };
+
+    // the supplicant takes 64 hex digits as the PSK itself
+    let mut pmk_hex = [0u8; 64];
+    let password = match cached.pmk {
+        Some(pmk) => {
+            const HEX: &[u8; 16] = b"0123456789abcdef";
+            for (i, b) in pmk.iter().enumerate() {
+                pmk_hex[i * 2] = HEX[(b >> 4) as usize];
+                pmk_hex[i * 2 + 1] = HEX[(b & 0xf) as usize];
+            }
+            core::str::from_utf8(&pmk_hex).unwrap_or(password)
+        }
+        None => password,
+    };
+
+    wifi_connect_with_config(&StaConfig {
+        bssid: Some(cached.bssid),
+        channel: Some(cached.channel),
+        ..StaConfig::new(ssid, password)
+    })
+}
+
+/// Cache the info of the AP the station is connected to. Call it once connected.
+/// The storage is only written if the info changed.
+pub fn fast_connect_remember(ssid: &str, password: &str, storage: &mut impl ApCacheStorage) -> i32 {
+    if ssid.len() > 32 {
+        return ESP_ERR_INVALID_ARG as i32;
+    }
+
+    let info = match wifi_get_ap_info() {
+        Ok(info) => info,
+        Err(res) => return res,
+    };
+
+    let mut cached = CachedAp {
+        ssid: [0u8; 32],
+        ssid_len: ssid.len() as u8,
+        bssid: info.bssid,
+        channel: info.channel,
+        pmk: None,
+    };
+    cached.ssid[..ssid.len()].copy_from_slice(ssid.as_bytes());
+
+    let psk = matches!(
+        wifi_negotiated_auth_method(),
+        Some(AuthMethod::WPA2Personal | AuthMethod::WPAWPA2Personal | AuthMethod::WPA)
+    );
+
+    let mut stored = [0u8; CACHED_AP_LEN];
+    let previous = if storage.load(&mut stored) {
+        CachedAp::from_bytes(&stored)
+    } else {
+        None
+    };
+
+    if psk {
+        // deriving the PMK takes a while, reuse it if it was just used successfully
+        let from_cache = critical_section::with(|_| unsafe { CONNECTED_FROM_CACHE });
+        cached.pmk = match previous {
+            Some(previous) if from_cache && previous.pmk.is_some() => previous.pmk,
+            _ => derive_pmk(ssid, password),
+        };
+    }
+
+    let data = cached.to_bytes();
+    if stored != data {
+        storage.store(&data);
+    }
+
+    0
+}
+
+/// Drop the cached AP info, the next `fast_connect` scans again
+pub fn fast_connect_forget(storage: &mut impl ApCacheStorage) {
+    storage.store(&[0u8; CACHED_AP_LEN]);
+}
+
+fn derive_pmk(ssid: &str, password: &str) -> Option<[u8; 32]> {
+    // 64 characters are the PSK in hex already, which is used as is
+    if password.len() >= 64 {
+        return None;
+    }
+
+    // the passphrase needs to be NUL terminated
+    let mut passphrase = [0u8; 64];
+    passphrase[..password.len()].copy_from_slice(password.as_bytes());
+
+    let pbkdf2_sha1 = unsafe { g_wifi_default_wpa_crypto_funcs.pbkdf2_sha1? };
+
+    let mut pmk = [0u8; 32];
+    let res = unsafe {
+        pbkdf2_sha1(
+            passphrase.as_ptr() as *const _,
+            ssid.as_ptr() as *const _,
+            ssid.len() as u32,
+            4096,
+            pmk.as_mut_ptr(),
+            pmk.len() as u32,
+        )
+    };
+
+    if res == 0 {
+        Some(pmk)
+    } else {
+        None
+    }
+}
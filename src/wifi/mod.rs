@@ -1,4 +1,26 @@
+mod ack_coalescing;
+#[cfg(feature = "alloc")]
+pub mod alloc_helpers;
+pub mod ap_isolation;
+pub mod backend;
+pub mod bridge;
+pub mod bss_load;
+pub mod capture;
+pub mod congestion;
+pub mod eapol_trace;
+pub mod error_log;
+pub mod fast_connect;
 pub mod os_adapter;
+pub mod provisioning;
+pub mod radio;
+pub mod reconnect;
+mod smoltcp_compat;
+pub mod sniffer;
+pub mod tx_credits;
+use bss_load::BssLoad;
+use congestion::CongestionLevel;
+use embedded_hal::prelude::_embedded_hal_blocking_rng_Read;
+use error_log::ErrorSource;
 use hal::Rng;
 pub use os_adapter::*;
 use smoltcp::phy::{Device, DeviceCapabilities, RxToken, TxToken};
@@ -6,48 +28,330 @@ mod phy_init_data;
 
 use crate::{
     binary::include::{
-        __BindgenBitfieldUnit, esp_err_t, esp_interface_t_ESP_IF_WIFI_STA, esp_supplicant_init,
-        esp_wifi_connect, esp_wifi_init_internal, esp_wifi_internal_free_rx_buffer,
-        esp_wifi_internal_reg_rxcb, esp_wifi_internal_set_log_level, esp_wifi_internal_set_log_mod,
-        esp_wifi_internal_tx, esp_wifi_scan_start, esp_wifi_set_config, esp_wifi_set_country,
-        esp_wifi_set_mode, esp_wifi_set_ps, esp_wifi_set_tx_done_cb, esp_wifi_start, esp_wifi_stop,
-        g_wifi_default_wpa_crypto_funcs, u_int32_t, wifi_active_scan_time_t,
-        wifi_auth_mode_t_WIFI_AUTH_OPEN, wifi_config_t,
-        wifi_country_policy_t_WIFI_COUNTRY_POLICY_MANUAL, wifi_country_t, wifi_init_config_t,
+        __BindgenBitfieldUnit, esp_err_t, esp_interface_t_ESP_IF_WIFI_AP,
+        esp_interface_t_ESP_IF_WIFI_STA, esp_supplicant_deinit, esp_supplicant_init,
+        esp_wifi_connect, esp_wifi_deinit_internal, esp_wifi_get_country,
+        esp_wifi_get_max_tx_power, esp_wifi_get_protocol, esp_wifi_get_ps, esp_wifi_get_tsf_time,
+        esp_wifi_init_internal, esp_wifi_internal_free_rx_buffer,
+        esp_wifi_internal_reg_netstack_buf_cb, esp_wifi_internal_reg_rxcb,
+        esp_wifi_internal_set_fix_rate, esp_wifi_internal_set_log_level,
+        esp_wifi_internal_set_log_mod, esp_wifi_scan_get_ap_num, esp_wifi_scan_get_ap_records,
+        esp_wifi_scan_start, esp_wifi_scan_stop, esp_wifi_set_config, esp_wifi_set_country,
+        esp_wifi_set_max_tx_power, esp_wifi_set_mode, esp_wifi_set_protocol, esp_wifi_set_ps,
+        esp_wifi_set_storage, esp_wifi_set_tx_done_cb, esp_wifi_set_vendor_ie,
+        esp_wifi_sta_get_ap_info, g_wifi_default_wpa_crypto_funcs, size_t, u_int32_t,
+        wifi_active_scan_time_t, wifi_ap_config_t, wifi_ap_record_t, wifi_auth_mode_t,
+        wifi_auth_mode_t_WIFI_AUTH_MAX, wifi_auth_mode_t_WIFI_AUTH_OPEN,
+        wifi_auth_mode_t_WIFI_AUTH_WAPI_PSK, wifi_auth_mode_t_WIFI_AUTH_WEP,
+        wifi_auth_mode_t_WIFI_AUTH_WPA2_ENTERPRISE, wifi_auth_mode_t_WIFI_AUTH_WPA2_PSK,
+        wifi_auth_mode_t_WIFI_AUTH_WPA2_WPA3_PSK, wifi_auth_mode_t_WIFI_AUTH_WPA3_PSK,
+        wifi_auth_mode_t_WIFI_AUTH_WPA_PSK, wifi_auth_mode_t_WIFI_AUTH_WPA_WPA2_PSK,
+        wifi_cipher_type_t, wifi_cipher_type_t_WIFI_CIPHER_TYPE_AES_CMAC128,
+        wifi_cipher_type_t_WIFI_CIPHER_TYPE_CCMP, wifi_cipher_type_t_WIFI_CIPHER_TYPE_NONE,
+        wifi_cipher_type_t_WIFI_CIPHER_TYPE_SMS4, wifi_cipher_type_t_WIFI_CIPHER_TYPE_TKIP,
+        wifi_cipher_type_t_WIFI_CIPHER_TYPE_TKIP_CCMP, wifi_cipher_type_t_WIFI_CIPHER_TYPE_WEP104,
+        wifi_cipher_type_t_WIFI_CIPHER_TYPE_WEP40, wifi_config_t,
+        wifi_country_policy_t_WIFI_COUNTRY_POLICY_AUTO,
+        wifi_country_policy_t_WIFI_COUNTRY_POLICY_MANUAL, wifi_country_t, wifi_err_reason_t,
+        wifi_err_reason_t_WIFI_REASON_4WAY_HANDSHAKE_TIMEOUT,
+        wifi_err_reason_t_WIFI_REASON_ASSOC_FAIL, wifi_err_reason_t_WIFI_REASON_ASSOC_LEAVE,
+        wifi_err_reason_t_WIFI_REASON_AUTH_EXPIRE, wifi_err_reason_t_WIFI_REASON_AUTH_FAIL,
+        wifi_err_reason_t_WIFI_REASON_BEACON_TIMEOUT,
+        wifi_err_reason_t_WIFI_REASON_HANDSHAKE_TIMEOUT, wifi_err_reason_t_WIFI_REASON_NO_AP_FOUND,
+        wifi_event_ap_staconnected_t, wifi_event_ap_stadisconnected_t, wifi_event_sta_connected_t,
+        wifi_event_sta_disconnected_t, wifi_event_sta_scan_done_t, wifi_event_t,
+        wifi_event_t_WIFI_EVENT_ACTION_TX_STATUS, wifi_event_t_WIFI_EVENT_AP_PROBEREQRECVED,
+        wifi_event_t_WIFI_EVENT_AP_STACONNECTED, wifi_event_t_WIFI_EVENT_AP_STADISCONNECTED,
+        wifi_event_t_WIFI_EVENT_AP_START, wifi_event_t_WIFI_EVENT_AP_STOP,
+        wifi_event_t_WIFI_EVENT_FTM_REPORT, wifi_event_t_WIFI_EVENT_ROC_DONE,
+        wifi_event_t_WIFI_EVENT_SCAN_DONE, wifi_event_t_WIFI_EVENT_STA_AUTHMODE_CHANGE,
+        wifi_event_t_WIFI_EVENT_STA_BEACON_TIMEOUT, wifi_event_t_WIFI_EVENT_STA_BSS_RSSI_LOW,
+        wifi_event_t_WIFI_EVENT_STA_CONNECTED, wifi_event_t_WIFI_EVENT_STA_DISCONNECTED,
+        wifi_event_t_WIFI_EVENT_STA_START, wifi_event_t_WIFI_EVENT_STA_STOP,
+        wifi_event_t_WIFI_EVENT_STA_WPS_ER_FAILED, wifi_event_t_WIFI_EVENT_STA_WPS_ER_PBC_OVERLAP,
+        wifi_event_t_WIFI_EVENT_STA_WPS_ER_PIN, wifi_event_t_WIFI_EVENT_STA_WPS_ER_SUCCESS,
+        wifi_event_t_WIFI_EVENT_STA_WPS_ER_TIMEOUT, wifi_event_t_WIFI_EVENT_WIFI_READY,
+        wifi_init_config_t, wifi_interface_t, wifi_interface_t_WIFI_IF_AP,
         wifi_interface_t_WIFI_IF_STA, wifi_log_level_t, wifi_log_module_t_WIFI_LOG_MODULE_ALL,
-        wifi_mode_t_WIFI_MODE_STA, wifi_osi_funcs_t, wifi_pmf_config_t,
+        wifi_mode_t, wifi_mode_t_WIFI_MODE_AP, wifi_mode_t_WIFI_MODE_APSTA,
+        wifi_mode_t_WIFI_MODE_STA, wifi_osi_funcs_t, wifi_phy_rate_t_WIFI_PHY_RATE_11M_L,
+        wifi_phy_rate_t_WIFI_PHY_RATE_11M_S, wifi_phy_rate_t_WIFI_PHY_RATE_1M_L,
+        wifi_phy_rate_t_WIFI_PHY_RATE_2M_L, wifi_phy_rate_t_WIFI_PHY_RATE_2M_S,
+        wifi_phy_rate_t_WIFI_PHY_RATE_5M_L, wifi_phy_rate_t_WIFI_PHY_RATE_5M_S, wifi_pmf_config_t,
+        wifi_ps_type_t, wifi_ps_type_t_WIFI_PS_MAX_MODEM, wifi_ps_type_t_WIFI_PS_MIN_MODEM,
         wifi_ps_type_t_WIFI_PS_NONE, wifi_scan_config_t, wifi_scan_method_t_WIFI_FAST_SCAN,
         wifi_scan_threshold_t, wifi_scan_time_t, wifi_scan_type_t_WIFI_SCAN_TYPE_ACTIVE,
-        wifi_sort_method_t_WIFI_CONNECT_AP_BY_SIGNAL, wifi_sta_config_t, wpa_crypto_funcs_t,
-        ESP_WIFI_OS_ADAPTER_MAGIC, ESP_WIFI_OS_ADAPTER_VERSION, WIFI_INIT_CONFIG_MAGIC,
-        WIFI_LOG_SUBMODULE_ALL,
+        wifi_scan_type_t_WIFI_SCAN_TYPE_PASSIVE, wifi_sort_method_t_WIFI_CONNECT_AP_BY_SIGNAL,
+        wifi_sta_config_t, wifi_storage_t_WIFI_STORAGE_RAM, wifi_vendor_ie_id_t,
+        wifi_vendor_ie_type_t_WIFI_VND_IE_TYPE_PROBE_REQ, wpa_crypto_funcs_t, ESP_ERR_INVALID_ARG,
+        ESP_ERR_INVALID_SIZE, ESP_ERR_INVALID_STATE, ESP_ERR_NOT_FOUND, ESP_ERR_NOT_SUPPORTED,
+        ESP_ERR_NO_MEM, ESP_ERR_TIMEOUT, ESP_ERR_WIFI_CONN, ESP_ERR_WIFI_IF, ESP_ERR_WIFI_MAC,
+        ESP_ERR_WIFI_MODE, ESP_ERR_WIFI_NOT_CONNECT, ESP_ERR_WIFI_NOT_INIT,
+        ESP_ERR_WIFI_NOT_STARTED, ESP_ERR_WIFI_NOT_STOPPED, ESP_ERR_WIFI_NVS,
+        ESP_ERR_WIFI_PASSWORD, ESP_ERR_WIFI_SSID, ESP_ERR_WIFI_STATE, ESP_ERR_WIFI_TIMEOUT,
+        ESP_ERR_WIFI_WAKE_FAIL, ESP_ERR_WIFI_WOULD_BLOCK, ESP_FAIL, ESP_WIFI_OS_ADAPTER_MAGIC,
+        ESP_WIFI_OS_ADAPTER_VERSION, WIFI_INIT_CONFIG_MAGIC, WIFI_LOG_SUBMODULE_ALL,
+        WIFI_PROTOCOL_11B, WIFI_PROTOCOL_11G, WIFI_PROTOCOL_11N, WIFI_PROTOCOL_LR,
     },
     compat::queue::SimpleQueue,
-    debug, print, println, verbose,
+    debug,
+    timer::get_systimer_count,
+    verbose,
 };
 
 extern "C" {
     static mut s_wifi_task_hdl: u32;
+
+    // key installation used by the driver's supplicant, not part of the generated bindings
+    fn esp_wifi_set_sta_key_internal(
+        alg: i32,
+        addr: *mut u8,
+        key_idx: i32,
+        set_tx: i32,
+        seq: *mut u8,
+        seq_len: size_t,
+        key: *mut u8,
+        key_len: size_t,
+        key_flag: i32,
+    ) -> i32;
+    fn esp_wifi_auth_done_internal();
+}
+
+/// Size of the RX / TX frame buffers, the largest Ethernet frame (header included) which can be
+/// received or sent. Nodes only exchanging small packets can reduce it to save RAM by setting
+/// `ESP_WIFI_MAX_FRAME_LEN` when building (590 at least). Larger received frames are dropped.
+pub const MAX_FRAME_LEN: usize = parse_frame_len(option_env!("ESP_WIFI_MAX_FRAME_LEN"));
+
+const DEFAULT_MAX_FRAME_LEN: usize = 1536;
+
+/// Parse a decimal number set via an env variable at build time, `None` if it isn't one
+const fn parse_number(value: &[u8]) -> Option<usize> {
+    let mut number = 0;
+    let mut i = 0;
+    while i < value.len() {
+        if !value[i].is_ascii_digit() {
+            return None;
+        }
+        number = number * 10 + (value[i] - b'0') as usize;
+        i += 1;
+    }
+    Some(number)
 }
 
-static DUMP_PACKETS: bool = false;
+const fn parse_frame_len(value: Option<&str>) -> usize {
+    let value = match value {
+        Some(value) => value.as_bytes(),
+        None => return DEFAULT_MAX_FRAME_LEN,
+    };
+
+    let len = match parse_number(value) {
+        Some(len) => len,
+        None => panic!("ESP_WIFI_MAX_FRAME_LEN must be a number"),
+    };
+
+    // IPv4 hosts need to accept datagrams of 576 bytes
+    assert!(
+        len >= 590 && len <= DEFAULT_MAX_FRAME_LEN,
+        "ESP_WIFI_MAX_FRAME_LEN must be between 590 and 1536"
+    );
+    len
+}
 
 struct DataFrame {
     len: usize,
-    data: [u8; 2500],
+    data: [u8; MAX_FRAME_LEN],
+}
+
+const RX_QUEUE_SIZE: usize = 3;
+const RX_DEFERRED_QUEUE_SIZE: usize = 4;
+
+// Received frames are copied into these buffers with interrupts enabled, the RX queues only
+// hold the index of the buffer. There is a buffer for each entry of a queue.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RxBufferState {
+    Free,
+    Writing,
+    Queued,
+    Reading,
+}
+
+const EMPTY_FRAME: DataFrame = DataFrame {
+    len: 0,
+    data: [0u8; MAX_FRAME_LEN],
+};
+const EMPTY_RX_BUFFERS: [DataFrame; RX_QUEUE_CAPACITY] = [EMPTY_FRAME; RX_QUEUE_CAPACITY];
+
+static mut RX_BUFFERS: [[DataFrame; RX_QUEUE_CAPACITY]; 2] = [EMPTY_RX_BUFFERS; 2];
+static mut RX_BUFFER_STATE: [[RxBufferState; RX_QUEUE_CAPACITY]; 2] =
+    [[RxBufferState::Free; RX_QUEUE_CAPACITY]; 2];
+
+static mut DATA_QUEUE_RX: Option<SimpleQueue<usize, RX_QUEUE_SIZE>> = None;
+static mut DATA_QUEUE_RX_AP: Option<SimpleQueue<usize, RX_QUEUE_SIZE>> = None;
+
+static mut DATA_QUEUE_RX_DEFERRED: Option<
+    SimpleQueue<
+        (
+            *mut crate::binary::c_types::c_void,
+            u16,
+            *mut crate::binary::c_types::c_void,
+            WifiInterface,
+        ),
+        RX_DEFERRED_QUEUE_SIZE,
+    >,
+> = None;
+
+// EAP-TLS / PEAP certificate fragments fill whole frames
+struct EapolFrame {
+    len: usize,
+    data: [u8; MAX_FRAME_LEN],
 }
 
-static mut DATA_QUEUE_RX: Option<SimpleQueue<DataFrame, 3>> = None;
+static mut EXTERNAL_SUPPLICANT: bool = false;
+static mut EAPOL_QUEUE_RX: Option<SimpleQueue<EapolFrame, 3>> = None;
+static mut EAPOL_DROPPED: u32 = 0;
+
+static mut RX_QUEUE_FULL_POLICY: RxQueueFullPolicy = RxQueueFullPolicy::Drop;
+static mut RX_DROPPED: u32 = 0;
 
-pub static mut TX_BUFFER: [u8; 2500] = [0u8; 2500]; // should be a queue
+static mut QUEUE_STATS: QueueStats = QueueStats {
+    sta_rx_high_water: 0,
+    ap_rx_high_water: 0,
+    rx_deferred_high_water: 0,
+    event_high_water: 0,
+};
+
+pub static mut TX_BUFFER: [u8; MAX_FRAME_LEN] = [0u8; MAX_FRAME_LEN]; // should be a queue
 pub static mut TX_QUEUED: bool = false;
+// a token was handed out for the buffer
+static mut TX_RESERVED: bool = false;
 pub static mut TX_QUEUED_DATA_LEN: u16 = 0;
+pub static mut TX_QUEUED_INTERFACE: WifiInterface = WifiInterface::Sta;
+
+// second slot handed out while the main one is busy. Control frames (ARP, DHCP, EAPOL)
+// written into it are sent first so they don't wait behind bulk data, anything else keeps
+// the order it was queued in.
+static mut TX_PRIORITY_BUFFER: [u8; MAX_FRAME_LEN] = [0u8; MAX_FRAME_LEN];
+static mut TX_PRIORITY_QUEUED: bool = false;
+// the priority slot is sent before the main one
+static mut TX_PRIORITY_FIRST: bool = false;
+static mut TX_PRIORITY_RESERVED: bool = false;
+static mut TX_PRIORITY_DATA_LEN: u16 = 0;
+static mut TX_PRIORITY_INTERFACE: WifiInterface = WifiInterface::Sta;
+
+// how often a frame is sent again while the driver is out of buffers before it's dropped
+const TX_MAX_RETRIES: u8 = 5;
+// retries of the frame currently being sent and when to try again (systimer ticks)
+static mut TX_RETRIES: u8 = 0;
+static mut TX_RETRY_AT: u64 = 0;
+static mut TX_DROPPED: u32 = 0;
+
+static mut RX_FRAMES: u32 = 0;
+static mut TX_FRAMES: u32 = 0;
+static mut LAST_TX_ERROR: Option<EspError> = None;
+
+const TX_REF_BUFFER_COUNT: usize = 4;
+const TX_REF_BUFFER_LEN: usize = MAX_FRAME_LEN;
+
+struct TxRefBuffer {
+    // held by us while the frame is written / waiting and by the driver until it's sent
+    refs: u8,
+    len: u16,
+    interface: WifiInterface,
+    data: [u8; TX_REF_BUFFER_LEN],
+}
+
+const TX_REF_BUFFER_UNUSED: TxRefBuffer = TxRefBuffer {
+    refs: 0,
+    len: 0,
+    interface: WifiInterface::Sta,
+    data: [0u8; TX_REF_BUFFER_LEN],
+};
+
+static mut TX_BY_REF: bool = false;
+static mut TX_REF_BUFFERS: [TxRefBuffer; TX_REF_BUFFER_COUNT] =
+    [TX_REF_BUFFER_UNUSED; TX_REF_BUFFER_COUNT];
+// indices of the buffers waiting to get sent, control frames go first
+static mut TX_REF_QUEUE: Option<SimpleQueue<usize, { TX_REF_BUFFER_COUNT + 1 }>> = None;
+static mut TX_REF_PRIORITY_QUEUE: Option<SimpleQueue<usize, { TX_REF_BUFFER_COUNT + 1 }>> = None;
 
 static mut RANDOM_GENERATOR: Option<Rng> = None;
 
+// set while a scan runs, scans started by several tasks run one after the other
+static mut SCAN_RUNNING: bool = false;
+// set by `wifi_abort_scan`, keeps `wifi_scan_each` from scanning the remaining channels
+static mut SCAN_ABORTED: bool = false;
+
+static mut TX_DONE_OBSERVER: Option<fn(WifiInterface, u16, bool)> = None;
+
+static mut WIFI_MODE: WifiMode = WifiMode::Sta;
+
+static mut SCAN_DURING_CONNECT: ScanDuringConnect = ScanDuringConnect::Reject;
+
+static mut POWER_SAVE_MODE: PowerSaveMode = PowerSaveMode::None;
+
+static mut WPA3_TRANSITION: Wpa3Transition = Wpa3Transition::PreferSae;
+
+static mut COUNTRY: wifi_country_t = wifi_country_t {
+    cc: [b'C', b'N', 0],
+    schan: 1,
+    nchan: 13,
+    max_tx_power: 20,
+    policy: wifi_country_policy_t_WIFI_COUNTRY_POLICY_MANUAL,
+};
+
+/// Number of events (other than state changes) which can be pending, set
+/// `ESP_WIFI_EVENT_QUEUE_SIZE` when building to change it (2 to 255).
+pub const EVENT_QUEUE_CAPACITY: usize =
+    parse_event_queue_capacity(option_env!("ESP_WIFI_EVENT_QUEUE_SIZE"));
+
+const DEFAULT_EVENT_QUEUE_CAPACITY: usize = 15;
+
+const fn parse_event_queue_capacity(value: Option<&str>) -> usize {
+    let value = match value {
+        Some(value) => value.as_bytes(),
+        None => return DEFAULT_EVENT_QUEUE_CAPACITY,
+    };
+
+    let capacity = match parse_number(value) {
+        Some(capacity) => capacity,
+        None => panic!("ESP_WIFI_EVENT_QUEUE_SIZE must be a number"),
+    };
+
+    assert!(
+        capacity >= 2 && capacity <= 255,
+        "ESP_WIFI_EVENT_QUEUE_SIZE must be between 2 and 255"
+    );
+    capacity
+}
+
+// one slot of the queue always stays free
+const EVENT_QUEUE_SIZE: usize = EVENT_QUEUE_CAPACITY + 1;
+
+static mut EVENT_QUEUE: Option<SimpleQueue<WifiEvent, EVENT_QUEUE_SIZE>> = None;
+static mut EVENTS_DROPPED: u32 = 0;
+
+// the last state change of the station, the access point and of scanning which wasn't taken
+// yet - these are never dropped, a newer one replaces an older one of the same kind
+static mut STA_STATE_EVENT: Option<WifiEvent> = None;
+static mut AP_STATE_EVENT: Option<WifiEvent> = None;
+static mut SCAN_STATE_EVENT: Option<WifiEvent> = None;
+
+static mut TSF_ALARM: Option<(u64, fn())> = None;
+
+static mut WIFI_INITIALIZED: bool = false;
+static mut WIFI_STARTED: bool = false;
+// incremented when the driver is stopped, tokens from before are invalid afterwards
+static mut LINK_GENERATION: u32 = 0;
+static mut WIFI_DEVICE_TAKEN: [bool; 2] = [false; 2];
+
 pub fn init_buffer() {
     unsafe {
         DATA_QUEUE_RX = Some(SimpleQueue::new());
+        DATA_QUEUE_RX_AP = Some(SimpleQueue::new());
+        DATA_QUEUE_RX_DEFERRED = Some(SimpleQueue::new());
+        EAPOL_QUEUE_RX = Some(SimpleQueue::new());
+        TX_REF_QUEUE = Some(SimpleQueue::new());
+        TX_REF_PRIORITY_QUEUE = Some(SimpleQueue::new());
     }
 }
 
@@ -57,6 +361,60 @@ pub fn init_rng(rng: hal::pac::RNG) {
     }
 }
 
+/// Fill `buffer` from the RNG passed to `init_rng`. Returns false if there is none.
+pub(crate) fn fill_random(buffer: &mut [u8]) -> bool {
+    critical_section::with(|_| unsafe {
+        match RANDOM_GENERATOR {
+            Some(ref mut rng) => rng.read(buffer).is_ok(),
+            None => false,
+        }
+    })
+}
+
+/// Handle to the RNG passed to `init_rng` so the application (e.g. a TLS stack) can use the
+/// hardware RNG as well. Reads are serialized with the ones of the driver.
+#[derive(Debug, Clone, Copy)]
+pub struct SharedRng {
+    _private: (),
+}
+
+/// The RNG was not initialized by `init_rng` or taken back by `deinit_rng`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RngUnavailable;
+
+impl embedded_hal::blocking::rng::Read for SharedRng {
+    type Error = RngUnavailable;
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), RngUnavailable> {
+        if fill_random(buffer) {
+            Ok(())
+        } else {
+            Err(RngUnavailable)
+        }
+    }
+}
+
+/// Get a handle to the RNG, `None` if `init_rng` wasn't called
+pub fn shared_rng() -> Option<SharedRng> {
+    critical_section::with(|_| unsafe { RANDOM_GENERATOR.is_some() })
+        .then(|| SharedRng { _private: () })
+}
+
+/// Give back the RNG passed to `init_rng`, `None` while the driver is initialized - `wifi_deinit`
+/// returns it then. Features depending on random numbers (e.g. reconnect jitter) must not be
+/// used afterwards.
+pub fn deinit_rng() -> Option<hal::pac::RNG> {
+    let rng = critical_section::with(|_| unsafe {
+        if WIFI_INITIALIZED {
+            None
+        } else {
+            RANDOM_GENERATOR.take()
+        }
+    });
+    // it was handed to us by `init_rng` so it's safe to hand it out again
+    rng.map(|_| unsafe { hal::pac::Peripherals::steal().RNG })
+}
+
 pub fn wifi_set_log_verbose() {
     let g_wifi_log_submodule: u_int32_t = WIFI_LOG_SUBMODULE_ALL;
     let level: wifi_log_level_t = crate::binary::include::wifi_log_level_t_WIFI_LOG_VERBOSE;
@@ -259,420 +617,3184 @@ pub fn get_sta_mac(mac: &mut [u8; 6]) {
     }
 }
 
-pub fn wifi_init() -> i32 {
+pub fn get_ap_mac(mac: &mut [u8; 6]) {
     unsafe {
-        G_CONFIG.wpa_crypto_funcs = g_wifi_default_wpa_crypto_funcs;
-        G_CONFIG.feature_caps = g_wifi_feature_caps;
-
-        let cntry_code = [b'C', b'N', 0];
-        let country = wifi_country_t {
-            cc: cntry_code,
-            schan: 1,
-            nchan: 13,
-            max_tx_power: 20,
-            policy: wifi_country_policy_t_WIFI_COUNTRY_POLICY_MANUAL,
-        };
-
-        wifi_set_log_verbose();
-
-        let res = esp_wifi_init_internal(&G_CONFIG);
-        if res != 0 {
-            return res;
-        }
-
-        wifi_set_log_verbose();
-
-        let res = esp_supplicant_init();
-        if res != 0 {
-            return res;
-        }
+        read_mac(mac as *mut u8, 1);
+    }
+}
 
-        let res = esp_wifi_set_mode(wifi_mode_t_WIFI_MODE_STA);
-        if res != 0 {
-            return res;
-        }
+/// Which interfaces of the driver are enabled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WifiMode {
+    /// Station only (default)
+    Sta,
+    /// Access point only
+    Ap,
+    /// Station and access point at the same time.
+    /// Both interfaces share the radio so the AP always uses the channel of the AP the station is connected to.
+    ApSta,
+}
 
-        let mut cfg = wifi_config_t {
-            sta: wifi_sta_config_t {
-                ssid: [0; 32],
-                password: [0; 64],
-                scan_method: wifi_scan_method_t_WIFI_FAST_SCAN,
-                bssid_set: false,
-                bssid: [0; 6],
-                channel: 1,
-                listen_interval: 3,
-                sort_method: wifi_sort_method_t_WIFI_CONNECT_AP_BY_SIGNAL,
-                threshold: wifi_scan_threshold_t {
-                    rssi: 20,
-                    authmode: wifi_auth_mode_t_WIFI_AUTH_OPEN,
-                },
-                pmf_cfg: wifi_pmf_config_t {
-                    capable: false,
-                    required: false,
-                },
-                _bitfield_align_1: [0u32; 0],
-                _bitfield_1: __BindgenBitfieldUnit::new([0u8; 4usize]),
-            },
-        };
-        let res = esp_wifi_set_config(wifi_interface_t_WIFI_IF_STA, &mut cfg);
-        if res != 0 {
-            return res;
+impl WifiMode {
+    fn to_raw(self) -> wifi_mode_t {
+        match self {
+            WifiMode::Sta => wifi_mode_t_WIFI_MODE_STA,
+            WifiMode::Ap => wifi_mode_t_WIFI_MODE_AP,
+            WifiMode::ApSta => wifi_mode_t_WIFI_MODE_APSTA,
         }
+    }
 
-        let res = esp_wifi_set_tx_done_cb(Some(esp_wifi_tx_done_cb));
-        if res != 0 {
-            return res;
-        }
+    fn has_sta(self) -> bool {
+        self != WifiMode::Ap
+    }
 
-        let res = esp_wifi_set_country(&country);
-        if res != 0 {
-            return res;
-        }
+    fn has_ap(self) -> bool {
+        self != WifiMode::Sta
+    }
+}
 
-        let res = esp_wifi_internal_reg_rxcb(esp_interface_t_ESP_IF_WIFI_STA, Some(recv_cb));
-        if res != 0 {
-            return res;
+/// Set the mode used by `wifi_init`. Needs to be called before `wifi_init`.
+pub fn wifi_set_mode(mode: WifiMode) -> i32 {
+    critical_section::with(|_| unsafe {
+        if WIFI_INITIALIZED {
+            ESP_ERR_INVALID_STATE as i32
+        } else {
+            WIFI_MODE = mode;
+            0
         }
+    })
+}
 
-        // unclear why? it should get initialized correctly by the driver
-        // but apparently it gets the wrong - maybe we run init on the wrong task
-        // but seems to not work otherwise
-        debug!("s_wifi_task_hdl = {}", s_wifi_task_hdl);
-        debug!("&s_wifi_task_hdl = {:p}", &s_wifi_task_hdl);
-        s_wifi_task_hdl = 0;
-
-        0
-    }
+/// Where the driver keeps its own copy of the configuration
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WifiStorage {
+    /// Only in RAM, the configuration set from Rust is the single source of truth
+    Ram,
+    /// Persisted in NVS by the driver
+    Flash,
 }
 
-unsafe extern "C" fn recv_cb(
-    buffer: *mut crate::binary::c_types::c_void,
-    len: u16,
-    eb: *mut crate::binary::c_types::c_void,
-) -> esp_err_t {
-    critical_section::with(|_| {
-        if let Some(ref mut data_queue_rx) = DATA_QUEUE_RX {
-            if !data_queue_rx.is_full() {
-                let mut buf = [0u8; 2500];
-                let src = core::slice::from_raw_parts_mut(buffer as *mut u8, len as usize);
-                buf[..(len as usize)].copy_from_slice(src);
-                data_queue_rx.enqueue(DataFrame {
-                    len: len as usize,
-                    data: buf,
-                });
+/// Select where the driver keeps its configuration. Needs to be called after `wifi_init`.
+///
+/// There is no NVS implementation yet so only `WifiStorage::Ram` is supported,
+/// `WifiStorage::Flash` returns `ESP_ERR_NOT_SUPPORTED`.
+pub fn wifi_set_storage(storage: WifiStorage) -> i32 {
+    if storage == WifiStorage::Flash {
+        return ESP_ERR_NOT_SUPPORTED as i32;
+    }
 
-                esp_wifi_internal_free_rx_buffer(eb);
-                verbose!("esp_wifi_internal_free_rx_buffer done");
-            }
-        }
-    });
+    let res = wifi_require_state(WifiState::Initialized);
+    if res != 0 {
+        return res;
+    }
 
-    0
+    unsafe { esp_wifi_set_storage(wifi_storage_t_WIFI_STORAGE_RAM) }
 }
 
-unsafe extern "C" fn esp_wifi_tx_done_cb(
-    _ifidx: u8,
-    _data: *mut u8,
-    _data_len: *mut u16,
-    _tx_status: bool,
-) {
-    debug!("esp_wifi_tx_done_cb");
+/// The interface a `WifiDevice` sends and receives on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WifiInterface {
+    Sta,
+    Ap,
 }
 
-pub fn wifi_start() -> i32 {
-    unsafe {
-        let res = esp_wifi_start();
-        if res != 0 {
-            return res;
+impl WifiInterface {
+    fn to_raw(self) -> wifi_interface_t {
+        match self {
+            WifiInterface::Sta => wifi_interface_t_WIFI_IF_STA,
+            WifiInterface::Ap => wifi_interface_t_WIFI_IF_AP,
         }
+    }
 
-        let res = esp_wifi_set_ps(wifi_ps_type_t_WIFI_PS_NONE);
-        if res != 0 {
-            return res;
+    fn index(self) -> usize {
+        match self {
+            WifiInterface::Sta => 0,
+            WifiInterface::Ap => 1,
         }
     }
-
-    0
 }
 
-pub fn wifi_start_scan() -> i32 {
-    let scan_time = wifi_scan_time_t {
-        active: wifi_active_scan_time_t { min: 0, max: 0 },
-        passive: 0,
-    };
-
-    let scan_config = wifi_scan_config_t {
-        ssid: core::ptr::null_mut(),
-        bssid: core::ptr::null_mut(),
-        channel: 0,
-        show_hidden: false,
-        scan_type: wifi_scan_type_t_WIFI_SCAN_TYPE_ACTIVE,
-        scan_time: scan_time,
-    };
-
-    unsafe { esp_wifi_scan_start(&scan_config, true) }
+/// Frame aggregation of the driver, see `WifiInitConfig::aggregation`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AggregationConfig {
+    /// Accept A-MPDUs
+    pub ampdu_rx: bool,
+    /// Send A-MPDUs
+    pub ampdu_tx: bool,
+    /// Send A-MSDUs, needs 16 cache TX buffers at least (`WifiInitConfig::cache_tx_buffers`)
+    pub amsdu_tx: bool,
+    /// Block ack window for received A-MPDUs (2 - 32 frames), at most twice the static RX
+    /// buffers
+    pub rx_ba_win: u8,
 }
 
-pub fn wifi_connect(ssid: &str, password: &str) -> i32 {
-    unsafe {
-        let mut cfg = wifi_config_t {
-            sta: wifi_sta_config_t {
-                ssid: [0; 32],
-                password: [0; 64],
-                scan_method: wifi_scan_method_t_WIFI_FAST_SCAN,
-                bssid_set: false,
-                bssid: [0; 6],
-                channel: 10,
-                listen_interval: 3,
-                sort_method: wifi_sort_method_t_WIFI_CONNECT_AP_BY_SIGNAL,
-                threshold: wifi_scan_threshold_t {
-                    rssi: -99,
-                    authmode: wifi_auth_mode_t_WIFI_AUTH_OPEN,
-                },
-                pmf_cfg: wifi_pmf_config_t {
-                    capable: true,
-                    required: false,
-                },
-                _bitfield_align_1: [0u32; 0],
-                _bitfield_1: __BindgenBitfieldUnit::new([0u8; 4usize]),
-            },
-        };
-
-        cfg.sta.ssid[0..(ssid.len())].copy_from_slice(ssid.as_bytes());
-        cfg.sta.password[0..(password.len())].copy_from_slice(password.as_bytes());
-
-        let res = esp_wifi_set_config(wifi_interface_t_WIFI_IF_STA, &mut cfg);
-        if res != 0 {
-            return res;
+impl AggregationConfig {
+    /// A-MPDU in both directions with the settings esp-idf uses
+    pub fn throughput() -> AggregationConfig {
+        AggregationConfig {
+            ampdu_rx: true,
+            ampdu_tx: true,
+            amsdu_tx: false,
+            rx_ba_win: 6,
         }
-
-        esp_wifi_connect()
     }
 }
 
-pub fn wifi_stop() -> i32 {
-    unsafe { esp_wifi_stop() }
+/// Buffers and features of the driver, passed to `wifi_init_with_config`.
+///
+/// More buffers help throughput but they are allocated from the driver's heap which likely
+/// needs to be enlarged then (`ESP_WIFI_HEAP_SIZE`). The WiFi task always runs on the single
+/// core of the ESP32-C3.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WifiInitConfig {
+    static_rx_buffers: u8,
+    dynamic_rx_buffers: u16,
+    static_tx_buffers: u8,
+    dynamic_tx_buffers: u8,
+    cache_tx_buffers: u8,
+    beacon_max_len: u16,
+    mgmt_sbuf_num: u8,
+    aggregation: Option<AggregationConfig>,
 }
 
-pub fn init_clocks() {
-    // CPU as 160Mhz
-    unsafe {
-        (*hal::pac::SYSTEM::PTR)
-            .cpu_per_conf
-            .modify(|_, w| w.cpuperiod_sel().bits(1).pll_freq_sel().set_bit());
-        (*hal::pac::SYSTEM::PTR)
-            .sysclk_conf
-            .modify(|_, w| w.soc_clk_sel().bits(1));
+impl WifiInitConfig {
+    /// The configuration `wifi_init` uses
+    pub fn new() -> WifiInitConfig {
+        WifiInitConfig {
+            static_rx_buffers: 10,
+            dynamic_rx_buffers: 32,
+            static_tx_buffers: 0,
+            dynamic_tx_buffers: 32,
+            cache_tx_buffers: 0,
+            beacon_max_len: 752,
+            mgmt_sbuf_num: 32,
+            aggregation: None,
+        }
     }
 
-    unsafe {
-        // PERIP_CLK_EN0
-        ((0x600c0000 + 0x10) as *mut u32).write_volatile(0xffffffff);
-        // PERIP_CLK_EN1
-        ((0x600c0000 + 0x14) as *mut u32).write_volatile(0xffffffff);
+    /// RX buffers allocated up front (2 - 25, about 1.6 KB each), 10 by default
+    pub fn static_rx_buffers(mut self, count: u8) -> WifiInitConfig {
+        self.static_rx_buffers = count;
+        self
     }
 
-    // APB_CTRL_WIFI_CLK_EN_REG
-    unsafe {
-        ((0x60026000 + 0x14) as *mut u32).write_volatile(0xffffffff);
+    /// RX buffers allocated when needed (0 - 1024, 0 means unlimited), 32 by default
+    pub fn dynamic_rx_buffers(mut self, count: u16) -> WifiInitConfig {
+        self.dynamic_rx_buffers = count;
+        self
     }
-}
 
-pub struct WifiDevice {}
-
-impl WifiDevice {
-    pub fn new() -> WifiDevice {
-        WifiDevice {}
+    /// Use TX buffers allocated up front (6 - 64) instead of dynamic ones, 0 (default) for
+    /// dynamic TX buffers
+    pub fn static_tx_buffers(mut self, count: u8) -> WifiInitConfig {
+        self.static_tx_buffers = count;
+        self
     }
-}
-
-// see https://docs.rs/smoltcp/0.7.1/smoltcp/phy/index.html
-impl<'a> Device<'a> for WifiDevice {
-    type RxToken = WifiRxToken;
-
-    type TxToken = WifiTxToken;
 
-    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
-        let available = unsafe {
-            if let Some(ref data_queue_rx) = DATA_QUEUE_RX {
-                !data_queue_rx.is_empty()
-            } else {
-                false
-            }
-        };
+    /// Dynamic TX buffers (1 - 128), 32 by default
+    pub fn dynamic_tx_buffers(mut self, count: u8) -> WifiInitConfig {
+        self.dynamic_tx_buffers = count;
+        self
+    }
 
-        if available {
-            Some((WifiRxToken::default(), WifiTxToken::default()))
-        } else {
-            None
-        }
+    /// TX buffers used for A-MSDUs (0 or 16 - 128), 0 by default
+    pub fn cache_tx_buffers(mut self, count: u8) -> WifiInitConfig {
+        self.cache_tx_buffers = count;
+        self
     }
 
-    fn transmit(&'a mut self) -> Option<Self::TxToken> {
-        Some(WifiTxToken::default())
+    /// Max length of the beacons sent in AP mode (752 - 1024 bytes), 752 by default
+    pub fn beacon_max_len(mut self, len: u16) -> WifiInitConfig {
+        self.beacon_max_len = len;
+        self
     }
 
-    fn capabilities(&self) -> smoltcp::phy::DeviceCapabilities {
-        let mut caps = DeviceCapabilities::default();
-        caps.max_transmission_unit = 1514;
-        caps.max_burst_size = Some(1);
-        caps
+    /// Buffers for management frames (6 - 32), 32 by default
+    pub fn mgmt_sbuf_num(mut self, count: u8) -> WifiInitConfig {
+        self.mgmt_sbuf_num = count;
+        self
     }
-}
 
-#[derive(Debug, Default)]
-pub struct WifiRxToken {}
+    /// Enable aggregating frames (disabled by default). Increases throughput a lot but the
+    /// driver needs more memory for buffering the aggregates.
+    pub fn aggregation(mut self, aggregation: AggregationConfig) -> WifiInitConfig {
+        self.aggregation = Some(aggregation);
+        self
+    }
 
-impl RxToken for WifiRxToken {
-    fn consume<R, F>(self, _timestamp: smoltcp::time::Instant, f: F) -> smoltcp::Result<R>
-    where
-        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
-    {
-        let mut result: Option<smoltcp::Result<R>> = None;
-        unsafe {
-            if let Some(ref mut data_queue_rx) = DATA_QUEUE_RX {
-                if !data_queue_rx.is_empty() {
-                    let element = data_queue_rx.dequeue();
-
-                    result = match element {
-                        Some(mut data) => {
-                            let buffer =
-                                core::slice::from_raw_parts(&data.data as *const u8, data.len);
-                            verbose!("received {:?}", _timestamp);
-                            dump_packet_info(&buffer);
-                            Some(f(&mut data.data[..]))
-                        }
-                        None => Some(Err(smoltcp::Error::Exhausted)),
-                    };
-                }
+    fn is_valid(&self) -> bool {
+        let aggregation_valid = match self.aggregation {
+            Some(aggregation) => {
+                (2..=32).contains(&aggregation.rx_ba_win)
+                    && aggregation.rx_ba_win as u32 <= self.static_rx_buffers as u32 * 2
+                    && (!aggregation.amsdu_tx || self.cache_tx_buffers >= 16)
             }
-        }
+            None => true,
+        };
 
-        if let Some(res) = result {
-            res
-        } else {
-            Err(smoltcp::Error::Exhausted)
-        }
+        aggregation_valid
+            && (2..=25).contains(&self.static_rx_buffers)
+            && self.dynamic_rx_buffers <= 1024
+            && (self.static_tx_buffers == 0 || (6..=64).contains(&self.static_tx_buffers))
+            && (1..=128).contains(&self.dynamic_tx_buffers)
+            && (self.cache_tx_buffers == 0 || (16..=128).contains(&self.cache_tx_buffers))
+            && (752..=1024).contains(&self.beacon_max_len)
+            && (6..=32).contains(&self.mgmt_sbuf_num)
     }
 }
 
-#[derive(Debug, Default)]
-pub struct WifiTxToken {}
+impl Default for WifiInitConfig {
+    fn default() -> WifiInitConfig {
+        WifiInitConfig::new()
+    }
+}
 
-impl TxToken for WifiTxToken {
-    fn consume<R, F>(
-        self,
-        _timestamp: smoltcp::time::Instant,
-        len: usize,
-        f: F,
-    ) -> smoltcp::Result<R>
-    where
-        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
-    {
-        let res = unsafe { f(&mut TX_BUFFER[..len]) };
+/// The initialized driver. `wifi_init` hands it out in exchange for the RNG the driver needs and
+/// `wifi_deinit` gives the RNG back, so there is at most one and the driver can't be
+/// deinitialized without it.
+#[derive(Debug)]
+pub struct WifiDriver {
+    _private: (),
+}
 
-        match res {
-            Ok(_) => {
-                critical_section::with(|_| unsafe {
-                    if !TX_QUEUED {
-                        TX_QUEUED_DATA_LEN = len as u16;
-                        TX_QUEUED = true;
-                    } else {
-                        // Err(smoltcp::Error::Exhausted)
-                    }
-                });
-            }
-            Err(_) => (),
-        };
+/// Initialize the driver with the given buffers and features instead of the defaults
+pub fn wifi_init_with_config(
+    rng: hal::pac::RNG,
+    config: &WifiInitConfig,
+) -> Result<WifiDriver, EspError> {
+    init_rng(rng);
 
-        res
+    if !config.is_valid() {
+        return Err(EspError::InvalidArg);
     }
+
+    init(config)
 }
 
-pub fn send_data_if_needed() {
-    let to_send = critical_section::with(|_| unsafe {
-        if TX_QUEUED {
-            debug!("sending... {} bytes", TX_QUEUED_DATA_LEN);
-            dump_packet_info(&TX_BUFFER);
-            TX_QUEUED = false;
-            Some((TX_BUFFER, TX_QUEUED_DATA_LEN))
-        } else {
-            None
+/// Initialize the driver. The RNG is used by the driver (and `random`, `shared_rng`) until
+/// `wifi_deinit` returns it - if initializing fails get it back via `deinit_rng`.
+pub fn wifi_init(rng: hal::pac::RNG) -> Result<WifiDriver, EspError> {
+    init_rng(rng);
+    init(&WifiInitConfig::new())
+}
+
+fn init(config: &WifiInitConfig) -> Result<WifiDriver, EspError> {
+    let already_initialized = critical_section::with(|_| unsafe {
+        let initialized = WIFI_INITIALIZED;
+        if !initialized {
+            WIFI_INITIALIZED = true;
+            apply_init_config(config);
         }
+        initialized
     });
+    if already_initialized {
+        return Err(EspError::InvalidState);
+    }
 
-    if let Some((data, len)) = to_send {
-        unsafe {
-            let _res = esp_wifi_internal_tx(
-                wifi_interface_t_WIFI_IF_STA,
-                &data as *const _ as *mut crate::binary::c_types::c_void,
-                len,
-            );
-            debug!("esp_wifi_internal_tx {}", _res);
-        }
+    // the flag stays set while initializing so a concurrent call fails, it's cleared again if
+    // anything fails
+    let res = unsafe { init_driver() };
+    if res.is_err() {
+        critical_section::with(|_| unsafe {
+            apply_init_config(&WifiInitConfig::new());
+            WIFI_INITIALIZED = false;
+        });
     }
+    res.map(|_| WifiDriver { _private: () })
 }
 
-fn dump_packet_info(buffer: &[u8]) {
-    if !DUMP_PACKETS {
-        return;
+/// `G_CONFIG` holds the configuration of the running driver, the defaults otherwise.
+/// Needs to be called in a critical section.
+unsafe fn apply_init_config(config: &WifiInitConfig) {
+    let aggregation = config.aggregation.unwrap_or(AggregationConfig {
+        ampdu_rx: false,
+        ampdu_tx: false,
+        amsdu_tx: false,
+        rx_ba_win: 6,
+    });
+
+    G_CONFIG.static_rx_buf_num = config.static_rx_buffers as i32;
+    G_CONFIG.dynamic_rx_buf_num = config.dynamic_rx_buffers as i32;
+    // 0 = static, 1 = dynamic TX buffers
+    G_CONFIG.tx_buf_type = if config.static_tx_buffers > 0 { 0 } else { 1 };
+    G_CONFIG.static_tx_buf_num = config.static_tx_buffers as i32;
+    G_CONFIG.dynamic_tx_buf_num = config.dynamic_tx_buffers as i32;
+    G_CONFIG.cache_tx_buf_num = config.cache_tx_buffers as i32;
+    G_CONFIG.beacon_max_len = config.beacon_max_len as i32;
+    G_CONFIG.mgmt_sbuf_num = config.mgmt_sbuf_num as i32;
+    G_CONFIG.ampdu_rx_enable = aggregation.ampdu_rx as i32;
+    G_CONFIG.ampdu_tx_enable = aggregation.ampdu_tx as i32;
+    G_CONFIG.amsdu_tx_enable = aggregation.amsdu_tx as i32;
+    G_CONFIG.rx_ba_win = aggregation.rx_ba_win as i32;
+}
+
+unsafe fn init_driver() -> Result<(), EspError> {
+    G_CONFIG.wpa_crypto_funcs = g_wifi_default_wpa_crypto_funcs;
+    G_CONFIG.feature_caps = g_wifi_feature_caps;
+
+    wifi_set_log_verbose();
+
+    let res = esp_wifi_init_internal(&G_CONFIG);
+    if res != 0 {
+        error_log::record(ErrorSource::Init, res);
     }
+    EspError::check(res)?;
 
-    let ef = smoltcp::wire::EthernetFrame::new_unchecked(buffer);
-    println!(
-        "src={:x?} dst={:x?} type={:x?}",
-        ef.src_addr(),
-        ef.dst_addr(),
-        ef.ethertype()
-    );
-    match ef.ethertype() {
-        smoltcp::wire::EthernetProtocol::Ipv4 => {
-            let ip = smoltcp::wire::Ipv4Packet::new_unchecked(ef.payload());
-            println!(
-                "src={:?} dst={:?} proto={:x?}",
-                ip.src_addr(),
-                ip.dst_addr(),
-                ip.protocol()
-            );
+    wifi_set_log_verbose();
 
-            match ip.protocol() {
-                smoltcp::wire::IpProtocol::HopByHop => {}
-                smoltcp::wire::IpProtocol::Icmp => {}
-                smoltcp::wire::IpProtocol::Igmp => {}
-                smoltcp::wire::IpProtocol::Tcp => {
-                    let tp = smoltcp::wire::TcpPacket::new_unchecked(ip.payload());
-                    println!("src={:?} dst={:?}", tp.src_port(), tp.dst_port());
-                }
-                smoltcp::wire::IpProtocol::Udp => {
-                    let up = smoltcp::wire::UdpPacket::new_unchecked(ip.payload());
-                    println!("src={:?} dst={:?}", up.src_port(), up.dst_port());
-
-                    for c in up.payload() {
-                        if *c >= 32 {
-                            print!("{}", *c as char);
-                        } else {
-                            print!(".");
-                        }
+    // everything brought up so far is torn down again if a later step fails
+    let mut supplicant_initialized = false;
+    let mut res = Ok(());
+    if !EXTERNAL_SUPPLICANT {
+        res = EspError::check(esp_supplicant_init());
+        supplicant_initialized = res.is_ok();
+    }
+    if res.is_ok() {
+        res = configure_driver();
+    }
+
+    if res.is_err() {
+        deinit_driver(supplicant_initialized);
+    }
+    res
+}
+
+unsafe fn configure_driver() -> Result<(), EspError> {
+    let country = COUNTRY;
+    let mode = WIFI_MODE;
+    EspError::check(esp_wifi_set_mode(mode.to_raw()))?;
+
+    if mode.has_sta() {
+        EspError::check(init_sta_config())?;
+    }
+
+    EspError::check(esp_wifi_set_tx_done_cb(Some(esp_wifi_tx_done_cb)))?;
+
+    if TX_BY_REF {
+        EspError::check(esp_wifi_internal_reg_netstack_buf_cb(
+            Some(tx_ref_buffer_ref),
+            Some(tx_ref_buffer_free),
+        ))?;
+    }
+
+    critical_section::with(|_| EFFECTIVE_COUNTRY = Some([country.cc[0], country.cc[1]]));
+    EspError::check(esp_wifi_set_country(&country))?;
+
+    if mode.has_sta() {
+        EspError::check(esp_wifi_internal_reg_rxcb(
+            esp_interface_t_ESP_IF_WIFI_STA,
+            Some(recv_cb_sta),
+        ))?;
+    }
+
+    if mode.has_ap() {
+        EspError::check(esp_wifi_internal_reg_rxcb(
+            esp_interface_t_ESP_IF_WIFI_AP,
+            Some(recv_cb_ap),
+        ))?;
+    }
+
+    // unclear why? it should get initialized correctly by the driver
+    // but apparently it gets the wrong - maybe we run init on the wrong task
+    // but seems to not work otherwise
+    debug!("s_wifi_task_hdl = {}", s_wifi_task_hdl);
+    debug!("&s_wifi_task_hdl = {:p}", &s_wifi_task_hdl);
+    s_wifi_task_hdl = 0;
+
+    Ok(())
+}
+
+unsafe fn init_sta_config() -> i32 {
+    let mut cfg = wifi_config_t {
+        sta: wifi_sta_config_t {
+            ssid: [0; 32],
+            password: [0; 64],
+            scan_method: wifi_scan_method_t_WIFI_FAST_SCAN,
+            bssid_set: false,
+            bssid: [0; 6],
+            channel: 1,
+            listen_interval: 3,
+            sort_method: wifi_sort_method_t_WIFI_CONNECT_AP_BY_SIGNAL,
+            threshold: wifi_scan_threshold_t {
+                rssi: 20,
+                authmode: wifi_auth_mode_t_WIFI_AUTH_OPEN,
+            },
+            pmf_cfg: wifi_pmf_config_t {
+                capable: false,
+                required: false,
+            },
+            _bitfield_align_1: [0u32; 0],
+            _bitfield_1: __BindgenBitfieldUnit::new([0u8; 4usize]),
+        },
+    };
+
+    esp_wifi_set_config(wifi_interface_t_WIFI_IF_STA, &mut cfg)
+}
+
+unsafe extern "C" fn recv_cb_sta(
+    buffer: *mut crate::binary::c_types::c_void,
+    len: u16,
+    eb: *mut crate::binary::c_types::c_void,
+) -> esp_err_t {
+    recv_cb(buffer, len, eb, WifiInterface::Sta)
+}
+
+unsafe extern "C" fn recv_cb_ap(
+    buffer: *mut crate::binary::c_types::c_void,
+    len: u16,
+    eb: *mut crate::binary::c_types::c_void,
+) -> esp_err_t {
+    recv_cb(buffer, len, eb, WifiInterface::Ap)
+}
+
+unsafe fn recv_cb(
+    buffer: *mut crate::binary::c_types::c_void,
+    len: u16,
+    eb: *mut crate::binary::c_types::c_void,
+    interface: WifiInterface,
+) -> esp_err_t {
+    let frame = core::slice::from_raw_parts(buffer as *const u8, len as usize);
+
+    // the queues were already flushed by `wifi_stop`
+    if !critical_section::with(|_| WIFI_STARTED) {
+        esp_wifi_internal_free_rx_buffer(eb);
+        return 0;
+    }
+
+    if len as usize > MAX_FRAME_LEN {
+        debug!("dropping frame of {} bytes, larger than MAX_FRAME_LEN", len);
+        critical_section::with(|_| RX_DROPPED += 1);
+        esp_wifi_internal_free_rx_buffer(eb);
+        return 0;
+    }
+
+    if interface == WifiInterface::Ap && ap_isolation::filter_rx_frame(frame) {
+        esp_wifi_internal_free_rx_buffer(eb);
+        return 0;
+    }
+
+    if interface == WifiInterface::Sta
+        && critical_section::with(|_| EXTERNAL_SUPPLICANT)
+        && is_eapol_frame(buffer, len)
+    {
+        eapol_trace::note_frame(frame, eapol_trace::Direction::Rx);
+
+        // frames longer than MAX_FRAME_LEN were dropped already
+        let mut data = [0u8; MAX_FRAME_LEN];
+        data[..(len as usize)].copy_from_slice(frame);
+
+        let queued = critical_section::with(|_| match EAPOL_QUEUE_RX {
+            Some(ref mut eapol_queue_rx) if !eapol_queue_rx.is_full() => {
+                eapol_queue_rx.enqueue(EapolFrame {
+                    len: len as usize,
+                    data,
+                });
+                true
+            }
+            _ => {
+                EAPOL_DROPPED += 1;
+                false
+            }
+        });
+        if !queued {
+            debug!("EAPOL queue full, dropping frame");
+        }
+
+        esp_wifi_internal_free_rx_buffer(eb);
+        return 0;
+    }
+
+    // only reserve a buffer (or defer the frame) with interrupts disabled
+    let slot = critical_section::with(|_| {
+        if let Some(slot) = reserve_rx_buffer(interface) {
+            return Some(Some(slot));
+        }
+
+        match RX_QUEUE_FULL_POLICY {
+            RxQueueFullPolicy::Drop => (),
+            RxQueueFullPolicy::Defer => {
+                if let Some(ref mut deferred) = DATA_QUEUE_RX_DEFERRED {
+                    if !deferred.is_full() {
+                        deferred.enqueue((buffer, len, eb, interface));
+                        QUEUE_STATS.rx_deferred_high_water =
+                            usize::max(QUEUE_STATS.rx_deferred_high_water, deferred.len());
+                        return None;
                     }
                 }
-                smoltcp::wire::IpProtocol::Ipv6Route => {}
-                smoltcp::wire::IpProtocol::Ipv6Frag => {}
-                smoltcp::wire::IpProtocol::Icmpv6 => {}
-                smoltcp::wire::IpProtocol::Ipv6NoNxt => {}
-                smoltcp::wire::IpProtocol::Ipv6Opts => {}
-                smoltcp::wire::IpProtocol::Unknown(_) => {}
             }
+            RxQueueFullPolicy::Panic => panic!("RX queue full"),
+        }
+
+        RX_DROPPED += 1;
+        Some(None)
+    });
+
+    match slot {
+        Some(Some((index, generation))) => {
+            fill_rx_buffer(interface, index, generation, frame);
+            esp_wifi_internal_free_rx_buffer(eb);
+            verbose!("esp_wifi_internal_free_rx_buffer done");
+        }
+        Some(None) => esp_wifi_internal_free_rx_buffer(eb),
+        // the driver buffer is freed once the frame is promoted
+        None => (),
+    }
+
+    0
+}
+
+unsafe fn is_eapol_frame(buffer: *mut crate::binary::c_types::c_void, len: u16) -> bool {
+    if len < 14 {
+        return false;
+    }
+
+    let frame = core::slice::from_raw_parts(buffer as *const u8, 14);
+    frame[12..14] == [0x88, 0x8e]
+}
+
+/// The RX queue of an interface. Needs to be called in a critical section.
+unsafe fn rx_queue(
+    interface: WifiInterface,
+) -> &'static mut Option<SimpleQueue<usize, RX_QUEUE_SIZE>> {
+    match interface {
+        WifiInterface::Sta => &mut DATA_QUEUE_RX,
+        WifiInterface::Ap => &mut DATA_QUEUE_RX_AP,
+    }
+}
+
+/// Reserve a free RX buffer of `interface`. Returns its index and the current link generation
+/// to pass to `fill_rx_buffer`. Needs to be called in a critical section.
+unsafe fn reserve_rx_buffer(interface: WifiInterface) -> Option<(usize, u32)> {
+    let states = &mut RX_BUFFER_STATE[interface.index()];
+    let index = states
+        .iter()
+        .position(|state| *state == RxBufferState::Free)?;
+    states[index] = RxBufferState::Writing;
+    Some((index, LINK_GENERATION))
+}
+
+/// Copy `frame` into the reserved buffer and queue it. The buffer is released instead if the
+/// driver was stopped in the meantime. Must not be called in a critical section.
+unsafe fn fill_rx_buffer(interface: WifiInterface, index: usize, generation: u32, frame: &[u8]) {
+    let buffer = &mut RX_BUFFERS[interface.index()][index];
+    buffer.data[..frame.len()].copy_from_slice(frame);
+    buffer.len = frame.len();
+
+    critical_section::with(|_| {
+        let state = &mut RX_BUFFER_STATE[interface.index()][index];
+        match rx_queue(interface) {
+            Some(data_queue_rx) if generation == LINK_GENERATION => {
+                // there is an entry for each buffer
+                data_queue_rx.enqueue(index);
+                *state = RxBufferState::Queued;
+                QUEUE_STATS.note_rx(interface, data_queue_rx.len());
+            }
+            _ => *state = RxBufferState::Free,
+        }
+    });
+}
+
+/// Move a frame still held in a driver buffer into the RX queue of its interface.
+/// Frames are promoted in the order they were received.
+fn promote_deferred_rx_frame() {
+    let promoted = critical_section::with(|_| unsafe {
+        let deferred = DATA_QUEUE_RX_DEFERRED.as_mut()?;
+        let (_, _, _, interface) = *deferred.peek()?;
+        let slot = reserve_rx_buffer(interface)?;
+        deferred.dequeue().map(|element| (element, slot))
+    });
+
+    if let Some(((buffer, len, eb, interface), (index, generation))) = promoted {
+        unsafe {
+            let frame = core::slice::from_raw_parts(buffer as *const u8, len as usize);
+            fill_rx_buffer(interface, index, generation, frame);
+            esp_wifi_internal_free_rx_buffer(eb);
+        }
+    }
+}
+
+/// Don't start the supplicant of the WiFi driver. Instead EAPOL frames received on the STA interface
+/// are kept away from the network stack and can be read via `wifi_eapol_receive` and answered via
+/// `wifi_eapol_send` so an external supplicant can be used.
+///
+/// Needs to be called before `wifi_init`.
+///
+/// Once the 4-way handshake is done the negotiated keys are installed via
+/// `wifi_install_pairwise_key` and `wifi_install_group_key` followed by
+/// `wifi_external_auth_done`.
+pub fn wifi_set_external_supplicant(enabled: bool) {
+    critical_section::with(|_| unsafe {
+        EXTERNAL_SUPPLICANT = enabled;
+    });
+}
+
+/// Get the next received EAPOL frame (including the ethernet header) when using an external supplicant.
+/// Returns the length of the frame copied into `buffer`.
+///
+/// If `buffer` is shorter than the frame `EspError::InvalidSize` is returned and the frame stays
+/// queued, `wifi_eapol_peek_len` tells the size needed. A buffer of `MAX_FRAME_LEN` bytes always
+/// fits.
+pub fn wifi_eapol_receive(buffer: &mut [u8]) -> Result<Option<usize>, EspError> {
+    critical_section::with(|_| unsafe {
+        let eapol_queue_rx = match EAPOL_QUEUE_RX.as_mut() {
+            Some(eapol_queue_rx) => eapol_queue_rx,
+            None => return Ok(None),
+        };
+
+        match eapol_queue_rx.peek() {
+            Some(frame) if frame.len > buffer.len() => return Err(EspError::InvalidSize),
+            Some(_) => (),
+            None => return Ok(None),
+        }
+
+        Ok(eapol_queue_rx.dequeue().map(|frame| {
+            buffer[..frame.len].copy_from_slice(&frame.data[..frame.len]);
+            frame.len
+        }))
+    })
+}
+
+/// Length of the next received EAPOL frame
+pub fn wifi_eapol_peek_len() -> Option<usize> {
+    critical_section::with(|_| unsafe {
+        EAPOL_QUEUE_RX
+            .as_ref()
+            .and_then(|eapol_queue_rx| eapol_queue_rx.peek())
+            .map(|frame| frame.len)
+    })
+}
+
+/// Number of received EAPOL frames dropped because the external supplicant didn't take them
+/// in time via `wifi_eapol_receive`
+pub fn wifi_eapol_dropped_count() -> u32 {
+    critical_section::with(|_| unsafe { EAPOL_DROPPED })
+}
+
+/// Send an EAPOL frame (including the ethernet header) on the STA interface.
+pub fn wifi_eapol_send(frame: &[u8]) -> i32 {
+    eapol_trace::note_frame(frame, eapol_trace::Direction::Tx);
+
+    backend::with_backend(|backend| backend.transmit(WifiInterface::Sta, frame))
+}
+
+/// Cipher of a key installed by an external supplicant
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyCipher {
+    Tkip,
+    Ccmp,
+}
+
+impl KeyCipher {
+    // the supplicant's `wpa_alg`
+    fn to_raw(self) -> i32 {
+        match self {
+            KeyCipher::Tkip => 2,
+            KeyCipher::Ccmp => 3,
+        }
+    }
+}
+
+// the supplicant's `key_flag` bits
+const KEY_FLAG_RX: i32 = 1 << 2;
+const KEY_FLAG_TX: i32 = 1 << 3;
+const KEY_FLAG_GROUP: i32 = 1 << 4;
+const KEY_FLAG_PAIRWISE: i32 = 1 << 5;
+
+/// Install the pairwise key (the TK of the PTK) negotiated with the AP `bssid` by an external
+/// supplicant.
+pub fn wifi_install_pairwise_key(
+    bssid: &[u8; 6],
+    cipher: KeyCipher,
+    key: &[u8],
+) -> Result<(), EspError> {
+    let mut bssid = *bssid;
+    let mut seq = [0u8; 6];
+    let mut key_buffer = [0u8; 32];
+    if key.len() > key_buffer.len() {
+        return Err(EspError::InvalidArg);
+    }
+    key_buffer[..key.len()].copy_from_slice(key);
+
+    EspError::check(unsafe {
+        esp_wifi_set_sta_key_internal(
+            cipher.to_raw(),
+            bssid.as_mut_ptr(),
+            0,
+            1,
+            seq.as_mut_ptr(),
+            seq.len() as size_t,
+            key_buffer.as_mut_ptr(),
+            key.len() as size_t,
+            KEY_FLAG_PAIRWISE | KEY_FLAG_RX | KEY_FLAG_TX,
+        )
+    })
+}
+
+/// Install the group key (GTK) with its index and receive sequence counter as sent by the AP in
+/// message 3 of the 4-way handshake or in a group key handshake.
+pub fn wifi_install_group_key(
+    cipher: KeyCipher,
+    key_index: u8,
+    rsc: &[u8; 6],
+    key: &[u8],
+) -> Result<(), EspError> {
+    let mut broadcast = [0xffu8; 6];
+    let mut seq = *rsc;
+    let mut key_buffer = [0u8; 32];
+    if key.len() > key_buffer.len() || key_index > 3 {
+        return Err(EspError::InvalidArg);
+    }
+    key_buffer[..key.len()].copy_from_slice(key);
+
+    EspError::check(unsafe {
+        esp_wifi_set_sta_key_internal(
+            cipher.to_raw(),
+            broadcast.as_mut_ptr(),
+            key_index as i32,
+            0,
+            seq.as_mut_ptr(),
+            seq.len() as size_t,
+            key_buffer.as_mut_ptr(),
+            key.len() as size_t,
+            KEY_FLAG_GROUP | KEY_FLAG_RX,
+        )
+    })
+}
+
+/// Tell the driver the external supplicant finished the handshake after the keys were
+/// installed, the station is connected then.
+pub fn wifi_external_auth_done() {
+    unsafe { esp_wifi_auth_done_internal() };
+}
+
+/// What to do with a received frame when the RX queue is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RxQueueFullPolicy {
+    /// Drop the frame and count it (default)
+    Drop,
+    /// Keep the frame in the driver's buffer and queue it once there is room again.
+    /// This holds on to driver RX buffers - if too many are held the driver runs out of them.
+    Defer,
+    /// Panic - useful for debugging
+    Panic,
+}
+
+/// Set the behavior when the RX queue is full. Should be set before calling `wifi_init`.
+pub fn wifi_set_rx_queue_full_policy(policy: RxQueueFullPolicy) {
+    critical_section::with(|_| unsafe {
+        RX_QUEUE_FULL_POLICY = policy;
+    });
+}
+
+/// Number of received frames dropped because the RX queue was full
+pub fn wifi_rx_dropped_count() -> u32 {
+    critical_section::with(|_| unsafe { RX_DROPPED })
+}
+
+/// Number of frames dropped because the driver failed to send them, either with an error or
+/// since it stayed out of buffers while they were retried
+pub fn wifi_tx_dropped_count() -> u32 {
+    critical_section::with(|_| unsafe { TX_DROPPED })
+}
+
+/// Frame counters of the devices, e.g. to find out why the throughput is low in the field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WifiCounters {
+    /// Frames passed to smoltcp
+    pub rx_frames: u32,
+    /// Frames the driver accepted to be sent
+    pub tx_frames: u32,
+    /// Received frames dropped since the RX queue was full, see `wifi_rx_dropped_count`
+    pub rx_dropped: u32,
+    /// Frames the driver failed to send, see `wifi_tx_dropped_count`
+    pub tx_dropped: u32,
+    /// The last error the driver returned for a frame to be sent. Running out of buffers is
+    /// included even though such frames are retried.
+    pub last_tx_error: Option<EspError>,
+}
+
+/// The counters since `wifi_init` or the last `wifi_reset_counters`
+pub fn wifi_counters() -> WifiCounters {
+    critical_section::with(|_| unsafe {
+        WifiCounters {
+            rx_frames: RX_FRAMES,
+            tx_frames: TX_FRAMES,
+            rx_dropped: RX_DROPPED,
+            tx_dropped: TX_DROPPED,
+            last_tx_error: LAST_TX_ERROR,
+        }
+    })
+}
+
+pub fn wifi_reset_counters() {
+    critical_section::with(|_| unsafe {
+        RX_FRAMES = 0;
+        TX_FRAMES = 0;
+        RX_DROPPED = 0;
+        TX_DROPPED = 0;
+        LAST_TX_ERROR = None;
+    });
+}
+
+/// `res` is what the driver returned for a frame to be sent
+fn note_tx_result(res: i32) {
+    critical_section::with(|_| unsafe {
+        match EspError::check(res) {
+            Ok(()) => TX_FRAMES = TX_FRAMES.wrapping_add(1),
+            Err(error) => LAST_TX_ERROR = Some(error),
+        }
+    });
+}
+
+/// The most entries the queues between the driver and the application held at the same time.
+///
+/// If a queue never gets close to its capacity the application keeps up, if it reaches it
+/// frames / events get dropped. The buffer usage of smoltcp sockets can be watched via
+/// e.g. `TcpSocket::recv_queue` and `TcpSocket::send_queue`.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct QueueStats {
+    /// Capacity is `RX_QUEUE_CAPACITY`
+    pub sta_rx_high_water: usize,
+    /// Capacity is `RX_QUEUE_CAPACITY`
+    pub ap_rx_high_water: usize,
+    /// Capacity is `RX_DEFERRED_QUEUE_CAPACITY`, only used with `RxQueueFullPolicy::Defer`
+    pub rx_deferred_high_water: usize,
+    /// Capacity is `EVENT_QUEUE_CAPACITY`
+    pub event_high_water: usize,
+}
+
+impl QueueStats {
+    fn note_rx(&mut self, interface: WifiInterface, len: usize) {
+        let high_water = match interface {
+            WifiInterface::Sta => &mut self.sta_rx_high_water,
+            WifiInterface::Ap => &mut self.ap_rx_high_water,
+        };
+        *high_water = usize::max(*high_water, len);
+    }
+}
+
+// one slot of each queue always stays free
+pub const RX_QUEUE_CAPACITY: usize = RX_QUEUE_SIZE - 1;
+pub const RX_DEFERRED_QUEUE_CAPACITY: usize = RX_DEFERRED_QUEUE_SIZE - 1;
+
+pub fn wifi_queue_stats() -> QueueStats {
+    critical_section::with(|_| unsafe { QUEUE_STATS })
+}
+
+pub fn wifi_reset_queue_stats() {
+    critical_section::with(|_| unsafe {
+        QUEUE_STATS = QueueStats {
+            sta_rx_high_water: 0,
+            ap_rx_high_water: 0,
+            rx_deferred_high_water: 0,
+            event_high_water: 0,
+        };
+    });
+}
+
+/// Called for every frame the driver finished sending with the interface, the length of the
+/// frame and whether it was sent successfully (i.e. acknowledged by the receiver), e.g. to
+/// estimate the used airtime on duty-cycle limited bands.
+///
+/// The observer may be called from the WiFi interrupt handler. It must return quickly and must
+/// not block, take a lock held by the application or call into the driver.
+pub fn wifi_set_tx_done_observer(observer: Option<fn(WifiInterface, u16, bool)>) {
+    critical_section::with(|_| unsafe {
+        TX_DONE_OBSERVER = observer;
+    });
+}
+
+unsafe extern "C" fn esp_wifi_tx_done_cb(
+    ifidx: u8,
+    _data: *mut u8,
+    data_len: *mut u16,
+    tx_status: bool,
+) {
+    debug!("esp_wifi_tx_done_cb");
+
+    tx_credits::give_back();
+
+    if let Some(observer) = critical_section::with(|_| TX_DONE_OBSERVER) {
+        let interface = if ifidx as wifi_interface_t == wifi_interface_t_WIFI_IF_AP {
+            WifiInterface::Ap
+        } else {
+            WifiInterface::Sta
+        };
+        let len = if data_len.is_null() { 0 } else { *data_len };
+        observer(interface, len, tx_status);
+    }
+}
+
+pub fn wifi_start() -> Result<(), EspError> {
+    EspError::check(wifi_require_state(WifiState::Initialized))?;
+
+    unsafe {
+        let res = backend::with_backend(|backend| backend.start());
+        if res != 0 {
+            error_log::record(ErrorSource::Start, res);
+        }
+        EspError::check(res)?;
+
+        critical_section::with(|_| {
+            WIFI_STARTED = true;
+            LAST_DISCONNECT_REASON = None;
+        });
+
+        EspError::check(esp_wifi_set_ps(POWER_SAVE_MODE.to_raw()))?;
+    }
+
+    Ok(())
+}
+
+/// Modem sleep of the station while connected
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerSaveMode {
+    /// The radio is always on (default)
+    None,
+    /// Wake up for every DTIM beacon
+    Minimum,
+    /// Wake up every listen interval (3 beacons), saves more power but adds latency
+    Maximum,
+}
+
+impl PowerSaveMode {
+    fn to_raw(self) -> wifi_ps_type_t {
+        match self {
+            PowerSaveMode::None => wifi_ps_type_t_WIFI_PS_NONE,
+            PowerSaveMode::Minimum => wifi_ps_type_t_WIFI_PS_MIN_MODEM,
+            PowerSaveMode::Maximum => wifi_ps_type_t_WIFI_PS_MAX_MODEM,
+        }
+    }
+
+    fn from_raw(ps_type: wifi_ps_type_t) -> PowerSaveMode {
+        match ps_type {
+            wifi_ps_type_t_WIFI_PS_MIN_MODEM => PowerSaveMode::Minimum,
+            wifi_ps_type_t_WIFI_PS_MAX_MODEM => PowerSaveMode::Maximum,
+            _ => PowerSaveMode::None,
+        }
+    }
+}
+
+/// Set the power save mode. Can be called before `wifi_start`, the mode is applied when
+/// the driver is started.
+pub fn wifi_set_power_save(mode: PowerSaveMode) -> i32 {
+    let started = critical_section::with(|_| unsafe {
+        POWER_SAVE_MODE = mode;
+        WIFI_STARTED
+    });
+
+    if !started {
+        return 0;
+    }
+
+    unsafe { esp_wifi_set_ps(mode.to_raw()) }
+}
+
+pub fn wifi_get_power_save() -> Result<PowerSaveMode, i32> {
+    let mut ps_type: wifi_ps_type_t = wifi_ps_type_t_WIFI_PS_NONE;
+    let res = unsafe { esp_wifi_get_ps(&mut ps_type) };
+    if res != 0 {
+        return Err(res);
+    }
+
+    Ok(PowerSaveMode::from_raw(ps_type))
+}
+
+/// What `wifi_start_scan` does while a connection attempt is in progress.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScanDuringConnect {
+    /// Return `ESP_ERR_WIFI_STATE` (default)
+    Reject,
+    /// Wait for the connection attempt to finish and scan afterwards. Returns `ESP_ERR_TIMEOUT`
+    /// if it doesn't finish within `SCAN_DEFER_TIMEOUT_MS`.
+    Defer,
+}
+
+/// How long a deferred scan waits for a connection attempt to finish
+pub const SCAN_DEFER_TIMEOUT_MS: u64 = 15_000;
+
+pub fn wifi_set_scan_during_connect(behavior: ScanDuringConnect) {
+    critical_section::with(|_| unsafe {
+        SCAN_DURING_CONNECT = behavior;
+    });
+}
+
+fn is_connecting() -> bool {
+    critical_section::with(|_| unsafe { WIFI_CONNECTING })
+}
+
+/// Scan type and how long to stay on each channel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScanType {
+    /// Send probe requests. 0 for both uses the default of the driver (120 ms per channel).
+    Active { min_ms: u32, max_ms: u32 },
+    /// Only listen for beacons. 0 uses the default of the driver (360 ms per channel).
+    Passive { ms: u32 },
+}
+
+/// What to scan for, see `wifi_start_scan_with_config`
+#[derive(Debug, Clone, Copy)]
+pub struct ScanConfig<'a> {
+    /// Only report APs with this SSID. It's sent in the probe requests of an active scan, leave
+    /// it `None` to only send wildcard probe requests which don't reveal any known network.
+    pub ssid: Option<&'a str>,
+    /// Only report the AP with this BSSID
+    pub bssid: Option<[u8; 6]>,
+    /// Only scan this channel instead of all channels
+    pub channel: Option<u8>,
+    /// Also report APs not broadcasting their SSID
+    pub show_hidden: bool,
+    pub scan_type: ScanType,
+}
+
+impl Default for ScanConfig<'_> {
+    /// Active scan of all channels with the default dwell times - what `wifi_start_scan` does
+    fn default() -> Self {
+        ScanConfig {
+            ssid: None,
+            bssid: None,
+            channel: None,
+            show_hidden: false,
+            scan_type: ScanType::Active {
+                min_ms: 0,
+                max_ms: 0,
+            },
         }
-        smoltcp::wire::EthernetProtocol::Arp => {
-            let ap = smoltcp::wire::ArpPacket::new_unchecked(ef.payload());
-            println!(
-                "src={:x?} dst={:x?} src proto addr={:x?}",
-                ap.source_hardware_addr(),
-                ap.target_hardware_addr(),
-                ap.source_protocol_addr()
+    }
+}
+
+pub fn wifi_start_scan() -> Result<(), EspError> {
+    wifi_start_scan_with_config(&ScanConfig::default())
+}
+
+/// Vendor specific information element carried in the probe requests of active scans
+#[derive(Debug, Clone, Copy)]
+pub struct VendorIe<'a> {
+    pub oui: [u8; 3],
+    pub oui_type: u8,
+    /// At most 251 bytes
+    pub payload: &'a [u8],
+}
+
+/// Add a vendor specific IE to the probe requests sent while scanning or remove it (`None`).
+/// There are two slots, `index` is 0 or 1. Needs to be called after `wifi_init`.
+///
+/// Other IEs of the probe requests (supported rates, HT capabilities etc.) are set by the
+/// driver and can't be changed.
+pub fn wifi_set_probe_request_ie(index: u8, ie: Option<VendorIe>) -> i32 {
+    if index > 1 {
+        return ESP_ERR_INVALID_ARG as i32;
+    }
+
+    let ie = match ie {
+        Some(ie) => ie,
+        None => unsafe {
+            return esp_wifi_set_vendor_ie(
+                false,
+                wifi_vendor_ie_type_t_WIFI_VND_IE_TYPE_PROBE_REQ,
+                index as wifi_vendor_ie_id_t,
+                core::ptr::null(),
             );
+        },
+    };
+
+    if ie.payload.len() > 251 {
+        return ESP_ERR_INVALID_ARG as i32;
+    }
+
+    let mut element = [0u8; 257];
+    element[0] = 0xdd;
+    element[1] = 4 + ie.payload.len() as u8;
+    element[2..5].copy_from_slice(&ie.oui);
+    element[5] = ie.oui_type;
+    element[6..6 + ie.payload.len()].copy_from_slice(ie.payload);
+
+    unsafe {
+        esp_wifi_set_vendor_ie(
+            true,
+            wifi_vendor_ie_type_t_WIFI_VND_IE_TYPE_PROBE_REQ,
+            index as wifi_vendor_ie_id_t,
+            element.as_ptr() as *const crate::binary::c_types::c_void,
+        )
+    }
+}
+
+/// Scan for access points, blocks until the scan is done.
+/// The results can be read via `esp_wifi_scan_get_ap_records`.
+///
+/// If another task is scanning already this waits for that scan to finish first.
+pub fn wifi_start_scan_with_config(config: &ScanConfig) -> Result<(), EspError> {
+    EspError::check(wifi_require_state(WifiState::Started))?;
+
+    while !critical_section::with(|_| unsafe {
+        let acquired = !SCAN_RUNNING;
+        SCAN_RUNNING = true;
+        acquired
+    }) {}
+
+    let collecting = bss_load::collect_start();
+    let res = start_scan(config);
+    if collecting {
+        bss_load::collect_stop();
+    }
+    if res != 0 {
+        error_log::record(ErrorSource::Scan, res);
+    }
+
+    critical_section::with(|_| unsafe { SCAN_RUNNING = false });
+    EspError::check(res)
+}
+
+/// Stop a running scan, e.g. a background scan when the user asks for a rescan.
+/// The scan returns right away with the APs found so far and `wifi_scan_each` doesn't
+/// scan the remaining channels.
+pub fn wifi_abort_scan() -> i32 {
+    critical_section::with(|_| unsafe { SCAN_ABORTED = true });
+    unsafe { esp_wifi_scan_stop() }
+}
+
+fn start_scan(config: &ScanConfig) -> i32 {
+    if is_connecting() {
+        match unsafe { SCAN_DURING_CONNECT } {
+            ScanDuringConnect::Reject => return ESP_ERR_WIFI_STATE as i32,
+            ScanDuringConnect::Defer => {
+                let timeout = get_systimer_count() + SCAN_DEFER_TIMEOUT_MS * 16_000;
+                while is_connecting() {
+                    if get_systimer_count() >= timeout {
+                        return ESP_ERR_TIMEOUT as i32;
+                    }
+                }
+            }
+        }
+    }
+
+    // the driver wants a NUL terminated SSID
+    let mut ssid = [0u8; 33];
+    if let Some(filter) = config.ssid {
+        if filter.len() > 32 {
+            return ESP_ERR_INVALID_ARG as i32;
         }
-        smoltcp::wire::EthernetProtocol::Ipv6 => {}
-        smoltcp::wire::EthernetProtocol::Unknown(_) => {}
+        ssid[..filter.len()].copy_from_slice(filter.as_bytes());
     }
+    let mut bssid = config.bssid.unwrap_or_default();
+
+    let (scan_type, scan_time) = match config.scan_type {
+        ScanType::Active { min_ms, max_ms } => (
+            wifi_scan_type_t_WIFI_SCAN_TYPE_ACTIVE,
+            wifi_scan_time_t {
+                active: wifi_active_scan_time_t {
+                    min: min_ms,
+                    max: max_ms,
+                },
+                passive: 0,
+            },
+        ),
+        ScanType::Passive { ms } => (
+            wifi_scan_type_t_WIFI_SCAN_TYPE_PASSIVE,
+            wifi_scan_time_t {
+                active: wifi_active_scan_time_t { min: 0, max: 0 },
+                passive: ms,
+            },
+        ),
+    };
+
+    let scan_config = wifi_scan_config_t {
+        ssid: if config.ssid.is_some() {
+            ssid.as_mut_ptr()
+        } else {
+            core::ptr::null_mut()
+        },
+        bssid: if config.bssid.is_some() {
+            bssid.as_mut_ptr()
+        } else {
+            core::ptr::null_mut()
+        },
+        channel: config.channel.unwrap_or(0),
+        show_hidden: config.show_hidden,
+        scan_type,
+        scan_time,
+    };
+
+    unsafe { esp_wifi_scan_start(&scan_config, true) }
+}
+
+// records fetched per channel by `wifi_scan_each`
+const SCAN_CHUNK_SIZE: usize = 8;
+
+/// Scan channel by channel and call `callback` for every AP found.
+///
+/// The driver hands out the results of a scan only once, so to keep the stack usage low
+/// each channel is scanned on its own and its results are fetched in one chunk of 8 APs.
+/// Returns the number of APs dropped because more than 8 were found on a single channel.
+/// If `config` names a channel only that one is scanned.
+pub fn wifi_scan_each<F>(config: &ScanConfig, mut callback: F) -> Result<usize, i32>
+where
+    F: FnMut(&ApInfo),
+{
+    let channels = match config.channel {
+        Some(channel) => channel..=channel,
+        None => unsafe { COUNTRY.schan..=(COUNTRY.schan + COUNTRY.nchan - 1) },
+    };
+
+    critical_section::with(|_| unsafe { SCAN_ABORTED = false });
+
+    let mut dropped = 0;
+    for channel in channels {
+        if critical_section::with(|_| unsafe { SCAN_ABORTED }) {
+            debug!("scan aborted");
+            break;
+        }
+
+        wifi_start_scan_with_config(&ScanConfig {
+            channel: Some(channel),
+            ..*config
+        })
+        .map_err(|err| err.code())?;
+
+        let mut found = 0u16;
+        let res = unsafe { esp_wifi_scan_get_ap_num(&mut found) };
+        if res != 0 {
+            return Err(res);
+        }
+
+        let mut records: [wifi_ap_record_t; SCAN_CHUNK_SIZE] = unsafe { core::mem::zeroed() };
+        let mut count = records.len() as u16;
+        let res = unsafe { esp_wifi_scan_get_ap_records(&mut count, records.as_mut_ptr()) };
+        if res != 0 {
+            return Err(res);
+        }
+
+        dropped += found.saturating_sub(count) as usize;
+        for record in &records[..count as usize] {
+            callback(&ApInfo::from_record(record));
+        }
+    }
+
+    Ok(dropped)
+}
+
+/// Scan and pick the least crowded 2.4 GHz channel, e.g. to start an access point on.
+///
+/// Every AP found adds to the score of its channel and the overlapping neighbour channels,
+/// stronger APs count more. The channel with the lowest score wins, on a tie the
+/// non-overlapping channels 1, 6 and 11 are preferred. Only channels allowed by the country
+/// set via `wifi_set_country` are considered.
+///
+/// Scanning needs a started driver (`wifi_start`) in `WifiMode::Sta` or `WifiMode::ApSta`.
+pub fn wifi_select_ap_channel() -> Result<u8, i32> {
+    wifi_start_scan().map_err(|err| err.code())?;
+
+    let mut records: [wifi_ap_record_t; 20] = unsafe { core::mem::zeroed() };
+    let mut count = records.len() as u16;
+    let res = unsafe { esp_wifi_scan_get_ap_records(&mut count, records.as_mut_ptr()) };
+    if res != 0 {
+        return Err(res);
+    }
+
+    // allowed channels are `schan` to `schan + nchan - 1`
+    let (first_channel, last_channel) = unsafe {
+        let first = COUNTRY.schan.clamp(1, 13) as usize;
+        let last = (COUNTRY.schan as usize + COUNTRY.nchan as usize).saturating_sub(1);
+        (first, last.clamp(first, 13))
+    };
+
+    // index 0 is channel 1
+    let mut scores = [0u32; 13];
+    for record in &records[..count as usize] {
+        let channel = record.primary as i32;
+        // -100 dBm contributes 1, -30 dBm contributes 71
+        let weight = (record.rssi as i32 + 101).clamp(1, 100) as u32;
+
+        for neighbour in (channel - 4)..=(channel + 4) {
+            if (first_channel as i32..=last_channel as i32).contains(&neighbour) {
+                // overlapping channels count less the further away they are
+                let distance = (neighbour - channel).unsigned_abs();
+                scores[neighbour as usize - 1] += weight * (5 - distance);
+            }
+        }
+    }
+
+    let mut best = first_channel;
+    for channel in first_channel..=last_channel {
+        let score = scores[channel - 1];
+        let best_score = scores[best - 1];
+        let preferred = channel == 1 || channel == 6 || channel == 11;
+        if score < best_score || (score == best_score && preferred) {
+            best = channel;
+        }
+    }
+
+    Ok(best as u8)
+}
+
+/// How the station authenticates to WPA2/WPA3 transition mode networks, which advertise both
+/// WPA2-PSK and WPA3-SAE. Some of these APs fail SAE in subtle ways, forcing PSK helps there.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Wpa3Transition {
+    /// Use SAE if the AP offers it, otherwise PSK (default)
+    PreferSae,
+    /// Never use SAE. WPA3-only networks can't be joined.
+    ForcePsk,
+    /// Only join networks using SAE. Needs protected management frames.
+    ForceSae,
+}
+
+/// Set how to authenticate to WPA2/WPA3 transition mode networks. Takes effect on the next
+/// `wifi_connect`. Use `wifi_negotiated_auth_method` to see what was used.
+pub fn wifi_set_wpa3_transition(transition: Wpa3Transition) {
+    critical_section::with(|_| unsafe {
+        WPA3_TRANSITION = transition;
+    });
+}
+
+/// Authentication method used for the current connection of the station, e.g. `WPA3Personal`
+/// when SAE was negotiated with a transition mode network. `None` while not connected.
+pub fn wifi_negotiated_auth_method() -> Option<AuthMethod> {
+    critical_section::with(|_| unsafe { STA_AUTH_MODE })
+}
+
+/// Station configuration, see `wifi_connect_with_config`
+#[derive(Debug, Clone, Copy)]
+pub struct StaConfig<'a> {
+    pub ssid: &'a str,
+    pub password: &'a str,
+    /// Only connect to the AP with this BSSID
+    pub bssid: Option<[u8; 6]>,
+    /// Channel of the AP if known - it's scanned first
+    pub channel: Option<u8>,
+    /// Don't associate to APs with a weaker signal (dBm)
+    pub min_rssi: Option<i8>,
+    /// Don't associate to APs with a less secure auth method, e.g. `AuthMethod::WPA2Personal`
+    /// refuses open and WEP networks. Ignored with `Wpa3Transition::ForceSae`.
+    pub min_auth_method: Option<AuthMethod>,
+}
+
+impl<'a> StaConfig<'a> {
+    /// Any AP with the SSID on any channel
+    pub fn new(ssid: &'a str, password: &'a str) -> StaConfig<'a> {
+        StaConfig {
+            ssid,
+            password,
+            bssid: None,
+            channel: None,
+            min_rssi: None,
+            min_auth_method: None,
+        }
+    }
+}
+
+pub fn wifi_connect(ssid: &str, password: &str) -> Result<(), EspError> {
+    wifi_connect_with_config(&StaConfig::new(ssid, password))
+}
+
+/// Connect to an access point. Giving the BSSID and channel of a known AP (e.g. remembered
+/// from the last connection via `wifi_get_ap_info`) makes reconnecting fast since no full
+/// scan is needed.
+pub fn wifi_connect_with_config(config: &StaConfig) -> Result<(), EspError> {
+    if config.ssid.len() > 32 || config.password.len() > 64 {
+        return Err(EspError::InvalidArg);
+    }
+
+    EspError::check(wifi_require_state(WifiState::Started))?;
+
+    let min_authmode = config
+        .min_auth_method
+        .map_or(wifi_auth_mode_t_WIFI_AUTH_OPEN, AuthMethod::to_raw);
+
+    // the driver only uses SAE if PMF is enabled
+    let (min_authmode, pmf_cfg) = match critical_section::with(|_| unsafe { WPA3_TRANSITION }) {
+        Wpa3Transition::PreferSae => (
+            min_authmode,
+            wifi_pmf_config_t {
+                capable: true,
+                required: false,
+            },
+        ),
+        Wpa3Transition::ForcePsk => (
+            min_authmode,
+            wifi_pmf_config_t {
+                capable: false,
+                required: false,
+            },
+        ),
+        Wpa3Transition::ForceSae => (
+            wifi_auth_mode_t_WIFI_AUTH_WPA3_PSK,
+            wifi_pmf_config_t {
+                capable: true,
+                required: true,
+            },
+        ),
+    };
+
+    unsafe {
+        let mut cfg = wifi_config_t {
+            sta: wifi_sta_config_t {
+                ssid: [0; 32],
+                password: [0; 64],
+                scan_method: wifi_scan_method_t_WIFI_FAST_SCAN,
+                bssid_set: config.bssid.is_some(),
+                bssid: config.bssid.unwrap_or_default(),
+                // 0 = unknown
+                channel: config.channel.unwrap_or(0),
+                listen_interval: 3,
+                sort_method: wifi_sort_method_t_WIFI_CONNECT_AP_BY_SIGNAL,
+                threshold: wifi_scan_threshold_t {
+                    rssi: config.min_rssi.unwrap_or(-99),
+                    authmode: min_authmode,
+                },
+                pmf_cfg,
+                _bitfield_align_1: [0u32; 0],
+                _bitfield_1: __BindgenBitfieldUnit::new([0u8; 4usize]),
+            },
+        };
+
+        cfg.sta.ssid[0..(config.ssid.len())].copy_from_slice(config.ssid.as_bytes());
+        cfg.sta.password[0..(config.password.len())].copy_from_slice(config.password.as_bytes());
+
+        EspError::check(esp_wifi_set_config(wifi_interface_t_WIFI_IF_STA, &mut cfg))?;
+
+        reconnect::on_connect_requested();
+        critical_section::with(|_| {
+            LAST_DISCONNECT_REASON = None;
+            WIFI_CONNECTING = true;
+        });
+        let res = esp_wifi_connect();
+        if res != 0 {
+            critical_section::with(|_| WIFI_CONNECTING = false);
+            error_log::record(ErrorSource::Connect, res);
+        }
+        EspError::check(res)
+    }
+}
+
+/// Why the station got disconnected or couldn't connect
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisconnectReason {
+    /// The AP wasn't found while scanning
+    NoApFound,
+    /// The AP rejected the authentication
+    AuthFailed,
+    /// The 4-way handshake didn't complete, usually because of a wrong password
+    HandshakeTimeout,
+    AssocFailed,
+    /// No beacons were received from the AP for a while
+    BeaconTimeout,
+    /// The AP or the station ended the association
+    AssocLeave,
+    /// Any other 802.11 reason code or driver code
+    Other(u8),
+}
+
+impl DisconnectReason {
+    fn from_raw(reason: u8) -> DisconnectReason {
+        match reason as wifi_err_reason_t {
+            wifi_err_reason_t_WIFI_REASON_NO_AP_FOUND => DisconnectReason::NoApFound,
+            wifi_err_reason_t_WIFI_REASON_AUTH_FAIL | wifi_err_reason_t_WIFI_REASON_AUTH_EXPIRE => {
+                DisconnectReason::AuthFailed
+            }
+            wifi_err_reason_t_WIFI_REASON_4WAY_HANDSHAKE_TIMEOUT
+            | wifi_err_reason_t_WIFI_REASON_HANDSHAKE_TIMEOUT => DisconnectReason::HandshakeTimeout,
+            wifi_err_reason_t_WIFI_REASON_ASSOC_FAIL => DisconnectReason::AssocFailed,
+            wifi_err_reason_t_WIFI_REASON_BEACON_TIMEOUT => DisconnectReason::BeaconTimeout,
+            wifi_err_reason_t_WIFI_REASON_ASSOC_LEAVE => DisconnectReason::AssocLeave,
+            _ => DisconnectReason::Other(reason),
+        }
+    }
+}
+
+/// Error codes (`esp_err_t`) returned by the driver
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EspError {
+    /// Generic failure (`ESP_FAIL`)
+    Fail,
+    /// Out of memory, see `HEAP_SIZE`
+    NoMem,
+    InvalidArg,
+    InvalidState,
+    InvalidSize,
+    NotFound,
+    NotSupported,
+    Timeout,
+    /// The driver wasn't initialized by `wifi_init`
+    WifiNotInit,
+    /// The driver wasn't started by `wifi_start`
+    WifiNotStarted,
+    /// The driver wasn't stopped by `wifi_stop`
+    WifiNotStopped,
+    /// Invalid interface
+    WifiIf,
+    /// Invalid mode
+    WifiMode,
+    /// The driver's internal state doesn't allow it
+    WifiState,
+    /// The driver's internal control block of the station or AP is broken
+    WifiConn,
+    WifiNvs,
+    /// Invalid MAC address
+    WifiMac,
+    /// Invalid SSID
+    WifiSsid,
+    /// Invalid password
+    WifiPassword,
+    WifiTimeout,
+    /// Waking up from modem sleep failed
+    WifiWakeFail,
+    /// The call would block
+    WifiWouldBlock,
+    /// The station isn't connected
+    WifiNotConnect,
+    /// Any other code
+    Other(i32),
+}
+
+impl EspError {
+    /// `Ok` for `ESP_OK` (0)
+    pub fn check(code: esp_err_t) -> Result<(), EspError> {
+        if code == 0 {
+            Ok(())
+        } else {
+            Err(EspError::from_raw(code))
+        }
+    }
+
+    pub fn from_raw(code: esp_err_t) -> EspError {
+        if code == ESP_FAIL {
+            return EspError::Fail;
+        }
+
+        match code as u32 {
+            ESP_ERR_NO_MEM => EspError::NoMem,
+            ESP_ERR_INVALID_ARG => EspError::InvalidArg,
+            ESP_ERR_INVALID_STATE => EspError::InvalidState,
+            ESP_ERR_INVALID_SIZE => EspError::InvalidSize,
+            ESP_ERR_NOT_FOUND => EspError::NotFound,
+            ESP_ERR_NOT_SUPPORTED => EspError::NotSupported,
+            ESP_ERR_TIMEOUT => EspError::Timeout,
+            ESP_ERR_WIFI_NOT_INIT => EspError::WifiNotInit,
+            ESP_ERR_WIFI_NOT_STARTED => EspError::WifiNotStarted,
+            ESP_ERR_WIFI_NOT_STOPPED => EspError::WifiNotStopped,
+            ESP_ERR_WIFI_IF => EspError::WifiIf,
+            ESP_ERR_WIFI_MODE => EspError::WifiMode,
+            ESP_ERR_WIFI_STATE => EspError::WifiState,
+            ESP_ERR_WIFI_CONN => EspError::WifiConn,
+            ESP_ERR_WIFI_NVS => EspError::WifiNvs,
+            ESP_ERR_WIFI_MAC => EspError::WifiMac,
+            ESP_ERR_WIFI_SSID => EspError::WifiSsid,
+            ESP_ERR_WIFI_PASSWORD => EspError::WifiPassword,
+            ESP_ERR_WIFI_TIMEOUT => EspError::WifiTimeout,
+            ESP_ERR_WIFI_WAKE_FAIL => EspError::WifiWakeFail,
+            ESP_ERR_WIFI_WOULD_BLOCK => EspError::WifiWouldBlock,
+            ESP_ERR_WIFI_NOT_CONNECT => EspError::WifiNotConnect,
+            _ => EspError::Other(code),
+        }
+    }
+
+    /// The raw `esp_err_t`, e.g. for logging
+    pub fn code(&self) -> esp_err_t {
+        match self {
+            EspError::Fail => ESP_FAIL,
+            EspError::NoMem => ESP_ERR_NO_MEM as i32,
+            EspError::InvalidArg => ESP_ERR_INVALID_ARG as i32,
+            EspError::InvalidState => ESP_ERR_INVALID_STATE as i32,
+            EspError::InvalidSize => ESP_ERR_INVALID_SIZE as i32,
+            EspError::NotFound => ESP_ERR_NOT_FOUND as i32,
+            EspError::NotSupported => ESP_ERR_NOT_SUPPORTED as i32,
+            EspError::Timeout => ESP_ERR_TIMEOUT as i32,
+            EspError::WifiNotInit => ESP_ERR_WIFI_NOT_INIT as i32,
+            EspError::WifiNotStarted => ESP_ERR_WIFI_NOT_STARTED as i32,
+            EspError::WifiNotStopped => ESP_ERR_WIFI_NOT_STOPPED as i32,
+            EspError::WifiIf => ESP_ERR_WIFI_IF as i32,
+            EspError::WifiMode => ESP_ERR_WIFI_MODE as i32,
+            EspError::WifiState => ESP_ERR_WIFI_STATE as i32,
+            EspError::WifiConn => ESP_ERR_WIFI_CONN as i32,
+            EspError::WifiNvs => ESP_ERR_WIFI_NVS as i32,
+            EspError::WifiMac => ESP_ERR_WIFI_MAC as i32,
+            EspError::WifiSsid => ESP_ERR_WIFI_SSID as i32,
+            EspError::WifiPassword => ESP_ERR_WIFI_PASSWORD as i32,
+            EspError::WifiTimeout => ESP_ERR_WIFI_TIMEOUT as i32,
+            EspError::WifiWakeFail => ESP_ERR_WIFI_WAKE_FAIL as i32,
+            EspError::WifiWouldBlock => ESP_ERR_WIFI_WOULD_BLOCK as i32,
+            EspError::WifiNotConnect => ESP_ERR_WIFI_NOT_CONNECT as i32,
+            EspError::Other(code) => *code,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WifiError {
+    /// Error returned by the driver
+    Driver(EspError),
+    /// The station got disconnected instead of connecting
+    Disconnected(DisconnectReason),
+    /// The function needs the driver to be in the `expected` state at least,
+    /// e.g. `wifi_connect` was called before `wifi_start`
+    InvalidState {
+        expected: WifiState,
+        actual: WifiState,
+    },
+}
+
+impl From<i32> for WifiError {
+    /// The state errors returned by the `wifi_*` functions are turned into
+    /// `WifiError::InvalidState`, `actual` is the state at the time of the conversion.
+    fn from(code: i32) -> WifiError {
+        let expected = match code as u32 {
+            ESP_ERR_WIFI_NOT_INIT => WifiState::Initialized,
+            ESP_ERR_WIFI_NOT_STARTED => WifiState::Started,
+            ESP_ERR_WIFI_NOT_CONNECT => WifiState::Connected,
+            _ => return WifiError::Driver(EspError::from_raw(code)),
+        };
+
+        WifiError::InvalidState {
+            expected,
+            actual: wifi_state(),
+        }
+    }
+}
+
+impl From<EspError> for WifiError {
+    fn from(error: EspError) -> WifiError {
+        WifiError::from(error.code())
+    }
+}
+
+/// Lifecycle state of the driver, each state implies the ones before
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum WifiState {
+    /// `wifi_init` wasn't called yet
+    Uninitialized,
+    /// `wifi_init` was called
+    Initialized,
+    /// `wifi_start` was called
+    Started,
+    /// The station is connected to an AP
+    Connected,
+}
+
+pub fn wifi_state() -> WifiState {
+    let (initialized, started) =
+        critical_section::with(|_| unsafe { (WIFI_INITIALIZED, WIFI_STARTED) });
+    if !initialized {
+        WifiState::Uninitialized
+    } else if !started {
+        WifiState::Initialized
+    } else if is_connected() {
+        WifiState::Connected
+    } else {
+        WifiState::Started
+    }
+}
+
+/// Returns the error code for the missing state, 0 if the driver is in `expected` at least
+fn wifi_require_state(expected: WifiState) -> i32 {
+    let actual = wifi_state();
+    if actual >= expected {
+        return 0;
+    }
+
+    debug!("invalid state {:?}, {:?} needed", actual, expected);
+    match expected {
+        WifiState::Uninitialized => 0,
+        WifiState::Initialized => ESP_ERR_WIFI_NOT_INIT as i32,
+        WifiState::Started => ESP_ERR_WIFI_NOT_STARTED as i32,
+        WifiState::Connected => ESP_ERR_WIFI_NOT_CONNECT as i32,
+    }
+}
+
+/// Reason of the last disconnect of the station, e.g. to find out why the link dropped.
+/// `None` if it wasn't disconnected since `wifi_start`.
+pub fn wifi_last_disconnect_reason() -> Option<DisconnectReason> {
+    critical_section::with(|_| unsafe { LAST_DISCONNECT_REASON.map(DisconnectReason::from_raw) })
+}
+
+/// Block until the connection attempt started by `wifi_connect` finished. On failure the reason
+/// reported by the driver tells a wrong password apart from a missing AP etc.
+pub fn wifi_wait_connected() -> Result<(), WifiError> {
+    loop {
+        if is_connected() {
+            return Ok(());
+        }
+
+        if !is_connecting() {
+            let reason = critical_section::with(|_| unsafe { LAST_DISCONNECT_REASON });
+            return Err(WifiError::Disconnected(DisconnectReason::from_raw(
+                reason.unwrap_or(0),
+            )));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WifiEvent {
+    WifiReady,
+    ScanDone {
+        /// 0 on success
+        status: u32,
+        /// Number of APs found
+        count: u8,
+    },
+    StaStart,
+    StaStop,
+    StaConnected {
+        bssid: [u8; 6],
+        channel: u8,
+        auth_method: AuthMethod,
+    },
+    StaDisconnected {
+        bssid: [u8; 6],
+        /// 802.11 reason code or one of the driver's own codes (200 and up), see
+        /// `DisconnectReason`
+        reason: u8,
+    },
+    StaAuthmodeChange,
+    StaWpsErSuccess,
+    StaWpsErFailed,
+    StaWpsErTimeout,
+    StaWpsErPin,
+    StaWpsErPbcOverlap,
+    ApStart,
+    ApStop,
+    ApStaConnected {
+        mac: [u8; 6],
+        /// Association ID given to the station
+        aid: u8,
+    },
+    ApStaDisconnected {
+        mac: [u8; 6],
+        aid: u8,
+    },
+    ApProbeRequestReceived,
+    FtmReport,
+    StaBssRssiLow,
+    ActionTxStatus,
+    RocDone,
+    StaBeaconTimeout,
+    /// Not from the driver - an automatic reconnect attempt was started, see
+    /// `reconnect::reconnect_set_policy`. Carries the number of the attempt.
+    StaReconnecting(u32),
+    /// Not from the driver - the automatic reconnect gave up after the carried number of
+    /// attempts, see `ReconnectPolicy::max_attempts`.
+    StaReconnectGaveUp(u32),
+    /// Not from the driver - frames couldn't be sent for a while or can be sent again,
+    /// see `congestion::congestion_set_thresholds`.
+    Congestion(CongestionLevel),
+    /// Not from the driver - the driver changed the regulatory domain on its own, e.g. adopted
+    /// the country of the AP when using `CountryInfo::auto`. Carries the new country code.
+    /// Changes via `wifi_set_country` aren't reported.
+    CountryChanged([u8; 2]),
+    Unknown(i32),
+}
+
+impl WifiEvent {
+    /// Parses the payload in `event_data` for the events carrying one.
+    /// Without a payload (`event_data` is null) their fields are zeroed.
+    pub(crate) unsafe fn from_raw(
+        event_id: i32,
+        event_data: *const crate::binary::c_types::c_void,
+    ) -> WifiEvent {
+        match event_id as wifi_event_t {
+            wifi_event_t_WIFI_EVENT_WIFI_READY => WifiEvent::WifiReady,
+            wifi_event_t_WIFI_EVENT_SCAN_DONE => {
+                let data: wifi_event_sta_scan_done_t = read_event_data(event_data);
+                WifiEvent::ScanDone {
+                    status: data.status,
+                    count: data.number,
+                }
+            }
+            wifi_event_t_WIFI_EVENT_STA_START => WifiEvent::StaStart,
+            wifi_event_t_WIFI_EVENT_STA_STOP => WifiEvent::StaStop,
+            wifi_event_t_WIFI_EVENT_STA_CONNECTED => {
+                let data: wifi_event_sta_connected_t = read_event_data(event_data);
+                WifiEvent::StaConnected {
+                    bssid: data.bssid,
+                    channel: data.channel,
+                    auth_method: AuthMethod::from_raw(data.authmode),
+                }
+            }
+            wifi_event_t_WIFI_EVENT_STA_DISCONNECTED => {
+                let data: wifi_event_sta_disconnected_t = read_event_data(event_data);
+                WifiEvent::StaDisconnected {
+                    bssid: data.bssid,
+                    reason: data.reason,
+                }
+            }
+            wifi_event_t_WIFI_EVENT_STA_AUTHMODE_CHANGE => WifiEvent::StaAuthmodeChange,
+            wifi_event_t_WIFI_EVENT_STA_WPS_ER_SUCCESS => WifiEvent::StaWpsErSuccess,
+            wifi_event_t_WIFI_EVENT_STA_WPS_ER_FAILED => WifiEvent::StaWpsErFailed,
+            wifi_event_t_WIFI_EVENT_STA_WPS_ER_TIMEOUT => WifiEvent::StaWpsErTimeout,
+            wifi_event_t_WIFI_EVENT_STA_WPS_ER_PIN => WifiEvent::StaWpsErPin,
+            wifi_event_t_WIFI_EVENT_STA_WPS_ER_PBC_OVERLAP => WifiEvent::StaWpsErPbcOverlap,
+            wifi_event_t_WIFI_EVENT_AP_START => WifiEvent::ApStart,
+            wifi_event_t_WIFI_EVENT_AP_STOP => WifiEvent::ApStop,
+            wifi_event_t_WIFI_EVENT_AP_STACONNECTED => {
+                let data: wifi_event_ap_staconnected_t = read_event_data(event_data);
+                WifiEvent::ApStaConnected {
+                    mac: data.mac,
+                    aid: data.aid,
+                }
+            }
+            wifi_event_t_WIFI_EVENT_AP_STADISCONNECTED => {
+                let data: wifi_event_ap_stadisconnected_t = read_event_data(event_data);
+                WifiEvent::ApStaDisconnected {
+                    mac: data.mac,
+                    aid: data.aid,
+                }
+            }
+            wifi_event_t_WIFI_EVENT_AP_PROBEREQRECVED => WifiEvent::ApProbeRequestReceived,
+            wifi_event_t_WIFI_EVENT_FTM_REPORT => WifiEvent::FtmReport,
+            wifi_event_t_WIFI_EVENT_STA_BSS_RSSI_LOW => WifiEvent::StaBssRssiLow,
+            wifi_event_t_WIFI_EVENT_ACTION_TX_STATUS => WifiEvent::ActionTxStatus,
+            wifi_event_t_WIFI_EVENT_ROC_DONE => WifiEvent::RocDone,
+            wifi_event_t_WIFI_EVENT_STA_BEACON_TIMEOUT => WifiEvent::StaBeaconTimeout,
+            _ => WifiEvent::Unknown(event_id),
+        }
+    }
+
+    /// Events a state machine built on top of the driver needs to see
+    pub fn is_state_change(&self) -> bool {
+        matches!(
+            self,
+            WifiEvent::ScanDone { .. }
+                | WifiEvent::StaStart
+                | WifiEvent::StaStop
+                | WifiEvent::StaConnected { .. }
+                | WifiEvent::StaDisconnected { .. }
+                | WifiEvent::StaReconnectGaveUp(_)
+                | WifiEvent::ApStart
+                | WifiEvent::ApStop
+        )
+    }
+}
+
+// the payload structs are plain data, all zeroes is a valid value
+unsafe fn read_event_data<T: Copy>(event_data: *const crate::binary::c_types::c_void) -> T {
+    if event_data.is_null() {
+        core::mem::zeroed()
+    } else {
+        core::ptr::read_unaligned(event_data as *const T)
+    }
+}
+
+/// Queue an event received from the driver.
+///
+/// State changes are kept in their own slot per station / access point / scan and never
+/// dropped. Other events are queued, if the queue is full the oldest one is dropped to make room
+/// and counted.
+pub(crate) fn queue_event(event: WifiEvent) {
+    error_log::note_event(&event);
+    eapol_trace::note_event(&event);
+
+    #[cfg(feature = "alloc")]
+    alloc_helpers::call_event_handler(&event);
+
+    critical_section::with(|_| unsafe {
+        if let Some(slot) = state_event_slot(&event) {
+            *slot = Some(event);
+            return;
+        }
+
+        let queue = EVENT_QUEUE.get_or_insert_with(SimpleQueue::new);
+
+        if queue.is_full() {
+            EVENTS_DROPPED += 1;
+            queue.dequeue();
+        }
+
+        queue.enqueue(event);
+        QUEUE_STATS.event_high_water = usize::max(QUEUE_STATS.event_high_water, queue.len());
+    });
+}
+
+/// Needs to be called in a critical section
+unsafe fn state_event_slot(event: &WifiEvent) -> Option<&'static mut Option<WifiEvent>> {
+    match event {
+        WifiEvent::StaStart
+        | WifiEvent::StaStop
+        | WifiEvent::StaConnected { .. }
+        | WifiEvent::StaDisconnected { .. }
+        | WifiEvent::StaReconnectGaveUp(_) => Some(&mut STA_STATE_EVENT),
+        WifiEvent::ApStart | WifiEvent::ApStop => Some(&mut AP_STATE_EVENT),
+        WifiEvent::ScanDone { .. } => Some(&mut SCAN_STATE_EVENT),
+        _ => None,
+    }
+}
+
+/// Get the next event received from the driver which wasn't taken yet.
+///
+/// Pending state changes (see `WifiEvent::is_state_change`) are returned first, then the
+/// other events oldest first. State changes are never dropped - if the station, the access
+/// point or a scan changes its state again before the last change was taken, only the latest
+/// state is returned. Other events should be taken regularly, if more than
+/// `EVENT_QUEUE_CAPACITY` are pending the oldest ones get dropped.
+pub fn wifi_take_event() -> Option<WifiEvent> {
+    critical_section::with(|_| unsafe {
+        STA_STATE_EVENT
+            .take()
+            .or_else(|| AP_STATE_EVENT.take())
+            .or_else(|| SCAN_STATE_EVENT.take())
+            .or_else(|| EVENT_QUEUE.as_mut().and_then(|queue| queue.dequeue()))
+    })
+}
+
+/// Block until an event is received
+pub fn wifi_wait_event() -> WifiEvent {
+    loop {
+        if let Some(event) = wifi_take_event() {
+            return event;
+        }
+    }
+}
+
+/// Blocking iterator over the received events, see `wifi_events`
+pub struct WifiEvents;
+
+impl Iterator for WifiEvents {
+    type Item = WifiEvent;
+
+    fn next(&mut self) -> Option<WifiEvent> {
+        Some(wifi_wait_event())
+    }
+}
+
+/// Iterate over the received events, waiting for the next one. The iterator never ends.
+pub fn wifi_events() -> WifiEvents {
+    WifiEvents
+}
+
+/// Number of events dropped because the event queue was full
+pub fn wifi_events_dropped() -> u32 {
+    critical_section::with(|_| unsafe { EVENTS_DROPPED })
+}
+
+/// 802.11 protocols an interface may use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Protocols {
+    pub b: bool,
+    pub g: bool,
+    pub n: bool,
+    /// Espressif's proprietary long range mode. Only works with other Espressif devices
+    /// but reaches much longer distances at a low data rate.
+    pub lr: bool,
+}
+
+impl Protocols {
+    /// Long range only
+    pub fn lr() -> Protocols {
+        Protocols {
+            b: false,
+            g: false,
+            n: false,
+            lr: true,
+        }
+    }
+
+    /// 802.11b only, for legacy APs which misbehave with ERP (11g) or HT (11n) stations.
+    /// Limits the link to 11 Mbps, in practice about 5 Mbps of TCP throughput, and the longer
+    /// airtime of the frames costs more power.
+    pub fn b_only() -> Protocols {
+        Protocols {
+            b: true,
+            g: false,
+            n: false,
+            lr: false,
+        }
+    }
+
+    fn to_raw(self) -> u8 {
+        let mut bitmap = 0;
+        if self.b {
+            bitmap |= WIFI_PROTOCOL_11B;
+        }
+        if self.g {
+            bitmap |= WIFI_PROTOCOL_11G;
+        }
+        if self.n {
+            bitmap |= WIFI_PROTOCOL_11N;
+        }
+        if self.lr {
+            bitmap |= WIFI_PROTOCOL_LR;
+        }
+        bitmap as u8
+    }
+
+    fn from_raw(bitmap: u8) -> Protocols {
+        let bitmap = bitmap as u32;
+        Protocols {
+            b: bitmap & WIFI_PROTOCOL_11B != 0,
+            g: bitmap & WIFI_PROTOCOL_11G != 0,
+            n: bitmap & WIFI_PROTOCOL_11N != 0,
+            lr: bitmap & WIFI_PROTOCOL_LR != 0,
+        }
+    }
+}
+
+impl Default for Protocols {
+    /// 802.11b/g/n - what the driver uses by default
+    fn default() -> Protocols {
+        Protocols {
+            b: true,
+            g: true,
+            n: true,
+            lr: false,
+        }
+    }
+}
+
+/// Set the protocols used by an interface. Needs to be called after `wifi_init`.
+/// Both devices need to enable LR to talk to each other in long range mode.
+pub fn wifi_set_protocols(interface: WifiInterface, protocols: Protocols) -> i32 {
+    unsafe { esp_wifi_set_protocol(interface.to_raw(), protocols.to_raw()) }
+}
+
+pub fn wifi_get_protocols(interface: WifiInterface) -> Result<Protocols, i32> {
+    let mut bitmap = 0u8;
+    let res = unsafe { esp_wifi_get_protocol(interface.to_raw(), &mut bitmap) };
+    if res != 0 {
+        return Err(res);
+    }
+
+    Ok(Protocols::from_raw(bitmap))
+}
+
+/// 802.11b data rate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rate11b {
+    Mbps1,
+    Mbps2,
+    Mbps5_5,
+    Mbps11,
+}
+
+/// PLCP preamble of 802.11b frames. Some old devices only understand the long one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Preamble {
+    Long,
+    Short,
+}
+
+/// Send all frames of an interface with a fixed 802.11b rate and preamble instead of letting
+/// the driver adapt the rate, e.g. together with `Protocols::b_only` for legacy APs.
+/// Pass `None` to go back to automatic rate selection. Needs to be called after `wifi_start`.
+///
+/// 1 Mbps is always sent with the long preamble.
+pub fn wifi_set_11b_rate(interface: WifiInterface, rate: Option<(Rate11b, Preamble)>) -> i32 {
+    let raw = match rate {
+        None => 0,
+        Some((Rate11b::Mbps1, _)) => wifi_phy_rate_t_WIFI_PHY_RATE_1M_L,
+        Some((Rate11b::Mbps2, Preamble::Long)) => wifi_phy_rate_t_WIFI_PHY_RATE_2M_L,
+        Some((Rate11b::Mbps2, Preamble::Short)) => wifi_phy_rate_t_WIFI_PHY_RATE_2M_S,
+        Some((Rate11b::Mbps5_5, Preamble::Long)) => wifi_phy_rate_t_WIFI_PHY_RATE_5M_L,
+        Some((Rate11b::Mbps5_5, Preamble::Short)) => wifi_phy_rate_t_WIFI_PHY_RATE_5M_S,
+        Some((Rate11b::Mbps11, Preamble::Long)) => wifi_phy_rate_t_WIFI_PHY_RATE_11M_L,
+        Some((Rate11b::Mbps11, Preamble::Short)) => wifi_phy_rate_t_WIFI_PHY_RATE_11M_S,
+    };
+
+    unsafe { esp_wifi_internal_set_fix_rate(interface.to_raw(), rate.is_some(), raw) }
+}
+
+/// Channel of the access point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApChannel {
+    Fixed(u8),
+    /// Scan and use the least crowded channel, see `wifi_select_ap_channel`.
+    /// Scanning needs the station interface so this is only possible in `WifiMode::ApSta`, and
+    /// a started driver - call `wifi_configure_ap` after `wifi_start` then, the access point
+    /// moves to the selected channel.
+    Auto,
+}
+
+/// Configuration of the access point interface
+#[derive(Debug, Clone, Copy)]
+pub struct AccessPointConfig<'a> {
+    pub ssid: &'a str,
+    pub password: &'a str,
+    pub channel: ApChannel,
+    /// `AuthMethod::WEP` isn't supported by the driver in AP mode
+    pub auth_method: AuthMethod,
+    /// Max number of connected stations, up to 10
+    pub max_connections: u8,
+    pub ssid_hidden: bool,
+}
+
+/// Configure the access point. Needs `WifiMode::Ap` or `WifiMode::ApSta`.
+///
+/// When also connected as a station the AP follows the channel of the upstream AP, regardless of
+/// the configured channel.
+pub fn wifi_configure_ap(config: &AccessPointConfig) -> i32 {
+    if config.ssid.len() > 32 || config.password.len() > 64 {
+        return ESP_ERR_INVALID_ARG as i32;
+    }
+
+    let channel = match config.channel {
+        ApChannel::Fixed(channel) => channel,
+        ApChannel::Auto => match wifi_select_ap_channel() {
+            Ok(channel) => channel,
+            Err(err) => return err,
+        },
+    };
+
+    unsafe {
+        let mut cfg = wifi_config_t {
+            ap: wifi_ap_config_t {
+                ssid: [0; 32],
+                password: [0; 64],
+                ssid_len: config.ssid.len() as u8,
+                channel,
+                authmode: config.auth_method.to_raw(),
+                ssid_hidden: config.ssid_hidden as u8,
+                max_connection: config.max_connections,
+                beacon_interval: 100,
+                pairwise_cipher: wifi_cipher_type_t_WIFI_CIPHER_TYPE_CCMP,
+                ftm_responder: false,
+            },
+        };
+
+        cfg.ap.ssid[0..(config.ssid.len())].copy_from_slice(config.ssid.as_bytes());
+        cfg.ap.password[0..(config.password.len())].copy_from_slice(config.password.as_bytes());
+
+        esp_wifi_set_config(wifi_interface_t_WIFI_IF_AP, &mut cfg)
+    }
+}
+
+/// Bring the radio back after light-sleep without a full cold init. The driver needs to be
+/// initialized still - after a reset (e.g. a brownout) call `wifi_init` instead, it uses the RF
+/// calibration data retained in RTC memory to skip the full calibration.
+///
+/// The PHY is re-enabled - restoring the digital registers saved when it was disabled - and the
+/// driver started again if it was stopped. If a connection was requested via `wifi_connect` and
+/// the station lost it while sleeping a reconnect is triggered.
+///
+/// Fails with `EspError::InvalidState` if the driver isn't initialized.
+pub fn wifi_resume_after_sleep() -> Result<(), EspError> {
+    if !critical_section::with(|_| unsafe { WIFI_INITIALIZED }) {
+        return Err(EspError::InvalidState);
+    }
+
+    unsafe {
+        phy_enable();
+
+        // returns early if the driver is still started
+        EspError::check(backend::with_backend(|backend| backend.start()))?;
+        critical_section::with(|_| WIFI_STARTED = true);
+
+        let has_sta = critical_section::with(|_| WIFI_MODE.has_sta());
+        if has_sta && reconnect::connection_wanted() && !is_connected() && !is_connecting() {
+            critical_section::with(|_| WIFI_CONNECTING = true);
+            let res = esp_wifi_connect();
+            if res != 0 {
+                critical_section::with(|_| WIFI_CONNECTING = false);
+            }
+            return EspError::check(res);
+        }
+    }
+
+    Ok(())
+}
+
+/// Current value of the TSF timer of the STA interface in microseconds.
+/// This is synchronized to the beacons of the connected AP. Returns 0 when not connected.
+pub fn wifi_get_tsf_time() -> i64 {
+    unsafe { esp_wifi_get_tsf_time(wifi_interface_t_WIFI_IF_STA) }
+}
+
+/// Call `callback` when the TSF timer reaches `tsf_time` (in microseconds).
+///
+/// The TSF time is converted into a systimer deadline when the alarm is set so it isn't affected
+/// by the imprecise RTC. The callback runs on the internal timer task and should return quickly.
+/// Setting a new alarm replaces the previous one.
+///
+/// Returns false if not connected or if `tsf_time` already passed.
+pub fn wifi_set_tsf_alarm(tsf_time: i64, callback: fn()) -> bool {
+    let now = wifi_get_tsf_time();
+    if now == 0 || tsf_time <= now {
+        return false;
+    }
+
+    let expire = get_systimer_count() + (tsf_time - now) as u64 * 16;
+    critical_section::with(|_| unsafe {
+        TSF_ALARM = Some((expire, callback));
+    });
+
+    true
+}
+
+pub fn wifi_cancel_tsf_alarm() {
+    critical_section::with(|_| unsafe {
+        TSF_ALARM = None;
+    });
+}
+
+pub(crate) fn check_tsf_alarm() {
+    let due = critical_section::with(|_| unsafe {
+        match TSF_ALARM {
+            Some((expire, callback)) if get_systimer_count() >= expire => {
+                TSF_ALARM = None;
+                Some(callback)
+            }
+            _ => None,
+        }
+    });
+
+    if let Some(callback) = due {
+        callback();
+    }
+}
+
+/// Regulatory domain settings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CountryInfo {
+    /// Two letter country code
+    pub country_code: [u8; 2],
+    pub start_channel: u8,
+    pub channel_count: u8,
+    /// Adopt the country advertised by the AP we are connected to
+    pub auto: bool,
+}
+
+/// Set the regulatory domain. If `auto` is set the station adopts the country advertised
+/// by the AP it connects to (802.11d) and only uses these settings while not connected.
+///
+/// Can be called before `wifi_init` to set the initial country.
+pub fn wifi_set_country(country: CountryInfo) -> Result<(), EspError> {
+    let raw = wifi_country_t {
+        cc: [country.country_code[0], country.country_code[1], 0],
+        schan: country.start_channel,
+        nchan: country.channel_count,
+        max_tx_power: 20,
+        policy: if country.auto {
+            wifi_country_policy_t_WIFI_COUNTRY_POLICY_AUTO
+        } else {
+            wifi_country_policy_t_WIFI_COUNTRY_POLICY_MANUAL
+        },
+    };
+
+    let initialized = critical_section::with(|_| unsafe {
+        COUNTRY = raw;
+        if WIFI_INITIALIZED {
+            EFFECTIVE_COUNTRY = Some(country.country_code);
+        }
+        WIFI_INITIALIZED
+    });
+
+    if initialized {
+        EspError::check(unsafe { esp_wifi_set_country(&raw) })
+    } else {
+        Ok(())
+    }
+}
+
+/// Get the effective regulatory domain
+pub fn wifi_get_country() -> Result<CountryInfo, i32> {
+    let mut raw: wifi_country_t = unsafe { core::mem::zeroed() };
+    let res = unsafe { esp_wifi_get_country(&mut raw) };
+    if res != 0 {
+        return Err(res);
+    }
+
+    Ok(CountryInfo {
+        country_code: [raw.cc[0], raw.cc[1]],
+        start_channel: raw.schan,
+        channel_count: raw.nchan,
+        auto: raw.policy == wifi_country_policy_t_WIFI_COUNTRY_POLICY_AUTO,
+    })
+}
+
+/// Set the maximum TX power in dBm (2 to 20). Lower power saves energy at the cost of range.
+///
+/// Needs `wifi_start` to be called before. The driver only supports some steps,
+/// the value is rounded down to the next one (2, 5, 7, 8.5, 11, 13, 14, 15, 16.5, 18, 20 dBm).
+pub fn wifi_set_tx_power(dbm: i8) -> i32 {
+    // unit is 0.25 dBm
+    let power = (dbm.clamp(2, 20) as i32 * 4) as i8;
+    unsafe { esp_wifi_set_max_tx_power(power) }
+}
+
+/// Get the maximum TX power in dBm (rounded down)
+pub fn wifi_get_tx_power() -> Result<i8, i32> {
+    let mut power = 0i8;
+    let res = unsafe { esp_wifi_get_max_tx_power(&mut power) };
+    if res != 0 {
+        return Err(res);
+    }
+
+    Ok(power / 4)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthMethod {
+    None,
+    WEP,
+    WPA,
+    WPA2Personal,
+    WPAWPA2Personal,
+    WPA2Enterprise,
+    WPA3Personal,
+    WPA2WPA3Personal,
+    WAPIPersonal,
+    Unknown,
+}
+
+impl AuthMethod {
+    fn from_raw(authmode: wifi_auth_mode_t) -> AuthMethod {
+        match authmode {
+            wifi_auth_mode_t_WIFI_AUTH_OPEN => AuthMethod::None,
+            wifi_auth_mode_t_WIFI_AUTH_WEP => AuthMethod::WEP,
+            wifi_auth_mode_t_WIFI_AUTH_WPA_PSK => AuthMethod::WPA,
+            wifi_auth_mode_t_WIFI_AUTH_WPA2_PSK => AuthMethod::WPA2Personal,
+            wifi_auth_mode_t_WIFI_AUTH_WPA_WPA2_PSK => AuthMethod::WPAWPA2Personal,
+            wifi_auth_mode_t_WIFI_AUTH_WPA2_ENTERPRISE => AuthMethod::WPA2Enterprise,
+            wifi_auth_mode_t_WIFI_AUTH_WPA3_PSK => AuthMethod::WPA3Personal,
+            wifi_auth_mode_t_WIFI_AUTH_WPA2_WPA3_PSK => AuthMethod::WPA2WPA3Personal,
+            wifi_auth_mode_t_WIFI_AUTH_WAPI_PSK => AuthMethod::WAPIPersonal,
+            _ => AuthMethod::Unknown,
+        }
+    }
+
+    fn to_raw(self) -> wifi_auth_mode_t {
+        match self {
+            AuthMethod::None => wifi_auth_mode_t_WIFI_AUTH_OPEN,
+            AuthMethod::WEP => wifi_auth_mode_t_WIFI_AUTH_WEP,
+            AuthMethod::WPA => wifi_auth_mode_t_WIFI_AUTH_WPA_PSK,
+            AuthMethod::WPA2Personal => wifi_auth_mode_t_WIFI_AUTH_WPA2_PSK,
+            AuthMethod::WPAWPA2Personal => wifi_auth_mode_t_WIFI_AUTH_WPA_WPA2_PSK,
+            AuthMethod::WPA2Enterprise => wifi_auth_mode_t_WIFI_AUTH_WPA2_ENTERPRISE,
+            AuthMethod::WPA3Personal => wifi_auth_mode_t_WIFI_AUTH_WPA3_PSK,
+            AuthMethod::WPA2WPA3Personal => wifi_auth_mode_t_WIFI_AUTH_WPA2_WPA3_PSK,
+            AuthMethod::WAPIPersonal => wifi_auth_mode_t_WIFI_AUTH_WAPI_PSK,
+            // rejected by the driver
+            AuthMethod::Unknown => wifi_auth_mode_t_WIFI_AUTH_MAX,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cipher {
+    None,
+    Wep40,
+    Wep104,
+    Tkip,
+    Ccmp,
+    TkipCcmp,
+    AesCmac128,
+    Sms4,
+    Unknown,
+}
+
+impl Cipher {
+    fn from_raw(cipher: wifi_cipher_type_t) -> Cipher {
+        match cipher {
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_NONE => Cipher::None,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_WEP40 => Cipher::Wep40,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_WEP104 => Cipher::Wep104,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_TKIP => Cipher::Tkip,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_CCMP => Cipher::Ccmp,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_TKIP_CCMP => Cipher::TkipCcmp,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_AES_CMAC128 => Cipher::AesCmac128,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_SMS4 => Cipher::Sms4,
+            _ => Cipher::Unknown,
+        }
+    }
+}
+
+/// Information about an access point - the one the station is associated with or one found
+/// by a scan.
+#[derive(Debug, Clone, Copy)]
+pub struct ApInfo {
+    ssid: [u8; 33],
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    /// Protocols the AP supports
+    pub phy: Protocols,
+    /// dBm
+    pub rssi: i8,
+    pub auth_method: AuthMethod,
+    pub pairwise_cipher: Cipher,
+    pub group_cipher: Cipher,
+    /// Whether protected management frames are in use.
+    /// `None` if it can't be told - i.e. PMF is optional for the AP and for us.
+    pub pmf: Option<bool>,
+    /// Only known if enabled via `bss_load::bss_load_set_enabled` and advertised by the AP
+    pub bss_load: Option<BssLoad>,
+}
+
+impl ApInfo {
+    fn from_record(record: &wifi_ap_record_t) -> ApInfo {
+        let auth_method = AuthMethod::from_raw(record.authmode);
+        let pmf = match auth_method {
+            AuthMethod::WPA3Personal => Some(true),
+            AuthMethod::None | AuthMethod::WEP | AuthMethod::WPA => Some(false),
+            _ => None,
+        };
+
+        ApInfo {
+            ssid: record.ssid,
+            bssid: record.bssid,
+            channel: record.primary,
+            phy: Protocols {
+                b: record.phy_11b() != 0,
+                g: record.phy_11g() != 0,
+                n: record.phy_11n() != 0,
+                lr: record.phy_lr() != 0,
+            },
+            rssi: record.rssi,
+            auth_method,
+            pairwise_cipher: Cipher::from_raw(record.pairwise_cipher),
+            group_cipher: Cipher::from_raw(record.group_cipher),
+            pmf,
+            bss_load: bss_load::bss_load_lookup(&record.bssid),
+        }
+    }
+
+    pub fn ssid(&self) -> &str {
+        let len = self
+            .ssid
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(self.ssid.len());
+        core::str::from_utf8(&self.ssid[..len]).unwrap_or("")
+    }
+
+    /// True if the link doesn't use at least WPA2 with CCMP
+    pub fn is_weak(&self) -> bool {
+        match self.auth_method {
+            AuthMethod::None | AuthMethod::WEP | AuthMethod::WPA | AuthMethod::Unknown => true,
+            _ => self.pairwise_cipher != Cipher::Ccmp,
+        }
+    }
+}
+
+/// Get information about the AP the station is currently associated with.
+pub fn wifi_get_ap_info() -> Result<ApInfo, i32> {
+    let mut record: wifi_ap_record_t = unsafe { core::mem::zeroed() };
+    let res = unsafe { esp_wifi_sta_get_ap_info(&mut record) };
+    if res != 0 {
+        return Err(res);
+    }
+
+    Ok(ApInfo::from_record(&record))
+}
+
+/// Signal strength of the AP the station is associated with in dBm.
+pub fn wifi_rssi() -> Result<i8, i32> {
+    wifi_get_ap_info().map(|info| info.rssi)
+}
+
+/// Stop the driver.
+///
+/// Frames still waiting in the RX and TX queues are discarded and tokens handed out before
+/// can't be used anymore - consuming them fails. Until `wifi_start` is called again the devices
+/// don't hand out TX tokens and `WifiDevice::link_up` returns false.
+pub fn wifi_stop() -> Result<(), EspError> {
+    critical_section::with(|_| unsafe {
+        WIFI_STARTED = false;
+        LINK_GENERATION = LINK_GENERATION.wrapping_add(1);
+        STA_AUTH_MODE = None;
+    });
+
+    let res = backend::with_backend(|backend| backend.stop());
+
+    critical_section::with(|_| unsafe { flush_queues() });
+    ack_coalescing::discard_held_ack();
+    reconnect::on_stopped();
+    congestion::reset();
+    tx_credits::reset();
+
+    EspError::check(res)
+}
+
+/// Undo `wifi_init`: stop the driver if it's running, deinitialize it and release the
+/// memory it allocated. Returns the RNG passed to `wifi_init`, which can be called again
+/// afterwards (e.g. with another mode).
+///
+/// Fails with `EspError::InvalidState` while a `WifiDevice` is taken, the driver is handed back
+/// on errors. The timer interrupt and the worker tasks keep running since they are needed by
+/// `wifi_init`.
+pub fn wifi_deinit(driver: WifiDriver) -> Result<hal::pac::RNG, (WifiDriver, EspError)> {
+    let device_taken =
+        critical_section::with(|_| unsafe { WIFI_DEVICE_TAKEN.iter().any(|taken| *taken) });
+    if device_taken {
+        return Err((driver, EspError::InvalidState));
+    }
+
+    if critical_section::with(|_| unsafe { WIFI_STARTED }) {
+        if let Err(err) = wifi_stop() {
+            return Err((driver, err));
+        }
+    }
+
+    unsafe {
+        if !EXTERNAL_SUPPLICANT {
+            if let Err(err) = EspError::check(esp_supplicant_deinit()) {
+                return Err((driver, err));
+            }
+        }
+
+        if let Err(err) = EspError::check(deinit_driver(false)) {
+            return Err((driver, err));
+        }
+    }
+
+    critical_section::with(|_| unsafe {
+        WIFI_STATE = -1;
+        AP_STARTED = false;
+        WIFI_CONNECTING = false;
+        apply_init_config(&WifiInitConfig::new());
+        WIFI_INITIALIZED = false;
+        RANDOM_GENERATOR = None;
+    });
+
+    // it was handed to `wifi_init` and `deinit_rng` doesn't give it out while initialized
+    Ok(unsafe { hal::pac::Peripherals::steal().RNG })
+}
+
+/// Deinitialize the driver and release everything it allocated, used by `wifi_deinit` and
+/// when `wifi_init` fails half way.
+unsafe fn deinit_driver(supplicant_initialized: bool) -> i32 {
+    esp_wifi_internal_reg_rxcb(esp_interface_t_ESP_IF_WIFI_STA, None);
+    esp_wifi_internal_reg_rxcb(esp_interface_t_ESP_IF_WIFI_AP, None);
+
+    if supplicant_initialized {
+        esp_supplicant_deinit();
+    }
+
+    let res = esp_wifi_deinit_internal();
+    if res != 0 {
+        return res;
+    }
+
+    critical_section::with(|_| {
+        // disable the WiFi interrupt enabled by the driver via `ints_on`
+        (*hal::pac::INTERRUPT_CORE0::PTR)
+            .cpu_int_enable
+            .modify(|r, w| w.bits(r.bits() & !(1 << 1)));
+        ISR_INTERRUPT_1 = (core::ptr::null_mut(), core::ptr::null_mut());
+    });
+
+    // whatever the driver didn't delete still points into the heap, forget it before the
+    // allocations are reclaimed
+    crate::compat::timer_compat::compat_timers_reset();
+    crate::compat::work_queue::work_queue_reset();
+    crate::compat::common::compat_reset();
+
+    let leaked = crate::compat::malloc::heap_used();
+    if leaked != 0 {
+        debug!("driver didn't free {} bytes, reclaiming them", leaked);
+    }
+    crate::compat::malloc::heap_reset();
+
+    0
+}
+
+/// Needs to be called in a critical section.
+unsafe fn flush_queues() {
+    // buffers being written or read are released by their owner
+    for interface in [WifiInterface::Sta, WifiInterface::Ap] {
+        if let Some(data_queue_rx) = rx_queue(interface) {
+            while let Some(index) = data_queue_rx.dequeue() {
+                RX_BUFFER_STATE[interface.index()][index] = RxBufferState::Free;
+            }
+        }
+    }
+
+    if let Some(deferred) = &mut DATA_QUEUE_RX_DEFERRED {
+        while let Some((_, _, eb, _)) = deferred.dequeue() {
+            esp_wifi_internal_free_rx_buffer(eb);
+        }
+    }
+
+    if let Some(eapol_queue_rx) = &mut EAPOL_QUEUE_RX {
+        while eapol_queue_rx.dequeue().is_some() {}
+    }
+
+    TX_QUEUED = false;
+    TX_PRIORITY_QUEUED = false;
+    TX_RETRIES = 0;
+    TX_RETRY_AT = 0;
+
+    for tx_ref_queue in [&mut TX_REF_QUEUE, &mut TX_REF_PRIORITY_QUEUE] {
+        if let Some(tx_ref_queue) = tx_ref_queue {
+            while let Some(index) = tx_ref_queue.dequeue() {
+                TX_REF_BUFFERS[index].refs -= 1;
+            }
+        }
+    }
+}
+
+/// ARP, DHCP and EAPOL frames are sent before bulk data so connections don't stall while
+/// renewing a lease or rekeying during a large upload.
+fn is_control_frame(frame: &[u8]) -> bool {
+    use smoltcp::wire::{EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Packet, UdpPacket};
+
+    let eth = match EthernetFrame::new_checked(frame) {
+        Ok(eth) => eth,
+        Err(_) => return false,
+    };
+
+    match eth.ethertype() {
+        EthernetProtocol::Arp => true,
+        EthernetProtocol::Unknown(0x888e) => true,
+        EthernetProtocol::Ipv4 => {
+            let ip = match Ipv4Packet::new_checked(eth.payload()) {
+                Ok(ip) if ip.protocol() == IpProtocol::Udp => ip,
+                _ => return false,
+            };
+            match UdpPacket::new_checked(ip.payload()) {
+                Ok(udp) => matches!(udp.dst_port(), 67 | 68),
+                Err(_) => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+pub fn init_clocks() {
+    // CPU as 160Mhz
+    unsafe {
+        (*hal::pac::SYSTEM::PTR)
+            .cpu_per_conf
+            .modify(|_, w| w.cpuperiod_sel().bits(1).pll_freq_sel().set_bit());
+        (*hal::pac::SYSTEM::PTR)
+            .sysclk_conf
+            .modify(|_, w| w.soc_clk_sel().bits(1));
+    }
+
+    unsafe {
+        // PERIP_CLK_EN0
+        ((0x600c0000 + 0x10) as *mut u32).write_volatile(0xffffffff);
+        // PERIP_CLK_EN1
+        ((0x600c0000 + 0x14) as *mut u32).write_volatile(0xffffffff);
+    }
+
+    // APB_CTRL_WIFI_CLK_EN_REG
+    unsafe {
+        ((0x60026000 + 0x14) as *mut u32).write_volatile(0xffffffff);
+    }
+}
+
+/// The smoltcp device for one interface (STA or AP).
+///
+/// There is only one data queue per interface so only a single instance per interface can exist at a time.
+/// Use `WifiDevice::take(interface)` to get it - it can be taken again once the previous instance is dropped.
+/// In `WifiMode::ApSta` one device per interface can be used, e.g. to build a repeater.
+///
+/// The device is `Send` so it can be moved into another task. Polling it doesn't need to be
+/// wrapped in a critical section.
+pub struct WifiDevice {
+    interface: WifiInterface,
+}
+
+impl WifiDevice {
+    pub fn take(interface: WifiInterface) -> Option<WifiDevice> {
+        critical_section::with(|_| unsafe {
+            if WIFI_DEVICE_TAKEN[interface.index()] {
+                None
+            } else {
+                WIFI_DEVICE_TAKEN[interface.index()] = true;
+                Some(WifiDevice { interface })
+            }
+        })
+    }
+
+    pub fn interface(&self) -> WifiInterface {
+        self.interface
+    }
+
+    /// True if the driver is started and the station is connected / the access point is started
+    pub fn link_up(&self) -> bool {
+        let started = critical_section::with(|_| unsafe { WIFI_STARTED });
+        started
+            && match self.interface {
+                WifiInterface::Sta => is_connected(),
+                WifiInterface::Ap => is_ap_started(),
+            }
+    }
+
+    fn tx_token(&self) -> WifiTxToken {
+        let (generation, slot) =
+            critical_section::with(|_| unsafe { (LINK_GENERATION, reserve_tx_slot()) });
+
+        WifiTxToken {
+            interface: self.interface,
+            generation,
+            slot,
+        }
+    }
+}
+
+impl Drop for WifiDevice {
+    fn drop(&mut self) {
+        critical_section::with(|_| unsafe {
+            WIFI_DEVICE_TAKEN[self.interface.index()] = false;
+        });
+    }
+}
+
+// see https://docs.rs/smoltcp/0.7.1/smoltcp/phy/index.html
+impl<'a> Device<'a> for WifiDevice {
+    type RxToken = WifiRxToken;
+
+    type TxToken = WifiTxToken;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let interface = self.interface;
+        let available = critical_section::with(|_| unsafe {
+            if let Some(data_queue_rx) = rx_queue(interface) {
+                !data_queue_rx.is_empty()
+            } else {
+                false
+            }
+        });
+
+        if available {
+            Some((WifiRxToken { interface }, self.tx_token()))
+        } else {
+            None
+        }
+    }
+
+    // No token is handed out while all TX buffers are busy, smoltcp tries again on the next
+    // poll then
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        if !critical_section::with(|_| unsafe { WIFI_STARTED }) {
+            return None;
+        }
+
+        let token = self.tx_token();
+        if token.slot.is_none() {
+            congestion::note_tx_busy();
+            return None;
+        }
+        Some(token)
+    }
+
+    fn capabilities(&self) -> smoltcp::phy::DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = usize::min(MAX_FRAME_LEN, 1514);
+        caps.max_burst_size = Some(1);
+        caps
+    }
+}
+
+// The tokens only touch the shared queues inside critical sections so they (and the device
+// handing them out) can be moved to and used from any task. The frames are copied with
+// interrupts enabled, the buffers are reserved for the token meanwhile.
+// They must not be used from an interrupt handler.
+#[derive(Debug)]
+pub struct WifiRxToken {
+    interface: WifiInterface,
+}
+
+impl RxToken for WifiRxToken {
+    fn consume<R, F>(self, _timestamp: smoltcp::time::Instant, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        let interface = self.interface;
+        let index = critical_section::with(|_| unsafe {
+            let index = rx_queue(interface)
+                .as_mut()
+                .and_then(|data_queue_rx| data_queue_rx.dequeue())?;
+            RX_BUFFER_STATE[interface.index()][index] = RxBufferState::Reading;
+            RX_FRAMES = RX_FRAMES.wrapping_add(1);
+            Some(index)
+        });
+
+        let res = match index {
+            Some(index) => {
+                verbose!("received {:?}", _timestamp);
+                let data = unsafe { &mut RX_BUFFERS[interface.index()][index] };
+                capture::capture_frame(
+                    interface,
+                    capture::CaptureDirection::Rx,
+                    &data.data[..data.len],
+                );
+                let res = f(&mut data.data[..data.len]);
+
+                critical_section::with(|_| unsafe {
+                    RX_BUFFER_STATE[interface.index()][index] = RxBufferState::Free;
+                });
+                res
+            }
+            None => Err(smoltcp::Error::Exhausted),
+        };
+
+        promote_deferred_rx_frame();
+        res
+    }
+}
+
+/// A TX buffer reserved for a token
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TxSlot {
+    Main,
+    Priority,
+    ByRef(usize),
+}
+
+// Both interfaces share the TX buffers. A buffer is reserved when the token is handed out so
+// a frame is never dropped after it was written, the reservation is released if the token is
+// dropped unused.
+#[derive(Debug)]
+pub struct WifiTxToken {
+    interface: WifiInterface,
+    generation: u32,
+    slot: Option<TxSlot>,
+}
+
+impl Drop for WifiTxToken {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            critical_section::with(|_| unsafe { release_tx_slot(slot) });
+        }
+    }
+}
+
+/// Reserve a free TX buffer. Needs to be called in a critical section.
+unsafe fn reserve_tx_slot() -> Option<TxSlot> {
+    if TX_BY_REF {
+        let index = TX_REF_BUFFERS.iter().position(|buffer| buffer.refs == 0)?;
+        TX_REF_BUFFERS[index].refs = 1;
+        Some(TxSlot::ByRef(index))
+    } else if !TX_QUEUED && !TX_RESERVED {
+        TX_RESERVED = true;
+        Some(TxSlot::Main)
+    } else if !TX_PRIORITY_QUEUED && !TX_PRIORITY_RESERVED {
+        TX_PRIORITY_RESERVED = true;
+        Some(TxSlot::Priority)
+    } else {
+        None
+    }
+}
+
+/// Needs to be called in a critical section
+unsafe fn release_tx_slot(slot: TxSlot) {
+    match slot {
+        TxSlot::Main => TX_RESERVED = false,
+        TxSlot::Priority => TX_PRIORITY_RESERVED = false,
+        TxSlot::ByRef(index) => TX_REF_BUFFERS[index].refs = 0,
+    }
+}
+
+impl TxToken for WifiTxToken {
+    fn consume<R, F>(
+        mut self,
+        _timestamp: smoltcp::time::Instant,
+        len: usize,
+        f: F,
+    ) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        // tokens handed out along with a received frame may not get a buffer
+        let slot = match self.slot.take() {
+            Some(slot) => slot,
+            None => return Err(smoltcp::Error::Exhausted),
+        };
+
+        if let TxSlot::ByRef(index) = slot {
+            return consume_by_ref(self.interface, self.generation, index, len, f);
+        }
+
+        if len > MAX_FRAME_LEN {
+            critical_section::with(|_| unsafe { release_tx_slot(slot) });
+            return Err(smoltcp::Error::Truncated);
+        }
+
+        let priority = slot == TxSlot::Priority;
+        let buffer = unsafe {
+            if priority {
+                &mut TX_PRIORITY_BUFFER[..len]
+            } else {
+                &mut TX_BUFFER[..len]
+            }
+        };
+
+        let res = f(buffer);
+
+        let filtered = res.is_ok()
+            && (ap_isolation::filter_tx_frame(buffer, self.interface)
+                || ack_coalescing::filter_tx_frame(buffer, self.interface));
+
+        // Don't send anything if the driver was stopped since the token was handed out
+        let queued = critical_section::with(|_| unsafe {
+            release_tx_slot(slot);
+
+            if res.is_err() || filtered || LINK_GENERATION != self.generation {
+                return false;
+            }
+
+            if priority {
+                TX_PRIORITY_DATA_LEN = len as u16;
+                TX_PRIORITY_INTERFACE = self.interface;
+                TX_PRIORITY_QUEUED = true;
+                TX_PRIORITY_FIRST = !TX_QUEUED || is_control_frame(buffer);
+            } else {
+                TX_QUEUED_DATA_LEN = len as u16;
+                TX_QUEUED_INTERFACE = self.interface;
+                TX_QUEUED = true;
+            }
+            true
+        });
+
+        if res.is_ok() && !filtered && !queued {
+            return Err(smoltcp::Error::Exhausted);
+        }
+
+        res
+    }
+}
+
+/// Hold back pure TCP ACKs for up to `window_ms` milliseconds. If another segment of the same
+/// connection is sent meanwhile the held ACK is dropped, since every segment carries the latest
+/// ACK number. Saves airtime and radio wakeups for download heavy workloads.
+///
+/// Only one ACK is held at a time. A window of 0 disables this (default).
+/// Keep the window well below the round trip time, e.g. 1 - 10 ms.
+pub fn wifi_set_ack_coalescing(window_ms: u32) {
+    ack_coalescing::set_window_ms(window_ms);
+}
+
+/// Send frames via `esp_wifi_internal_tx_by_ref` instead of letting the driver copy them.
+///
+/// Frames are written into a pool of 4 buffers (about 6 KB) which the driver holds on to until
+/// the frame is sent. Saves a copy per frame and allows queuing more than one frame. If disabled
+/// (default) a single buffer is used which the driver copies from.
+///
+/// Needs to be called before `wifi_init`.
+pub fn wifi_set_tx_by_ref(enabled: bool) {
+    critical_section::with(|_| unsafe {
+        TX_BY_REF = enabled;
+    });
+}
+
+fn consume_by_ref<R, F>(
+    interface: WifiInterface,
+    generation: u32,
+    index: usize,
+    len: usize,
+    f: F,
+) -> smoltcp::Result<R>
+where
+    F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+{
+    if len > TX_REF_BUFFER_LEN {
+        critical_section::with(|_| unsafe { release_tx_slot(TxSlot::ByRef(index)) });
+        return Err(smoltcp::Error::Exhausted);
+    }
+
+    // the buffer is ours until it's queued
+    let res = unsafe { f(&mut TX_REF_BUFFERS[index].data[..len]) };
+
+    let frame = unsafe { &TX_REF_BUFFERS[index].data[..len] };
+    let filtered = res.is_ok()
+        && (ap_isolation::filter_tx_frame(frame, interface)
+            || ack_coalescing::filter_tx_frame(frame, interface));
+
+    let queued = critical_section::with(|_| unsafe {
+        let buffer = &mut TX_REF_BUFFERS[index];
+        if res.is_ok() && !filtered && LINK_GENERATION == generation {
+            let tx_ref_queue = if is_control_frame(&buffer.data[..len]) {
+                &mut TX_REF_PRIORITY_QUEUE
+            } else {
+                &mut TX_REF_QUEUE
+            };
+
+            // the queues have room for all buffers
+            if let Some(tx_ref_queue) = tx_ref_queue {
+                buffer.len = len as u16;
+                buffer.interface = interface;
+                tx_ref_queue.enqueue(index);
+                return true;
+            }
+        }
+
+        buffer.refs = 0;
+        false
+    });
+
+    if res.is_ok() && !filtered && !queued {
+        return Err(smoltcp::Error::Exhausted);
+    }
+
+    res
+}
+
+fn send_by_ref_if_needed() {
+    // the frames stay queued until the driver is done with others
+    while tx_credits::available() {
+        let index = critical_section::with(|_| unsafe {
+            TX_REF_PRIORITY_QUEUE
+                .as_mut()
+                .and_then(|tx_ref_queue| tx_ref_queue.dequeue())
+                .or_else(|| {
+                    TX_REF_QUEUE
+                        .as_mut()
+                        .and_then(|tx_ref_queue| tx_ref_queue.dequeue())
+                })
+        });
+
+        let index = match index {
+            Some(index) => index,
+            None => break,
+        };
+
+        unsafe {
+            let buffer = &mut TX_REF_BUFFERS[index];
+            debug!("sending by ref... {} bytes", buffer.len);
+            capture::capture_frame(
+                buffer.interface,
+                capture::CaptureDirection::Tx,
+                &buffer.data[..buffer.len as usize],
+            );
+            let interface = buffer.interface;
+            let frame = &mut buffer.data[..buffer.len as usize];
+            let res = backend::with_backend(|backend| {
+                backend.transmit_by_ref(
+                    interface,
+                    frame,
+                    (index + 1) as *mut crate::binary::c_types::c_void,
+                )
+            });
+            debug!("transmit_by_ref {}", res);
+            note_tx_result(res);
+            congestion::note_tx_result(res);
+            error_log::note_tx_result(res);
+
+            // frames sent by reference aren't retried, the queue can't take them back in order
+            if res != 0 {
+                critical_section::with(|_| TX_DROPPED += 1);
+            }
+
+            // the driver took its own reference if it still needs the buffer
+            tx_ref_buffer_free((index + 1) as *mut crate::binary::c_types::c_void);
+        }
+    }
+}
+
+// the netstack buffer passed to the driver is the index of the TX buffer + 1
+unsafe extern "C" fn tx_ref_buffer_ref(netstack_buf: *mut crate::binary::c_types::c_void) {
+    critical_section::with(|_| {
+        TX_REF_BUFFERS[netstack_buf as usize - 1].refs += 1;
+    });
+}
+
+unsafe extern "C" fn tx_ref_buffer_free(netstack_buf: *mut crate::binary::c_types::c_void) {
+    critical_section::with(|_| {
+        TX_REF_BUFFERS[netstack_buf as usize - 1].refs -= 1;
+    });
+}
+
+pub fn send_data_if_needed() {
+    if radio::tx_held() {
+        return;
+    }
+
+    ack_coalescing::send_held_ack_if_due();
+    send_by_ref_if_needed();
+
+    // the driver was out of buffers or is still busy with the frames handed to it
+    if get_systimer_count() < critical_section::with(|_| unsafe { TX_RETRY_AT })
+        || !tx_credits::available()
+    {
+        return;
+    }
+
+    let priority_first = critical_section::with(|_| unsafe { TX_PRIORITY_FIRST });
+
+    if priority_first {
+        if send_priority_buffer() {
+            send_main_buffer();
+        }
+    } else if send_main_buffer() {
+        send_priority_buffer();
+    }
+}
+
+/// Returns false if the frame is still queued
+fn send_priority_buffer() -> bool {
+    if !tx_credits::available() {
+        return false;
+    }
+
+    let to_send = critical_section::with(|_| unsafe {
+        if TX_PRIORITY_QUEUED {
+            Some((TX_PRIORITY_DATA_LEN, TX_PRIORITY_INTERFACE))
+        } else {
+            None
+        }
+    });
+
+    if let Some((len, interface)) = to_send {
+        let done = unsafe { send_from_buffer(&mut TX_PRIORITY_BUFFER[..len as usize], interface) };
+        if !done {
+            return false;
+        }
+
+        critical_section::with(|_| unsafe {
+            TX_PRIORITY_QUEUED = false;
+        });
+    }
+    true
+}
+
+/// Returns false if the frame is still queued
+fn send_main_buffer() -> bool {
+    if !tx_credits::available() {
+        return false;
+    }
+
+    let to_send = critical_section::with(|_| unsafe {
+        if TX_QUEUED {
+            Some((TX_QUEUED_DATA_LEN, TX_QUEUED_INTERFACE))
+        } else {
+            None
+        }
+    });
+
+    if let Some((len, interface)) = to_send {
+        let done = unsafe { send_from_buffer(&mut TX_BUFFER[..len as usize], interface) };
+        if !done {
+            return false;
+        }
+
+        critical_section::with(|_| unsafe {
+            TX_QUEUED = false;
+            // whatever waits in the priority slot was queued before the next main frame
+            TX_PRIORITY_FIRST = true;
+        });
+    }
+    true
+}
+
+// The frame is handed to the backend straight from the TX buffer. The buffer stays marked as
+// queued until the backend returns so no token writes into it meanwhile. The driver copies the
+// frame into one of its dynamic TX buffers so it's free again afterwards.
+//
+// Returns false if the frame needs to stay queued since the driver is out of buffers, it's
+// retried with backoff then.
+unsafe fn send_from_buffer(frame: &mut [u8], interface: WifiInterface) -> bool {
+    debug!("sending... {} bytes", frame.len());
+    capture::capture_frame(interface, capture::CaptureDirection::Tx, frame);
+    let res = backend::with_backend(|backend| backend.transmit(interface, frame));
+    debug!("transmit {}", res);
+    note_tx_result(res);
+    congestion::note_tx_result(res);
+    error_log::note_tx_result(res);
+
+    critical_section::with(|_| {
+        if res == ESP_ERR_NO_MEM as i32 && TX_RETRIES < TX_MAX_RETRIES {
+            // 1, 2, 4, 8, 16ms
+            TX_RETRY_AT = get_systimer_count() + (16_000 << TX_RETRIES);
+            TX_RETRIES += 1;
+            return false;
+        }
+
+        if res != 0 {
+            debug!("dropping frame after TX error {}", res);
+            TX_DROPPED += 1;
+        }
+        TX_RETRIES = 0;
+        true
+    })
 }
@@ -1,12 +1,11 @@
-use embedded_hal::prelude::_embedded_hal_blocking_rng_Read;
-
 use crate::{
     binary::include::*,
     compat::{
         common::{
-            create_recursive_mutex, create_wifi_queue, lock_mutex, receive_queued, sem_create,
-            sem_delete, sem_give, sem_take, send_queued, syslog, thread_sem_get, unlock_mutex,
-            StrBuf,
+            clear_event_bits, create_event_group, create_recursive_mutex, create_wifi_queue,
+            delete_event_group, delete_mutex, delete_wifi_queue, lock_mutex, receive_queued,
+            sem_create, sem_delete, sem_give, sem_take, send_queued, set_event_bits, syslog,
+            thread_sem_get, unlock_mutex, wait_event_bits, StrBuf,
         },
         malloc::calloc,
         timer_compat::{
@@ -16,15 +15,34 @@ use crate::{
         work_queue::queue_work,
     },
     trace,
-    wifi::{phy_init_data::PHY_INIT_DATA_DEFAULT, RANDOM_GENERATOR},
+    wifi::{
+        fill_random, phy_init_data::PHY_INIT_DATA_DEFAULT, queue_event, reconnect, AuthMethod,
+        WifiEvent,
+    },
 };
 
 pub static mut WIFI_STATE: i32 = -1;
 
+// set while a connection attempt is in progress
+pub(crate) static mut WIFI_CONNECTING: bool = false;
+
 pub fn is_connected() -> bool {
     unsafe { WIFI_STATE == wifi_event_t_WIFI_EVENT_STA_CONNECTED as i32 }
 }
 
+// reason reported by the last STA_DISCONNECTED event
+pub(crate) static mut LAST_DISCONNECT_REASON: Option<u8> = None;
+
+// auth method reported when the station connected, None while not connected
+pub(crate) static mut STA_AUTH_MODE: Option<AuthMethod> = None;
+
+// set between AP_START and AP_STOP
+pub(crate) static mut AP_STARTED: bool = false;
+
+pub fn is_ap_started() -> bool {
+    unsafe { AP_STARTED }
+}
+
 /****************************************************************************
  * Name: esp_event_send_internal
  *
@@ -59,7 +77,39 @@ pub unsafe extern "C" fn esp_event_send_internal(
     );
 
     // probably also need to look at event_base
-    WIFI_STATE = event_id;
+    // only events changing the state of an interface update it - e.g. a scan done or an AP
+    // interface event must not make the station look disconnected
+    let event = WifiEvent::from_raw(event_id, event_data);
+
+    // the reason is in place before the attempt is marked as finished so `wifi_wait_connected`
+    // never sees a finished attempt without its outcome
+    critical_section::with(|_| match event {
+        WifiEvent::ApStart => AP_STARTED = true,
+        WifiEvent::ApStop => AP_STARTED = false,
+        WifiEvent::StaConnected { auth_method, .. } => {
+            STA_AUTH_MODE = Some(auth_method);
+            WIFI_STATE = event_id;
+            WIFI_CONNECTING = false;
+        }
+        WifiEvent::StaDisconnected { reason, .. } => {
+            STA_AUTH_MODE = None;
+            LAST_DISCONNECT_REASON = Some(reason);
+            WIFI_STATE = event_id;
+            WIFI_CONNECTING = false;
+        }
+        WifiEvent::StaStart | WifiEvent::StaStop => WIFI_STATE = event_id,
+        _ => (),
+    });
+
+    queue_event(event);
+
+    match event {
+        WifiEvent::StaConnected { .. } => reconnect::on_connected(),
+        WifiEvent::StaDisconnected { reason, .. } => {
+            reconnect::on_disconnected(reason as wifi_err_reason_t)
+        }
+        _ => (),
+    }
 
     0
 }
@@ -109,7 +159,10 @@ pub unsafe extern "C" fn set_intr(cpu_no: i32, intr_source: u32, intr_num: u32,
     // resetbits(1 << cpuint, INTERRUPT_CPU_INT_ENABLE_REG);
 
     /* Set the interrupt priority. */
-    ((0x600c2000 + 0x114 + intr_num * 4) as *mut u32).write_volatile(intr_prio as u32);
+    let intr_prio = crate::timer::interrupt_priorities()
+        .radio
+        .map_or(intr_prio as u32, |prio| prio as u32);
+    ((0x600c2000 + 0x114 + intr_num * 4) as *mut u32).write_volatile(intr_prio);
 
     /* Set the interrupt type (Edge or Level). */
     // ----
@@ -486,8 +539,8 @@ pub unsafe extern "C" fn recursive_mutex_create() -> *mut crate::binary::c_types
  *   None
  *
  ****************************************************************************/
-pub unsafe extern "C" fn mutex_delete(_mutex: *mut crate::binary::c_types::c_void) {
-    todo!("mutex_delete")
+pub unsafe extern "C" fn mutex_delete(mutex: *mut crate::binary::c_types::c_void) {
+    delete_mutex(mutex);
 }
 
 /****************************************************************************
@@ -558,8 +611,9 @@ pub unsafe extern "C" fn queue_create(
  *   None
  *
  ****************************************************************************/
-pub unsafe extern "C" fn queue_delete(_queue: *mut crate::binary::c_types::c_void) {
-    todo!("queue_delete")
+pub unsafe extern "C" fn queue_delete(queue: *mut crate::binary::c_types::c_void) {
+    // the WiFi queue is the only queue created
+    delete_wifi_queue(queue);
 }
 
 /****************************************************************************
@@ -700,67 +754,109 @@ pub unsafe extern "C" fn queue_msg_waiting(_queue: *mut crate::binary::c_types::
  * Name: esp_event_group_create
  *
  * Description:
- *   Don't support
+ *   Create event group
+ *
+ * Input Parameters:
+ *   None
+ *
+ * Returned Value:
+ *   Event group data pointer
  *
  ****************************************************************************/
 pub unsafe extern "C" fn event_group_create() -> *mut crate::binary::c_types::c_void {
-    todo!("event_group_create")
+    create_event_group()
 }
 
 /****************************************************************************
  * Name: esp_event_group_delete
  *
  * Description:
- *   Don't support
+ *   Delete event group
+ *
+ * Input Parameters:
+ *   event - Event group data pointer
+ *
+ * Returned Value:
+ *   None
  *
  ****************************************************************************/
-pub unsafe extern "C" fn event_group_delete(_event: *mut crate::binary::c_types::c_void) {
-    todo!("event_group_delete")
+pub unsafe extern "C" fn event_group_delete(event: *mut crate::binary::c_types::c_void) {
+    delete_event_group(event);
 }
 
 /****************************************************************************
  * Name: esp_event_group_set_bits
  *
  * Description:
- *   Don't support
+ *   Set event bits
+ *
+ * Input Parameters:
+ *   event - Event group data pointer
+ *   bits  - Bits to set
+ *
+ * Returned Value:
+ *   The bits of the event group after setting them
  *
  ****************************************************************************/
 pub unsafe extern "C" fn event_group_set_bits(
-    _event: *mut crate::binary::c_types::c_void,
-    _bits: u32,
+    event: *mut crate::binary::c_types::c_void,
+    bits: u32,
 ) -> u32 {
-    todo!("event_group_set_bits")
+    set_event_bits(event, bits)
 }
 
 /****************************************************************************
  * Name: esp_event_group_clear_bits
  *
  * Description:
- *   Don't support
+ *   Clear event bits
+ *
+ * Input Parameters:
+ *   event - Event group data pointer
+ *   bits  - Bits to clear
+ *
+ * Returned Value:
+ *   The bits of the event group before clearing them
  *
  ****************************************************************************/
 pub unsafe extern "C" fn event_group_clear_bits(
-    _event: *mut crate::binary::c_types::c_void,
-    _bits: u32,
+    event: *mut crate::binary::c_types::c_void,
+    bits: u32,
 ) -> u32 {
-    todo!("event_group_clear_bits")
+    clear_event_bits(event, bits)
 }
 
 /****************************************************************************
  * Name: esp_event_group_wait_bits
  *
  * Description:
- *   Don't support
+ *   Wait for event bits to be set
+ *
+ * Input Parameters:
+ *   event             - Event group data pointer
+ *   bits_to_wait_for  - Bits to wait for
+ *   clear_on_exit     - Clear the bits waited for once they are set
+ *   wait_for_all_bits - Wait for all bits instead of any of them
+ *   block_time_tick   - Wait ticks
+ *
+ * Returned Value:
+ *   The bits of the event group when the wait ended (set or timed out)
  *
  ****************************************************************************/
 pub unsafe extern "C" fn event_group_wait_bits(
-    _event: *mut crate::binary::c_types::c_void,
-    _bits_to_wait_for: u32,
-    _clear_on_exit: crate::binary::c_types::c_int,
-    _wait_for_all_bits: crate::binary::c_types::c_int,
-    _block_time_tick: u32,
+    event: *mut crate::binary::c_types::c_void,
+    bits_to_wait_for: u32,
+    clear_on_exit: crate::binary::c_types::c_int,
+    wait_for_all_bits: crate::binary::c_types::c_int,
+    block_time_tick: u32,
 ) -> u32 {
-    todo!("event_group_wait_bits")
+    wait_event_bits(
+        event,
+        bits_to_wait_for,
+        clear_on_exit != 0,
+        wait_for_all_bits != 0,
+        block_time_tick,
+    )
 }
 
 /****************************************************************************
@@ -837,14 +933,14 @@ pub unsafe extern "C" fn task_create_pinned_to_core(
  *
  ****************************************************************************/
 pub unsafe extern "C" fn task_create(
-    _task_func: *mut crate::binary::c_types::c_void,
-    _name: *const crate::binary::c_types::c_char,
-    _stack_depth: u32,
-    _param: *mut crate::binary::c_types::c_void,
-    _prio: u32,
-    _task_handle: *mut crate::binary::c_types::c_void,
+    task_func: *mut crate::binary::c_types::c_void,
+    name: *const crate::binary::c_types::c_char,
+    stack_depth: u32,
+    param: *mut crate::binary::c_types::c_void,
+    prio: u32,
+    task_handle: *mut crate::binary::c_types::c_void,
 ) -> i32 {
-    todo!("task_create");
+    task_create_pinned_to_core(task_func, name, stack_depth, param, prio, task_handle, 0)
 }
 
 /****************************************************************************
@@ -861,8 +957,9 @@ pub unsafe extern "C" fn task_create(
  *   None
  *
  ****************************************************************************/
-pub unsafe extern "C" fn task_delete(_task_handle: *mut crate::binary::c_types::c_void) {
-    todo!("task_delete")
+pub unsafe extern "C" fn task_delete(task_handle: *mut crate::binary::c_types::c_void) {
+    // tasks are run as work items which return once done, there is nothing to delete
+    trace!("task_delete {:p}", task_handle);
 }
 
 /****************************************************************************
@@ -943,7 +1040,7 @@ pub unsafe extern "C" fn task_get_current_task() -> *mut crate::binary::c_types:
  ****************************************************************************/
 pub unsafe extern "C" fn task_get_max_priority() -> i32 {
     trace!("task_get_max_priority");
-    255
+    crate::compat::work_queue::MAX_PRIORITY
 }
 
 /****************************************************************************
@@ -1002,13 +1099,33 @@ pub unsafe extern "C" fn free(p: *mut crate::binary::c_types::c_void) {
  *
  ****************************************************************************/
 pub unsafe extern "C" fn event_post(
-    _event_base: *const crate::binary::c_types::c_char,
-    _event_id: i32,
-    _event_data: *mut crate::binary::c_types::c_void,
-    _event_data_size: size_t,
-    _ticks_to_wait: u32,
+    event_base: *const crate::binary::c_types::c_char,
+    event_id: i32,
+    event_data: *mut crate::binary::c_types::c_void,
+    event_data_size: size_t,
+    ticks_to_wait: u32,
 ) -> i32 {
-    todo!("event_post")
+    trace!(
+        "event_post {:?} {} {:p} {} {}",
+        event_base,
+        event_id,
+        event_data,
+        event_data_size,
+        ticks_to_wait
+    );
+
+    if event_base == SC_EVENT {
+        crate::wifi::provisioning::on_event(event_id, event_data);
+        return 0;
+    }
+
+    esp_event_send_internal(
+        event_base,
+        event_id,
+        event_data,
+        event_data_size,
+        ticks_to_wait,
+    )
 }
 
 /****************************************************************************
@@ -1101,7 +1218,41 @@ pub unsafe extern "C" fn wifi_apb80m_release() {
  *
  ****************************************************************************/
 pub unsafe extern "C" fn phy_disable() {
-    trace!("phy_disable")
+    trace!("phy_disable");
+
+    // keep the digital registers to restore them when the PHY is enabled again
+    critical_section::with(|_| {
+        phy_dig_reg_backup(true, PHY_DIGITAL_REGS.as_mut_ptr());
+        PHY_DIGITAL_REGS_STORED = true;
+    });
+}
+
+// SOC_PHY_DIG_REGS_MEM_SIZE is 21 words on the ESP32-C3
+static mut PHY_DIGITAL_REGS: [u32; 21] = [0u32; 21];
+static mut PHY_DIGITAL_REGS_STORED: bool = false;
+
+const PHY_CAL_DATA_MAGIC: u32 = 0x5048_5943;
+
+// RF calibration data is kept in RTC fast memory which is retained over light-sleep
+// and non power-on resets (e.g. brownout) so we can skip the full calibration then
+#[link_section = ".rtc_fast.noinit"]
+static mut PHY_CAL_DATA: [u8; core::mem::size_of::<esp_phy_calibration_data_t>()] =
+    [0u8; core::mem::size_of::<esp_phy_calibration_data_t>()];
+
+#[link_section = ".rtc_fast.noinit"]
+static mut PHY_CAL_DATA_VALID: u32 = 0;
+
+// RTC memory can keep the magic but lose parts of the data over a brownout
+#[link_section = ".rtc_fast.noinit"]
+static mut PHY_CAL_DATA_CRC: u32 = 0;
+
+extern "C" {
+    // in ROM
+    fn crc32_le(crc: u32, buf: *const u8, len: u32) -> u32;
+}
+
+unsafe fn phy_cal_data_crc() -> u32 {
+    crc32_le(0, PHY_CAL_DATA.as_ptr(), PHY_CAL_DATA.len() as u32)
 }
 
 /****************************************************************************
@@ -1123,9 +1274,6 @@ pub unsafe extern "C" fn phy_enable() {
 
     static mut G_IS_PHY_CALIBRATED: bool = false;
 
-    let mut cal_data: [u8; core::mem::size_of::<esp_phy_calibration_data_t>()] =
-        [0u8; core::mem::size_of::<esp_phy_calibration_data_t>()];
-
     let phy_version = get_phy_version_str();
     trace!("phy_version {}", StrBuf::from(phy_version).as_str_ref());
 
@@ -1136,26 +1284,38 @@ pub unsafe extern "C" fn phy_enable() {
         if G_IS_PHY_CALIBRATED == false {
             let init_data = &PHY_INIT_DATA_DEFAULT;
 
-            register_chipv7_phy(
+            // only do a full calibration if we don't have calibration data surviving a reset
+            let cal_mode = if PHY_CAL_DATA_VALID == PHY_CAL_DATA_MAGIC
+                && PHY_CAL_DATA_CRC == phy_cal_data_crc()
+            {
+                esp_phy_calibration_mode_t_PHY_RF_CAL_PARTIAL
+            } else {
+                esp_phy_calibration_mode_t_PHY_RF_CAL_FULL
+            };
+            trace!("phy calibration mode {}", cal_mode);
+
+            let res = register_chipv7_phy(
                 init_data,
-                &mut cal_data as *mut _ as *mut crate::binary::include::esp_phy_calibration_data_t,
-                esp_phy_calibration_mode_t_PHY_RF_CAL_FULL,
+                &mut PHY_CAL_DATA as *mut _
+                    as *mut crate::binary::include::esp_phy_calibration_data_t,
+                cal_mode,
             );
 
+            // only keep calibration data the PHY accepted
+            if res == 0 {
+                PHY_CAL_DATA_CRC = phy_cal_data_crc();
+                PHY_CAL_DATA_VALID = PHY_CAL_DATA_MAGIC;
+            } else {
+                trace!("phy calibration returned {}", res);
+                PHY_CAL_DATA_VALID = 0;
+            }
             G_IS_PHY_CALIBRATED = true;
         } else {
-            trace!("implement phy_digital_regs_load");
             phy_wakeup_init();
-            //phy_digital_regs_load();
-            /*
-            static inline void phy_digital_regs_load(void)
-            {
-            if (g_phy_digital_regs_mem != NULL)
-                {
-                phy_dig_reg_backup(false, g_phy_digital_regs_mem);
-                }
+
+            if PHY_DIGITAL_REGS_STORED {
+                phy_dig_reg_backup(false, PHY_DIGITAL_REGS.as_mut_ptr());
             }
-            */
         }
     });
 }
@@ -1184,9 +1344,27 @@ pub unsafe extern "C" fn phy_update_country_info(
 ) -> crate::binary::c_types::c_int {
     // not implemented in original code
     trace!("phy_update_country_info {}", *country as char);
+
+    // also called for the country set via `wifi_set_country`, that isn't reported
+    let cc = [*country, *country.offset(1)];
+    let changed = critical_section::with(|_| {
+        if EFFECTIVE_COUNTRY == Some(cc) {
+            false
+        } else {
+            EFFECTIVE_COUNTRY = Some(cc);
+            true
+        }
+    });
+    if changed {
+        queue_event(WifiEvent::CountryChanged(cc));
+    }
+
     -1
 }
 
+// the country code the driver uses
+pub(crate) static mut EFFECTIVE_COUNTRY: Option<[u8; 2]> = None;
+
 /****************************************************************************
  * Name: esp_wifi_read_mac
  *
@@ -1736,13 +1914,9 @@ pub unsafe extern "C" fn get_time(
 pub unsafe extern "C" fn random() -> crate::binary::c_types::c_ulong {
     trace!("random");
 
-    if let Some(ref mut rng) = RANDOM_GENERATOR {
-        let mut buffer = [0u8; 4];
-        rng.read(&mut buffer).unwrap();
-        u32::from_le_bytes(buffer)
-    } else {
-        0
-    }
+    let mut buffer = [0u8; 4];
+    fill_random(&mut buffer);
+    u32::from_le_bytes(buffer)
 }
 
 /****************************************************************************
@@ -2000,10 +2174,7 @@ pub unsafe extern "C" fn wifi_create_queue(
  *
  ****************************************************************************/
 pub unsafe extern "C" fn wifi_delete_queue(queue: *mut crate::binary::c_types::c_void) {
-    trace!(
-        "wifi_delete_queue {:p} - not implemented - doing nothing",
-        queue
-    );
+    delete_wifi_queue(queue);
 }
 
 /****************************************************************************
@@ -2286,11 +2457,26 @@ pub unsafe extern "C" fn pp_printf(s: *const u8, args: ...) {
     syslog(0, s, args);
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn sc_printf(s: *const u8, args: ...) {
+    syslog(0, s, args);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn putchar(c: i32) -> i32 {
+    trace!("{}", c as u8 as char);
+    c
+}
+
 // #define ESP_EVENT_DEFINE_BASE(id) esp_event_base_t id = #id
 static mut EVT: u8 = 0;
 #[no_mangle]
 static mut WIFI_EVENT: esp_event_base_t = unsafe { &EVT };
 
+static mut SC_EVT: u8 = 0;
+#[no_mangle]
+static mut SC_EVENT: esp_event_base_t = unsafe { &SC_EVT };
+
 // stuff needed by wpa-supplicant
 #[no_mangle]
 pub unsafe extern "C" fn __assert_func(
@@ -2340,9 +2526,7 @@ pub unsafe extern "C" fn esp_fill_random(dst: *mut u8, len: u32) {
     trace!("esp_fill_random");
     let dst = core::slice::from_raw_parts_mut(dst, len as usize);
 
-    if let Some(ref mut rng) = RANDOM_GENERATOR {
-        rng.read(dst).unwrap();
-    }
+    fill_random(dst);
 }
 
 #[no_mangle]
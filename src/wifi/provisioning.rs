@@ -0,0 +1,120 @@
+use crate::binary::include::{
+    esp_smartconfig_internal_start, esp_smartconfig_internal_stop, esp_smartconfig_set_type,
+    smartconfig_event_got_ssid_pswd_t, smartconfig_event_t_SC_EVENT_FOUND_CHANNEL,
+    smartconfig_event_t_SC_EVENT_GOT_SSID_PSWD, smartconfig_start_config_t, smartconfig_type_t,
+    smartconfig_type_t_SC_TYPE_AIRKISS, smartconfig_type_t_SC_TYPE_ESPTOUCH,
+    smartconfig_type_t_SC_TYPE_ESPTOUCH_AIRKISS, smartconfig_type_t_SC_TYPE_ESPTOUCH_V2,
+};
+use crate::debug;
+
+static mut CREDENTIALS: Option<Credentials> = None;
+
+/// Protocol used by the phone app to send the credentials
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmartConfigType {
+    EspTouch,
+    AirKiss,
+    EspTouchAirKiss,
+    EspTouchV2,
+}
+
+impl SmartConfigType {
+    fn to_raw(self) -> smartconfig_type_t {
+        match self {
+            SmartConfigType::EspTouch => smartconfig_type_t_SC_TYPE_ESPTOUCH,
+            SmartConfigType::AirKiss => smartconfig_type_t_SC_TYPE_AIRKISS,
+            SmartConfigType::EspTouchAirKiss => smartconfig_type_t_SC_TYPE_ESPTOUCH_AIRKISS,
+            SmartConfigType::EspTouchV2 => smartconfig_type_t_SC_TYPE_ESPTOUCH_V2,
+        }
+    }
+}
+
+/// Credentials received from the phone app
+#[derive(Debug, Clone, Copy)]
+pub struct Credentials {
+    ssid: [u8; 32],
+    password: [u8; 64],
+    pub bssid: Option<[u8; 6]>,
+}
+
+impl Credentials {
+    pub fn ssid(&self) -> &str {
+        str_from_nul_terminated(&self.ssid)
+    }
+
+    pub fn password(&self) -> &str {
+        str_from_nul_terminated(&self.password)
+    }
+}
+
+fn str_from_nul_terminated(bytes: &[u8]) -> &str {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len]).unwrap_or("")
+}
+
+/// Start listening for credentials sent by the ESP-Touch / AirKiss phone app.
+///
+/// Needs `wifi_start` to be called before and the station must not be connected.
+/// Uses promiscuous mode internally so it can't be used together with the sniffer.
+///
+/// The ACK the phone app waits for isn't sent since there is no UDP stack in the driver here,
+/// the app will report a timeout even though the credentials were received.
+pub fn provisioning_start(kind: SmartConfigType) -> i32 {
+    critical_section::with(|_| unsafe {
+        CREDENTIALS = None;
+    });
+
+    unsafe {
+        let res = esp_smartconfig_set_type(kind.to_raw());
+        if res != 0 {
+            return res;
+        }
+
+        let config = smartconfig_start_config_t {
+            enable_log: false,
+            esp_touch_v2_enable_crypt: false,
+            esp_touch_v2_key: core::ptr::null_mut(),
+        };
+        esp_smartconfig_internal_start(&config)
+    }
+}
+
+pub fn provisioning_stop() -> i32 {
+    unsafe { esp_smartconfig_internal_stop() }
+}
+
+/// Returns the received credentials once they are available
+pub fn provisioning_take_credentials() -> Option<Credentials> {
+    critical_section::with(|_| unsafe { CREDENTIALS.take() })
+}
+
+/// Block until the phone app sent the credentials and stop provisioning
+pub fn wait_for_credentials() -> Credentials {
+    loop {
+        if let Some(credentials) = provisioning_take_credentials() {
+            provisioning_stop();
+            return credentials;
+        }
+    }
+}
+
+pub(crate) unsafe fn on_event(event_id: i32, event_data: *mut crate::binary::c_types::c_void) {
+    debug!("smartconfig event {}", event_id);
+
+    if event_id == smartconfig_event_t_SC_EVENT_FOUND_CHANNEL as i32 {
+        debug!("smartconfig found channel");
+    } else if event_id == smartconfig_event_t_SC_EVENT_GOT_SSID_PSWD as i32 {
+        let data = &*(event_data as *const smartconfig_event_got_ssid_pswd_t);
+        critical_section::with(|_| {
+            CREDENTIALS = Some(Credentials {
+                ssid: data.ssid,
+                password: data.password,
+                bssid: if data.bssid_set {
+                    Some(data.bssid)
+                } else {
+                    None
+                },
+            });
+        });
+    }
+}
@@ -0,0 +1,81 @@
+use crate::{
+    binary::include::esp_wifi_set_ps,
+    debug,
+    timer::get_systimer_count,
+    wifi::{PowerSaveMode, POWER_SAVE_MODE, WIFI_STARTED},
+};
+
+/// How much the application needs the radio to keep quiet
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum RadioPriority {
+    /// Don't send frames, they are kept queued until the request ends. The radio still receives.
+    HoldTx,
+    /// Additionally put the modem to sleep between beacons (`PowerSaveMode::Maximum`)
+    /// while the station is connected
+    Sleep,
+}
+
+// the active request and when it ends in systimer ticks
+static mut REQUEST: Option<(RadioPriority, u64)> = None;
+
+/// Keep the radio quiet for `duration_ms`, e.g. while sampling an ADC which picks up RF noise.
+/// Everything is restored automatically once the time is up or via `radio_release`.
+///
+/// A new request replaces the previous one. The AP may drop the station if its frames are held
+/// back for too long (several seconds), so keep requests short.
+pub fn radio_request(priority: RadioPriority, duration_ms: u32) -> i32 {
+    let (previous, started) = critical_section::with(|_| unsafe {
+        let previous = REQUEST.map(|(priority, _)| priority);
+        REQUEST = Some((priority, get_systimer_count() + duration_ms as u64 * 16_000));
+        (previous, WIFI_STARTED)
+    });
+
+    if !started {
+        return 0;
+    }
+
+    match (previous, priority) {
+        (Some(RadioPriority::Sleep), RadioPriority::Sleep) => 0,
+        (_, RadioPriority::Sleep) => unsafe { esp_wifi_set_ps(PowerSaveMode::Maximum.to_raw()) },
+        (Some(RadioPriority::Sleep), _) => restore_power_save(),
+        _ => 0,
+    }
+}
+
+/// End the current request early
+pub fn radio_release() -> i32 {
+    let previous = critical_section::with(|_| unsafe { REQUEST.take() });
+
+    match previous {
+        Some((RadioPriority::Sleep, _)) => restore_power_save(),
+        _ => 0,
+    }
+}
+
+/// True while frames must not be sent
+pub(crate) fn tx_held() -> bool {
+    critical_section::with(|_| unsafe { REQUEST.is_some() })
+}
+
+pub(crate) fn check_radio_request() {
+    let expired = critical_section::with(|_| unsafe {
+        match REQUEST {
+            Some((_, end)) if get_systimer_count() >= end => true,
+            _ => false,
+        }
+    });
+
+    if expired {
+        debug!("radio request expired");
+        radio_release();
+    }
+}
+
+fn restore_power_save() -> i32 {
+    let (mode, started) = critical_section::with(|_| unsafe { (POWER_SAVE_MODE, WIFI_STARTED) });
+    if !started {
+        return 0;
+    }
+
+    unsafe { esp_wifi_set_ps(mode.to_raw()) }
+}
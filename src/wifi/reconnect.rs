@@ -0,0 +1,183 @@
+use crate::{
+    binary::include::{
+        esp_wifi_connect, wifi_err_reason_t, wifi_err_reason_t_WIFI_REASON_4WAY_HANDSHAKE_TIMEOUT,
+        wifi_err_reason_t_WIFI_REASON_HANDSHAKE_TIMEOUT,
+    },
+    debug,
+    timer::get_systimer_count,
+    wifi::{fill_random, os_adapter::WIFI_CONNECTING, queue_event, WifiEvent},
+};
+
+/// How the station reconnects after losing the connection to the AP.
+///
+/// The delay before attempt `n` is `initial_backoff_ms * 2^(n-1)` capped at `max_backoff_ms`
+/// (or taken from `schedule`) plus a random delay of up to `jitter_ms`. The jitter spreads the
+/// attempts of many devices powering up at the same time (e.g. after an outage) so they don't
+/// all hit the AP at once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    pub initial_backoff_ms: u32,
+    pub max_backoff_ms: u32,
+    /// Delays in milliseconds before the first, second, ... attempt instead of the exponential
+    /// backoff. The last delay is used for all further attempts.
+    pub schedule: Option<&'static [u32]>,
+    pub jitter_ms: u32,
+    /// Give up after this many failed attempts, `None` to retry forever.
+    /// `WifiEvent::StaReconnectGaveUp` is sent then.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            schedule: None,
+            jitter_ms: 1_000,
+            max_attempts: None,
+        }
+    }
+}
+
+static mut POLICY: Option<ReconnectPolicy> = None;
+// set by `wifi_connect`, cleared by `wifi_stop` - only reconnect if a connection is wanted
+static mut WANTED: bool = false;
+static mut ATTEMPT: u32 = 0;
+static mut NEXT_ATTEMPT: Option<u64> = None;
+
+static mut HANDSHAKE_RETRIES: u8 = 0;
+static mut HANDSHAKE_RETRY_DELAY_MS: u32 = 0;
+static mut HANDSHAKE_RETRIES_LEFT: u8 = 0;
+
+/// Reconnect automatically after the connection is lost or an attempt failed.
+/// Pass `None` to disable it again (the default).
+///
+/// Each attempt is reported by a `WifiEvent::StaReconnecting` event carrying the attempt number.
+pub fn reconnect_set_policy(policy: Option<ReconnectPolicy>) {
+    critical_section::with(|_| unsafe {
+        POLICY = policy;
+        if policy.is_none() {
+            NEXT_ATTEMPT = None;
+        }
+    });
+}
+
+/// Number of reconnect attempts since the station was last connected
+pub fn reconnect_attempts() -> u32 {
+    critical_section::with(|_| unsafe { ATTEMPT })
+}
+
+/// Retry the connection up to `retries` times, `delay_ms` after the AP didn't complete the
+/// 4-way handshake in time. Some consumer APs are slow to start the handshake and only succeed
+/// on a second try. Disabled by default.
+///
+/// The handshake timeouts of the supplicant are built into the driver and can't be changed.
+/// These retries don't need a `ReconnectPolicy` and happen before it kicks in. They are reported
+/// as `WifiEvent::StaReconnecting` as well.
+pub fn reconnect_set_handshake_retries(retries: u8, delay_ms: u32) {
+    critical_section::with(|_| unsafe {
+        HANDSHAKE_RETRIES = retries;
+        HANDSHAKE_RETRY_DELAY_MS = delay_ms;
+        HANDSHAKE_RETRIES_LEFT = retries;
+    });
+}
+
+/// A connection was requested via `wifi_connect` and the driver wasn't stopped since
+pub(crate) fn connection_wanted() -> bool {
+    critical_section::with(|_| unsafe { WANTED })
+}
+
+pub(crate) fn on_connect_requested() {
+    critical_section::with(|_| unsafe {
+        WANTED = true;
+        ATTEMPT = 0;
+        NEXT_ATTEMPT = None;
+        HANDSHAKE_RETRIES_LEFT = HANDSHAKE_RETRIES;
+    });
+}
+
+pub(crate) fn on_stopped() {
+    critical_section::with(|_| unsafe {
+        WANTED = false;
+        ATTEMPT = 0;
+        NEXT_ATTEMPT = None;
+    });
+}
+
+pub(crate) fn on_connected() {
+    critical_section::with(|_| unsafe {
+        ATTEMPT = 0;
+        NEXT_ATTEMPT = None;
+        HANDSHAKE_RETRIES_LEFT = HANDSHAKE_RETRIES;
+    });
+}
+
+/// `reason` is the `wifi_err_reason_t` reported by the driver, 0 if unknown
+pub(crate) fn on_disconnected(reason: wifi_err_reason_t) {
+    critical_section::with(|_| unsafe {
+        let handshake_timeout = reason == wifi_err_reason_t_WIFI_REASON_4WAY_HANDSHAKE_TIMEOUT
+            || reason == wifi_err_reason_t_WIFI_REASON_HANDSHAKE_TIMEOUT;
+        if WANTED && handshake_timeout && HANDSHAKE_RETRIES_LEFT > 0 {
+            debug!("handshake timed out, retrying");
+            HANDSHAKE_RETRIES_LEFT -= 1;
+            NEXT_ATTEMPT = Some(get_systimer_count() + HANDSHAKE_RETRY_DELAY_MS as u64 * 16_000);
+            return;
+        }
+
+        let policy = match POLICY {
+            Some(policy) if WANTED => policy,
+            _ => return,
+        };
+
+        if let Some(max_attempts) = policy.max_attempts {
+            if ATTEMPT >= max_attempts {
+                debug!("giving up reconnecting after {} attempts", ATTEMPT);
+                NEXT_ATTEMPT = None;
+                queue_event(WifiEvent::StaReconnectGaveUp(ATTEMPT));
+                return;
+            }
+        }
+
+        let backoff_ms = match policy.schedule {
+            Some(schedule) if !schedule.is_empty() => {
+                schedule[(ATTEMPT as usize).min(schedule.len() - 1)] as u64
+            }
+            _ => ((policy.initial_backoff_ms as u64) << ATTEMPT.min(31))
+                .min(policy.max_backoff_ms as u64),
+        };
+        let delay_ms = backoff_ms + random_below(policy.jitter_ms as u64 + 1);
+
+        NEXT_ATTEMPT = Some(get_systimer_count() + delay_ms * 16_000);
+    });
+}
+
+fn random_below(bound: u64) -> u64 {
+    let mut buffer = [0u8; 4];
+    fill_random(&mut buffer);
+    u32::from_le_bytes(buffer) as u64 % bound
+}
+
+pub(crate) fn check_reconnect() {
+    let attempt = critical_section::with(|_| unsafe {
+        match NEXT_ATTEMPT {
+            Some(due) if get_systimer_count() >= due => {
+                NEXT_ATTEMPT = None;
+                ATTEMPT += 1;
+                WIFI_CONNECTING = true;
+                Some(ATTEMPT)
+            }
+            _ => None,
+        }
+    });
+
+    if let Some(attempt) = attempt {
+        debug!("reconnect attempt {}", attempt);
+        queue_event(WifiEvent::StaReconnecting(attempt));
+
+        let res = unsafe { esp_wifi_connect() };
+        if res != 0 {
+            critical_section::with(|_| unsafe { WIFI_CONNECTING = false });
+            on_disconnected(0);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+// `Device` implementations for newer smoltcp versions, so applications depending on one of them
+// can use the WiFi interfaces. They use the same queues as the smoltcp 0.7 implementation.
+//
+// 0.9 and 0.10 share the same `Device` API, it only lives in different crates.
+macro_rules! impl_device {
+    ($smoltcp:ident) => {
+        use $smoltcp::{
+            phy::{Device, DeviceCapabilities, RxToken, TxToken},
+            time::Instant,
+        };
+
+        use crate::wifi::{WifiDevice, WifiRxToken, WifiTxToken, MAX_FRAME_LEN};
+
+        impl Device for WifiDevice {
+            type RxToken<'a>
+                = WifiRxToken
+            where
+                Self: 'a;
+            type TxToken<'a>
+                = WifiTxToken
+            where
+                Self: 'a;
+
+            fn receive(&mut self, _timestamp: Instant) -> Option<(WifiRxToken, WifiTxToken)> {
+                smoltcp::phy::Device::receive(self)
+            }
+
+            fn transmit(&mut self, _timestamp: Instant) -> Option<WifiTxToken> {
+                smoltcp::phy::Device::transmit(self)
+            }
+
+            fn capabilities(&self) -> DeviceCapabilities {
+                let mut caps = DeviceCapabilities::default();
+                caps.max_transmission_unit = usize::min(MAX_FRAME_LEN, 1514);
+                caps.max_burst_size = Some(1);
+                caps
+            }
+        }
+
+        impl RxToken for WifiRxToken {
+            fn consume<R, F>(self, f: F) -> R
+            where
+                F: FnOnce(&mut [u8]) -> R,
+            {
+                let mut f = Some(f);
+                let mut result = None;
+                smoltcp::phy::RxToken::consume(self, timestamp(), |frame| {
+                    result = f.take().map(|f| f(frame));
+                    Ok(())
+                })
+                .ok();
+
+                // the frame was taken by another token in the meantime, newer smoltcp versions
+                // can't handle an error here
+                match (result, f) {
+                    (Some(result), _) => result,
+                    (None, Some(f)) => f(&mut []),
+                    (None, None) => unreachable!(),
+                }
+            }
+        }
+
+        impl TxToken for WifiTxToken {
+            fn consume<R, F>(self, len: usize, f: F) -> R
+            where
+                F: FnOnce(&mut [u8]) -> R,
+            {
+                let mut f = Some(f);
+                let mut result = None;
+                smoltcp::phy::TxToken::consume(self, timestamp(), len, |frame| {
+                    result = f.take().map(|f| f(frame));
+                    Ok(())
+                })
+                .ok();
+
+                // no buffer is free (or the driver was stopped), the frame is dropped
+                match (result, f) {
+                    (Some(result), _) => result,
+                    (None, Some(f)) => {
+                        let mut scratch = [0u8; MAX_FRAME_LEN];
+                        f(&mut scratch[..usize::min(len, MAX_FRAME_LEN)])
+                    }
+                    (None, None) => unreachable!(),
+                }
+            }
+        }
+
+        // the tokens of 0.7 only use it for logging
+        fn timestamp() -> smoltcp::time::Instant {
+            smoltcp::time::Instant::from_millis(0)
+        }
+    };
+}
+
+#[cfg(feature = "smoltcp-0_9")]
+mod smoltcp_0_9 {
+    impl_device!(smoltcp_0_9);
+}
+
+#[cfg(feature = "smoltcp-0_10")]
+mod smoltcp_0_10 {
+    impl_device!(smoltcp_0_10);
+}
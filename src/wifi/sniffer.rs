@@ -0,0 +1,180 @@
+use crate::binary::include::{
+    esp_wifi_set_channel, esp_wifi_set_promiscuous, esp_wifi_set_promiscuous_filter,
+    esp_wifi_set_promiscuous_rx_cb, wifi_pkt_rx_ctrl_t, wifi_promiscuous_filter_t,
+    wifi_promiscuous_pkt_t, wifi_promiscuous_pkt_type_t, wifi_promiscuous_pkt_type_t_WIFI_PKT_CTRL,
+    wifi_promiscuous_pkt_type_t_WIFI_PKT_DATA, wifi_promiscuous_pkt_type_t_WIFI_PKT_MGMT,
+    wifi_second_chan_t_WIFI_SECOND_CHAN_NONE, WIFI_PROMIS_FILTER_MASK_CTRL,
+    WIFI_PROMIS_FILTER_MASK_DATA, WIFI_PROMIS_FILTER_MASK_MGMT, WIFI_PROMIS_FILTER_MASK_MISC,
+};
+
+static mut SNIFFER_CALLBACK: Option<fn(FrameType, &RxControlInfo, &[u8])> = None;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameType {
+    Management,
+    Control,
+    Data,
+    /// e.g. MIMO frames - these don't have a payload
+    Misc,
+}
+
+impl FrameType {
+    fn from_raw(type_: wifi_promiscuous_pkt_type_t) -> FrameType {
+        match type_ {
+            wifi_promiscuous_pkt_type_t_WIFI_PKT_MGMT => FrameType::Management,
+            wifi_promiscuous_pkt_type_t_WIFI_PKT_CTRL => FrameType::Control,
+            wifi_promiscuous_pkt_type_t_WIFI_PKT_DATA => FrameType::Data,
+            _ => FrameType::Misc,
+        }
+    }
+}
+
+/// Which frame types are delivered to the callback
+#[derive(Debug, Clone, Copy)]
+pub struct PromiscuousFilter {
+    pub management: bool,
+    pub control: bool,
+    pub data: bool,
+    pub misc: bool,
+}
+
+impl PromiscuousFilter {
+    fn to_raw(self) -> wifi_promiscuous_filter_t {
+        let mut filter_mask = 0;
+        if self.management {
+            filter_mask |= WIFI_PROMIS_FILTER_MASK_MGMT;
+        }
+        if self.control {
+            filter_mask |= WIFI_PROMIS_FILTER_MASK_CTRL;
+        }
+        if self.data {
+            filter_mask |= WIFI_PROMIS_FILTER_MASK_DATA;
+        }
+        if self.misc {
+            filter_mask |= WIFI_PROMIS_FILTER_MASK_MISC;
+        }
+
+        wifi_promiscuous_filter_t { filter_mask }
+    }
+}
+
+/// Metadata the radio reports for a received frame
+#[derive(Debug, Clone, Copy)]
+pub struct RxControlInfo {
+    /// dBm
+    pub rssi: i8,
+    /// PHY rate encoding of 11b/g frames
+    pub rate: u8,
+    /// 0: 11b/g, 1: 11n
+    pub sig_mode: u8,
+    /// Modulation coding scheme of 11n frames
+    pub mcs: u8,
+    pub channel: u8,
+    pub secondary_channel: u8,
+    /// Local time of the reception in microseconds
+    pub timestamp: u32,
+    /// dBm
+    pub noise_floor: i8,
+    /// Length of the frame including the FCS
+    pub sig_len: u16,
+    /// Non-zero if the frame wasn't received correctly
+    pub rx_state: u8,
+}
+
+impl RxControlInfo {
+    fn from_raw(rx_ctrl: &wifi_pkt_rx_ctrl_t) -> RxControlInfo {
+        RxControlInfo {
+            rssi: rx_ctrl.rssi() as i8,
+            rate: rx_ctrl.rate() as u8,
+            sig_mode: rx_ctrl.sig_mode() as u8,
+            mcs: rx_ctrl.mcs() as u8,
+            channel: rx_ctrl.channel() as u8,
+            secondary_channel: rx_ctrl.secondary_channel() as u8,
+            timestamp: rx_ctrl.timestamp(),
+            noise_floor: rx_ctrl.noise_floor() as i8,
+            sig_len: rx_ctrl.sig_len() as u16,
+            rx_state: rx_ctrl.rx_state() as u8,
+        }
+    }
+}
+
+/// Enable promiscuous mode. Every received frame passing `filter` is handed to `callback`
+/// together with its metadata. The frame starts with the 802.11 header.
+///
+/// The callback runs on the task of the WiFi driver and should return quickly.
+/// Needs `wifi_start` to be called before. If `channel` is given the radio is switched to it, which
+/// only makes sense while the station isn't connected.
+pub fn sniffer_start(
+    filter: PromiscuousFilter,
+    channel: Option<u8>,
+    callback: fn(FrameType, &RxControlInfo, &[u8]),
+) -> i32 {
+    critical_section::with(|_| unsafe {
+        SNIFFER_CALLBACK = Some(callback);
+    });
+
+    unsafe {
+        let res = esp_wifi_set_promiscuous_rx_cb(Some(promiscuous_rx_cb));
+        if res != 0 {
+            return res;
+        }
+
+        let res = esp_wifi_set_promiscuous_filter(&filter.to_raw());
+        if res != 0 {
+            return res;
+        }
+
+        let res = esp_wifi_set_promiscuous(true);
+        if res != 0 {
+            return res;
+        }
+
+        if let Some(channel) = channel {
+            return sniffer_set_channel(channel);
+        }
+    }
+
+    0
+}
+
+/// Switch the channel while sniffing, e.g. to hop through all channels
+pub fn sniffer_set_channel(channel: u8) -> i32 {
+    unsafe { esp_wifi_set_channel(channel, wifi_second_chan_t_WIFI_SECOND_CHAN_NONE) }
+}
+
+/// True while promiscuous mode is enabled via `sniffer_start`
+pub fn sniffer_active() -> bool {
+    critical_section::with(|_| unsafe { SNIFFER_CALLBACK.is_some() })
+}
+
+pub fn sniffer_stop() -> i32 {
+    let res = unsafe { esp_wifi_set_promiscuous(false) };
+
+    critical_section::with(|_| unsafe {
+        SNIFFER_CALLBACK = None;
+    });
+
+    res
+}
+
+unsafe extern "C" fn promiscuous_rx_cb(
+    buf: *mut crate::binary::c_types::c_void,
+    type_: wifi_promiscuous_pkt_type_t,
+) {
+    let callback = match critical_section::with(|_| SNIFFER_CALLBACK) {
+        Some(callback) => callback,
+        None => return,
+    };
+
+    let pkt = &*(buf as *const wifi_promiscuous_pkt_t);
+    let frame_type = FrameType::from_raw(type_);
+    let info = RxControlInfo::from_raw(&pkt.rx_ctrl);
+
+    let payload = if frame_type == FrameType::Misc {
+        &[]
+    } else {
+        pkt.payload.as_slice(info.sig_len as usize)
+    };
+
+    callback(frame_type, &info, payload);
+}
@@ -0,0 +1,61 @@
+use crate::wifi::G_CONFIG;
+
+// None if the limit follows the number of TX buffers of the driver
+static mut LIMIT: Option<u32> = None;
+// frames handed to the driver which it didn't report as done yet
+static mut IN_FLIGHT: u32 = 0;
+
+/// Hand at most `limit` frames to the driver which it didn't report as sent yet (via its TX done
+/// callback). Further frames stay queued in `WifiDevice` until the driver is done with one, so
+/// smoltcp sees no free TX buffer instead of the driver dropping frames.
+///
+/// By default the limit is the number of TX buffers the driver uses - the static ones
+/// (`WifiInitConfig::static_tx_buffers`) if configured, otherwise the dynamic ones
+/// (`WifiInitConfig::dynamic_tx_buffers`). `None` restores that. Only frames sent via the driver count, not those of another
+/// `RadioBackend`.
+pub fn tx_credits_set_limit(limit: Option<u32>) {
+    critical_section::with(|_| unsafe {
+        LIMIT = limit;
+    });
+}
+
+/// Number of frames the driver is still sending
+pub fn tx_credits_in_flight() -> u32 {
+    critical_section::with(|_| unsafe { IN_FLIGHT })
+}
+
+/// True if another frame can be handed to the driver
+pub(crate) fn available() -> bool {
+    critical_section::with(|_| unsafe {
+        // tx_buf_type: 0 = static, 1 = dynamic TX buffers
+        let buffers = if G_CONFIG.tx_buf_type == 0 {
+            G_CONFIG.static_tx_buf_num
+        } else {
+            G_CONFIG.dynamic_tx_buf_num
+        };
+        let limit = LIMIT.unwrap_or(buffers as u32);
+        IN_FLIGHT < limit
+    })
+}
+
+/// A frame was handed to the driver
+pub(crate) fn take() {
+    critical_section::with(|_| unsafe {
+        IN_FLIGHT += 1;
+    });
+}
+
+/// The driver reported a frame as done. Frames it sends on its own (e.g. EAPOL) are reported
+/// too, so this can't go below zero.
+pub(crate) fn give_back() {
+    critical_section::with(|_| unsafe {
+        IN_FLIGHT = IN_FLIGHT.saturating_sub(1);
+    });
+}
+
+/// The driver was stopped, it won't report the frames in flight anymore
+pub(crate) fn reset() {
+    critical_section::with(|_| unsafe {
+        IN_FLIGHT = 0;
+    });
+}